@@ -7,7 +7,8 @@ use shim::ioerr;
 use shim::path::Path;
 
 pub use fat32::traits;
-use fat32::vfat::{Dir, Entry, File, VFat, VFatHandle};
+use fat32::traits::MetadataChanges;
+use fat32::vfat::{Dir, Entry, File, Timestamp, VFat, VFatHandle};
 
 use self::sd::Sd;
 use crate::mutex::Mutex;
@@ -73,4 +74,28 @@ impl fat32::traits::FileSystem for &FileSystem {
         // self.0.lock().clone()
         self.0.lock().as_ref().unwrap().open(path)
     }
+
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        self.0.lock().as_ref().unwrap().create_file(path)
+    }
+
+    fn create_dir<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Dir> {
+        self.0.lock().as_ref().unwrap().create_dir(path)
+    }
+
+    fn remove<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        self.0.lock().as_ref().unwrap().remove(path)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        self.0.lock().as_ref().unwrap().remove_dir(path)
+    }
+
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()> {
+        self.0.lock().as_ref().unwrap().rename(from, to)
+    }
+
+    fn set_metadata<P: AsRef<Path>>(self, path: P, changes: &MetadataChanges<Timestamp>) -> io::Result<()> {
+        self.0.lock().as_ref().unwrap().set_metadata(path, changes)
+    }
 }
@@ -0,0 +1,255 @@
+//! Adapters that change how another `BlockDevice` is addressed or read.
+//!
+//! They compose: `CachedPartition` caches a `Translator` over a `Slice` of
+//! the disk, and any of them can wrap a device of its own.
+
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+
+use shim::io;
+
+use crate::traits::BlockDevice;
+
+/// Returns an error of kind `InvalidInput` for an access out of the range of
+/// a device.
+fn out_of_range() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "sector out of range")
+}
+
+/// The number of sectors of `sector_size` bytes that `len` bytes touch.
+fn sectors_touched(len: usize, sector_size: u64) -> u64 {
+    (len as u64).div_ceil(sector_size)
+}
+
+/// A range of sectors of another device. Sector `n` of the slice is sector
+/// `start + n` of the device.
+#[derive(Debug)]
+pub struct Slice<T> {
+    device: T,
+    start: u64,
+    num_sectors: u64,
+}
+
+impl<T: BlockDevice> Slice<T> {
+    /// Returns the `num_sectors` sectors of `device` starting at sector
+    /// `start`. Accesses to sectors past the end of the slice fail with an
+    /// error of kind `InvalidInput`.
+    pub fn new(device: T, start: u64, num_sectors: u64) -> Slice<T> {
+        Slice { device, start, num_sectors }
+    }
+
+    /// The number of sectors in the slice.
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Maps the `len` bytes starting at sector `n` to the device, or returns
+    /// an error if they don't fit in the slice.
+    fn map(&self, n: u64, len: usize) -> io::Result<u64> {
+        let count = max(sectors_touched(len, self.sector_size()), 1);
+        match n.checked_add(count) {
+            Some(end) if end <= self.num_sectors => Ok(self.start + n),
+            _ => Err(out_of_range()),
+        }
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for Slice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.map(n, 0)?;
+        self.device.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let n = self.map(n, 0)?;
+        self.device.write_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.map(n, buf.len())?;
+        self.device.read_sectors(n, buf)
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let n = self.map(n, buf.len())?;
+        self.device.write_sectors(n, buf)
+    }
+}
+
+/// Presents another device with logical sectors larger than its own, such as
+/// 4096 byte sectors over a disk with 512 byte sectors. Logical sector `n`
+/// covers the device's sectors starting at `n * factor`.
+#[derive(Debug)]
+pub struct Translator<T> {
+    device: T,
+    sector_size: u64,
+}
+
+impl<T: BlockDevice> Translator<T> {
+    /// Returns `device` addressed in sectors of `sector_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` is not a multiple of the device's sector size.
+    pub fn new(device: T, sector_size: u64) -> Translator<T> {
+        let physical = device.sector_size();
+        assert!(sector_size >= physical && sector_size.is_multiple_of(physical));
+        Translator { device, sector_size }
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// The number of physical sectors in one logical sector.
+    fn factor(&self) -> u64 {
+        self.sector_size / self.device.sector_size()
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for Translator<T> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(self.sector_size as usize, buf.len());
+        self.read_sectors(n, &mut buf[..len])
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let len = min(self.sector_size as usize, buf.len());
+        self.write_sectors(n, &buf[..len])
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let physical = n * self.factor();
+        self.device.read_sectors(physical, buf)
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let physical = n * self.factor();
+        self.device.write_sectors(physical, buf)
+    }
+}
+
+/// Reads a window of sectors from another device whenever a sector outside
+/// the previous window is read, so that reading sectors in order takes one
+/// device call per window. Writes go straight to the device and update the
+/// window.
+#[derive(Debug)]
+pub struct ReadAhead<T> {
+    device: T,
+    window: u64,
+    buffer: Vec<u8>,
+    /// The first sector in `buffer`.
+    first: u64,
+    /// The number of sectors in `buffer`.
+    buffered: u64,
+}
+
+impl<T: BlockDevice> ReadAhead<T> {
+    /// Returns `device` read `window` sectors at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is 0.
+    pub fn new(device: T, window: u64) -> ReadAhead<T> {
+        assert!(window > 0);
+        ReadAhead { device, window, buffer: Vec::new(), first: 0, buffered: 0 }
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Returns `true` if the `count` sectors starting at `n` are in the
+    /// window.
+    fn contains(&self, n: u64, count: u64) -> bool {
+        n >= self.first && n + count <= self.first + self.buffered
+    }
+
+    /// Fills the window with the sectors starting at `n`. Near the end of the
+    /// device, where the whole window can't be read, only sector `n` is.
+    fn fill(&mut self, n: u64) -> io::Result<()> {
+        let sector_size = self.device.sector_size() as usize;
+        self.buffered = 0;
+        self.buffer.resize(self.window as usize * sector_size, 0);
+        let count = match self.device.read_sectors(n, &mut self.buffer) {
+            Ok(_) => self.window,
+            Err(_) if self.window > 1 => {
+                self.device.read_sectors(n, &mut self.buffer[..sector_size])?;
+                1
+            }
+            Err(e) => return Err(e),
+        };
+        self.first = n;
+        self.buffered = count;
+        Ok(())
+    }
+
+    /// Copies `buf`, just written starting at sector `n`, into the window.
+    fn update(&mut self, n: u64, buf: &[u8]) {
+        let sector_size = self.device.sector_size() as usize;
+        for (i, chunk) in buf.chunks(sector_size).enumerate() {
+            let sector = n + i as u64;
+            if self.contains(sector, 1) {
+                let at = (sector - self.first) as usize * sector_size;
+                self.buffer[at..at + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for ReadAhead<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(self.sector_size() as usize, buf.len());
+        self.read_sectors(n, &mut buf[..len])
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let written = self.device.write_sector(n, buf)?;
+        self.update(n, &buf[..written]);
+        Ok(written)
+    }
+
+    /// Reads that fit in a window are served from it, filling it first if
+    /// needed. Longer reads go straight to the device.
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size();
+        let count = max(sectors_touched(buf.len(), sector_size), 1);
+        if count > self.window {
+            return self.device.read_sectors(n, buf);
+        }
+        if !self.contains(n, count) {
+            self.fill(n)?;
+            if !self.contains(n, count) {
+                return self.device.read_sectors(n, buf);
+            }
+        }
+        let at = ((n - self.first) * sector_size) as usize;
+        buf.copy_from_slice(&self.buffer[at..at + buf.len()]);
+        Ok(buf.len())
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let written = self.device.write_sectors(n, buf)?;
+        self.update(n, &buf[..written]);
+        Ok(written)
+    }
+}
@@ -0,0 +1,581 @@
+//! Consistency checking and repair of FAT32 volumes, in the spirit of
+//! `fsck.vfat`.
+//!
+//! `check()` walks every directory reachable from the root directory, follows
+//! the cluster chain of every entry and reports what is wrong without
+//! touching the volume. `repair()` performs the same walk and fixes the
+//! problems it finds.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+
+use shim::io;
+
+use crate::traits::FileSystem;
+use crate::vfat::{display_short_name, Cluster, Dir, EntryIterator, EntryLocation, LfnRun, VFat, VFatDirEntry, VFatHandle};
+
+/// FAT entry value of a cluster that is marked bad.
+const BAD_CLUSTER: u32 = 0x0FFFFFF7;
+/// FAT entry value written to end a chain.
+const END_OF_CHAIN: u32 = 0x0FFFFFFF;
+/// Name of the directory lost chains are saved to.
+const FOUND_DIR: &str = "/FOUND.000";
+
+/// A problem found on a volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The cluster chain of `path` links back to `cluster`, which is already
+    /// part of it. Repaired by ending the chain before the link.
+    ChainLoop { path: String, cluster: u32 },
+    /// The cluster chain of `path` runs into `cluster`, which belongs to the
+    /// chain of `other`. Repaired by ending the chain of `path` before it.
+    CrossLinked { path: String, other: String, cluster: u32 },
+    /// The cluster chain of `path` reaches `cluster`, whose FAT entry `entry`
+    /// is neither a link to a data cluster nor the end of a chain. Repaired by
+    /// ending the chain before `cluster` if it is free or bad, and at
+    /// `cluster` otherwise.
+    BadLink { path: String, cluster: u32, entry: u32 },
+    /// The entry `path` starts at `cluster`, which is not a data cluster.
+    /// Repaired by emptying the file, or removing the directory.
+    InvalidStart { path: String, cluster: u32 },
+    /// The file `path` is `size` bytes long but its chain holds `clusters`
+    /// clusters, too few or more than the size needs. Repaired by shrinking
+    /// the size to what a short chain holds, or by freeing the clusters of a
+    /// long chain past the size.
+    SizeMismatch { path: String, size: u32, clusters: u32 },
+    /// A chain of `clusters` allocated clusters starting at `start` that no
+    /// entry refers to. Repaired by freeing or saving the chain.
+    LostChain { start: u32, clusters: u32 },
+    /// The long file name entry at byte `offset` of `cluster` in the directory
+    /// `path` doesn't belong to the entry following it. Repaired by deleting
+    /// it.
+    OrphanLfn { path: String, cluster: u32, offset: usize },
+    /// The volume records `recorded` free clusters but `actual` are free.
+    /// Repaired by recording the actual count.
+    FreeCountMismatch { recorded: u32, actual: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Problem::*;
+
+        match self {
+            ChainLoop { path, cluster } => write!(f, "{}: cluster chain loops back to cluster {}", path, cluster),
+            CrossLinked { path, other, cluster } => write!(f, "{}: cross-linked with {} at cluster {}", path, other, cluster),
+            BadLink { path, cluster, entry } => write!(f, "{}: cluster {} has invalid FAT entry {:#010x}", path, cluster, entry),
+            InvalidStart { path, cluster } => write!(f, "{}: invalid start cluster {}", path, cluster),
+            SizeMismatch { path, size, clusters } => write!(f, "{}: size is {} bytes but chain holds {} clusters", path, size, clusters),
+            LostChain { start, clusters } => write!(f, "lost chain of {} clusters at cluster {}", clusters, start),
+            OrphanLfn { path, cluster, offset } => write!(f, "{}: orphan long file name entry at cluster {} offset {}", path, cluster, offset),
+            FreeCountMismatch { recorded, actual } => write!(f, "free cluster count is {} but {} clusters are free", recorded, actual),
+        }
+    }
+}
+
+/// What `repair()` does with lost chains.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LostChains {
+    /// Lost clusters are freed.
+    Reclaim,
+    /// Every lost chain is saved as a file `FILEnnnn.CHK` in `/FOUND.000`.
+    Save,
+}
+
+/// Options for `check_with()` and `repair_with()`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CheckOptions {
+    /// Whether files may hold clusters past their size, as those reserved by
+    /// `File::preallocate()` do. If set, such clusters are counted in the
+    /// report's `reserved_clusters` and kept. Otherwise every such file is a
+    /// `SizeMismatch`.
+    pub preallocated: bool,
+}
+
+/// The outcome of checking a volume.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Every problem found, in the order it was found.
+    pub problems: Vec<Problem>,
+    /// The number of files found.
+    pub files: usize,
+    /// The number of directories found, the root directory included.
+    pub directories: usize,
+    /// The number of clusters in use by files and directories.
+    pub used_clusters: u32,
+    /// The number of clusters in the chains of files past their size when
+    /// `CheckOptions::preallocated` is set. They're counted in `used_clusters`
+    /// and left alone by `repair_with()`.
+    pub reserved_clusters: u32,
+    /// Whether the problems were repaired.
+    pub repaired: bool,
+}
+
+impl Report {
+    /// Whether the volume is free of problems.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the volume behind `vfat` for problems without modifying it.
+///
+/// # Errors
+///
+/// Returns an error if reading from the disk fails.
+pub fn check<HANDLE: VFatHandle>(vfat: &HANDLE) -> io::Result<Report> {
+    check_with(vfat, &CheckOptions::default())
+}
+
+/// Checks the volume behind `vfat` for problems according to `options`,
+/// without modifying it.
+///
+/// # Errors
+///
+/// Returns an error if reading from the disk fails.
+pub fn check_with<HANDLE: VFatHandle>(vfat: &HANDLE, options: &CheckOptions) -> io::Result<Report> {
+    vfat.lock(|vfat| Checker::new(vfat, options, false).run(vfat)).map(|(report, _)| report)
+}
+
+/// Checks the volume behind `vfat` for problems and repairs them. Lost chains
+/// are dealt with according to `lost`. All changes are synced to the disk.
+///
+/// # Errors
+///
+/// Returns an error if reading from or writing to the disk fails, or if lost
+/// chains can't be saved to `/FOUND.000`.
+pub fn repair<HANDLE: VFatHandle>(vfat: &HANDLE, lost: LostChains) -> io::Result<Report> {
+    repair_with(vfat, lost, &CheckOptions::default())
+}
+
+/// Checks the volume behind `vfat` for problems according to `options` and
+/// repairs them, like `repair()`.
+///
+/// # Errors
+///
+/// Returns an error if reading from or writing to the disk fails, or if lost
+/// chains can't be saved to `/FOUND.000`.
+pub fn repair_with<HANDLE: VFatHandle>(vfat: &HANDLE, lost: LostChains, options: &CheckOptions) -> io::Result<Report> {
+    let (mut report, lost_chains) = vfat.lock(|vfat| -> io::Result<_> {
+        let (report, lost_chains) = Checker::new(vfat, options, true).run(vfat)?;
+        if lost == LostChains::Reclaim {
+            for (_, clusters) in &lost_chains {
+                for &cluster in clusters {
+                    vfat.set_fat_entry(Cluster::from(cluster), 0)?;
+                }
+            }
+        }
+        vfat.recount_free_clusters()?;
+        Ok((report, lost_chains))
+    })?;
+
+    if lost == LostChains::Save && !lost_chains.is_empty() {
+        let found = match vfat.open_dir(FOUND_DIR) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => vfat.create_dir(FOUND_DIR)?,
+            result => result?,
+        };
+
+        let bytes_per_cluster = vfat.lock(|vfat| vfat.bytes_per_cluster());
+        let mut index = 0;
+        for (start, clusters) in lost_chains {
+            let size = (clusters.len() * bytes_per_cluster) as u32;
+            loop {
+                let name = format!("FILE{:04}.CHK", index);
+                index += 1;
+                match found.create_file_from_chain(&name, Cluster::from(start), size) {
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                    result => break result?,
+                }
+            }
+        }
+    }
+
+    vfat.lock(|vfat| vfat.sync())?;
+    report.repaired = true;
+    Ok(report)
+}
+
+/// How a walk along a cluster chain ended.
+enum ChainEnd {
+    /// At an end of chain marker.
+    End,
+    /// At a link back to a cluster of the chain.
+    Loop(u32),
+    /// At a cluster that belongs to another chain.
+    CrossLinked(u32),
+    /// At a cluster with an invalid FAT entry.
+    BadLink(u32, u32),
+}
+
+/// A lost chain: its first cluster and all of its clusters.
+type LostChain = (u32, Vec<u32>);
+
+/// One bit for every cluster of a volume.
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn new(len: u32) -> Bitmap {
+        Bitmap(vec![0; (len as usize).div_ceil(64)])
+    }
+
+    fn get(&self, cluster: u32) -> bool {
+        self.0[cluster as usize / 64] & (1 << (cluster % 64)) != 0
+    }
+
+    fn set(&mut self, cluster: u32) {
+        self.0[cluster as usize / 64] |= 1 << (cluster % 64);
+    }
+
+    /// The number of bits set.
+    fn count(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+}
+
+/// An entry whose chain has been walked: the first `len` clusters of the
+/// chain from `start` are the entry's.
+struct Owner {
+    path: String,
+    start: u32,
+    len: u32,
+}
+
+struct Checker {
+    /// The number of FAT entries, which is the number of data clusters plus
+    /// the two reserved entries.
+    fat_len: u32,
+    /// The clusters that belong to a chain walked so far.
+    used: Bitmap,
+    /// Every entry whose chain has been walked, in walk order.
+    owners: Vec<Owner>,
+    bytes_per_cluster: usize,
+    options: CheckOptions,
+    repair: bool,
+    report: Report,
+}
+
+impl Checker {
+    fn new<HANDLE: VFatHandle>(vfat: &VFat<HANDLE>, options: &CheckOptions, repair: bool) -> Checker {
+        let fat_len = vfat.cluster_count() + 2;
+        Checker {
+            fat_len,
+            used: Bitmap::new(fat_len),
+            owners: Vec::new(),
+            bytes_per_cluster: vfat.bytes_per_cluster(),
+            options: *options,
+            repair,
+            report: Report::default(),
+        }
+    }
+
+    /// Checks the whole volume. Returns the report and every lost chain.
+    fn run<HANDLE: VFatHandle>(mut self, vfat: &mut VFat<HANDLE>) -> io::Result<(Report, Vec<LostChain>)> {
+        if let Some(recorded) = vfat.recorded_free_clusters() {
+            let mut actual = 0;
+            for cluster in 2..self.fat_len {
+                if fat_entry(vfat, cluster)? == 0 {
+                    actual += 1;
+                }
+            }
+            if recorded != actual {
+                self.report.problems.push(Problem::FreeCountMismatch { recorded, actual });
+            }
+        }
+
+        // The fixed root directory of FAT12 and FAT16 volumes has no chain.
+        let root = vfat.root_dir_cluster;
+        let root_clusters = match vfat.is_fixed_root(root) {
+            true => vec![0],
+            false => self.check_chain(vfat, "/", root.cluster_number())?,
+        };
+        let mut pending = vec![(String::from("/"), root_clusters)];
+        while let Some((path, clusters)) = pending.pop() {
+            self.report.directories += 1;
+            self.check_dir(vfat, &path, &clusters, &mut pending)?;
+        }
+        self.report.used_clusters = self.used.count();
+
+        let lost_chains = self.find_lost_chains(vfat)?;
+        Ok((self.report, lost_chains))
+    }
+
+    fn is_data(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.fat_len
+    }
+
+    /// Follows the chain starting at the data cluster `start` and marks its
+    /// clusters as used. Returns the clusters that belong to the chain and
+    /// how the walk ended.
+    fn walk<HANDLE: VFatHandle>(&mut self, vfat: &mut VFat<HANDLE>, start: u32) -> io::Result<(Vec<u32>, ChainEnd)> {
+        let mut clusters = Vec::new();
+        let mut current = start;
+        loop {
+            if self.used.get(current) {
+                let end = match clusters.contains(&current) {
+                    true => ChainEnd::Loop(current),
+                    false => ChainEnd::CrossLinked(current),
+                };
+                return Ok((clusters, end));
+            }
+
+            let entry = fat_entry(vfat, current)?;
+            if entry == 0 || entry == BAD_CLUSTER {
+                return Ok((clusters, ChainEnd::BadLink(current, entry)));
+            }
+
+            self.used.set(current);
+            clusters.push(current);
+            match entry {
+                0x0FFFFFF8..=0x0FFFFFFF => return Ok((clusters, ChainEnd::End)),
+                next if self.is_data(next) => current = next,
+                _ => return Ok((clusters, ChainEnd::BadLink(current, entry))),
+            }
+        }
+    }
+
+    /// Returns the path of the entry whose chain holds `cluster`. The chains
+    /// walked so far are followed again rather than remembering the owner of
+    /// every cluster, as only cross-linked chains need it.
+    fn owner_of<HANDLE: VFatHandle>(&self, vfat: &mut VFat<HANDLE>, cluster: u32) -> io::Result<String> {
+        for owner in &self.owners {
+            let mut current = owner.start;
+            for _ in 0..owner.len {
+                if current == cluster {
+                    return Ok(owner.path.clone());
+                }
+                current = fat_entry(vfat, current)?;
+            }
+        }
+        Ok(String::new())
+    }
+
+    /// Sets the FAT entry of `cluster` to `value`, if repairing.
+    fn set_fat_entry<HANDLE: VFatHandle>(&mut self, vfat: &mut VFat<HANDLE>, cluster: u32, value: u32) -> io::Result<()> {
+        if self.repair {
+            vfat.set_fat_entry(Cluster::from(cluster), value)?;
+        }
+        Ok(())
+    }
+
+    /// Walks the chain of the entry `path` starting at `start`, reporting and
+    /// repairing any problem along the way. Returns the clusters that belong
+    /// to the entry, which are none if the chain is unusable.
+    fn check_chain<HANDLE: VFatHandle>(&mut self, vfat: &mut VFat<HANDLE>, path: &str, start: u32) -> io::Result<Vec<u32>> {
+        if !self.is_data(start) {
+            self.report.problems.push(Problem::InvalidStart { path: String::from(path), cluster: start });
+            return Ok(Vec::new());
+        }
+
+        let (clusters, end) = self.walk(vfat, start)?;
+        self.owners.push(Owner { path: String::from(path), start, len: clusters.len() as u32 });
+        let problem = match end {
+            ChainEnd::End => return Ok(clusters),
+            ChainEnd::Loop(cluster) => Problem::ChainLoop { path: String::from(path), cluster },
+            ChainEnd::CrossLinked(cluster) => Problem::CrossLinked {
+                path: String::from(path),
+                other: self.owner_of(vfat, cluster)?,
+                cluster,
+            },
+            ChainEnd::BadLink(cluster, entry) => Problem::BadLink { path: String::from(path), cluster, entry },
+        };
+        self.report.problems.push(problem);
+
+        if let Some(&last) = clusters.last() {
+            self.set_fat_entry(vfat, last, END_OF_CHAIN)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Ends the chain made of `clusters` after its first `keep` clusters and
+    /// frees the rest, if repairing.
+    fn truncate_chain<HANDLE: VFatHandle>(&mut self, vfat: &mut VFat<HANDLE>, clusters: &[u32], keep: usize) -> io::Result<()> {
+        if keep > 0 {
+            self.set_fat_entry(vfat, clusters[keep - 1], END_OF_CHAIN)?;
+        }
+        for &cluster in &clusters[keep..] {
+            self.set_fat_entry(vfat, cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the file `path` whose entry is at `location`.
+    fn check_file<HANDLE: VFatHandle>(
+        &mut self,
+        vfat: &mut VFat<HANDLE>,
+        path: &str,
+        location: EntryLocation,
+        start: u32,
+        size: u32,
+    ) -> io::Result<()> {
+        self.report.files += 1;
+        let clusters = if start == 0 { Vec::new() } else { self.check_chain(vfat, path, start)? };
+
+        let mut new_size = size;
+        let mut kept = clusters.len();
+        let needed = (size as usize).div_ceil(self.bytes_per_cluster);
+        if clusters.len() > needed && self.options.preallocated {
+            self.report.reserved_clusters += (clusters.len() - needed) as u32;
+        } else if clusters.len() != needed {
+            self.report.problems.push(Problem::SizeMismatch {
+                path: String::from(path),
+                size,
+                clusters: clusters.len() as u32,
+            });
+            if clusters.len() > needed {
+                self.truncate_chain(vfat, &clusters, needed)?;
+                kept = needed;
+            } else {
+                new_size = (clusters.len() * self.bytes_per_cluster) as u32;
+            }
+        }
+
+        let new_start = clusters[..kept].first().cloned().unwrap_or(0);
+        if self.repair && (new_start != start || new_size != size) {
+            let entry = vfat.dir_entry_mut(location)?;
+            entry.metadata.set_start_cluster(new_start);
+            entry.set_size(new_size);
+        }
+        Ok(())
+    }
+
+    /// Checks the entries of the directory `path` that is stored in
+    /// `clusters`. Subdirectories are added to `pending`.
+    fn check_dir<HANDLE: VFatHandle>(
+        &mut self,
+        vfat: &mut VFat<HANDLE>,
+        path: &str,
+        clusters: &[u32],
+        pending: &mut Vec<(String, Vec<u32>)>,
+    ) -> io::Result<()> {
+        let mut run: Option<LfnRun> = None;
+        'clusters: for &cluster in clusters {
+            let cluster = Cluster::from(cluster);
+            let slots = EntryIterator::<HANDLE>::load(vfat, cluster)?;
+            for (i, slot) in slots.iter().enumerate() {
+                let location = EntryLocation { cluster, offset: i * size_of::<VFatDirEntry>() };
+                let unknown = unsafe { slot.unknown };
+                if unknown.prev_is_last_entry() {
+                    break 'clusters;
+                } else if unknown.is_deleted_or_unused() {
+                    self.orphan_lfn(vfat, path, run.take().map(LfnRun::into_slots))?;
+                    continue;
+                } else if unknown.is_lnf() {
+                    let lfn = unsafe { slot.long_filename };
+                    let (next, orphans) = LfnRun::next(run.take(), &lfn, location);
+                    run = next;
+                    self.orphan_lfn(vfat, path, Some(orphans))?;
+                    continue;
+                }
+
+                let regular = unsafe { slot.regular };
+                let short_name = regular.short_name();
+                let (long_name, mut entry_slots) = match run.take().map(|run| run.finish(&short_name)) {
+                    Some(Ok((long_name, slots))) => (Some(long_name), slots),
+                    Some(Err(slots)) => {
+                        self.orphan_lfn(vfat, path, Some(slots))?;
+                        (None, Vec::new())
+                    }
+                    None => (None, Vec::new()),
+                };
+                if unknown.is_volume_label() || &short_name == b".          " || &short_name == b"..         " {
+                    continue;
+                }
+
+                let metadata = regular.metadata;
+                let name = long_name.unwrap_or_else(|| {
+                    let mut short_name = short_name;
+                    if short_name[0] == 0x05 {
+                        short_name[0] = 0xE5;
+                    }
+                    display_short_name(&short_name, metadata.case_flags(), vfat.code_page())
+                });
+                let child = if path == "/" { format!("/{}", name) } else { format!("{}/{}", path, name) };
+                let start = metadata.start_cluster();
+                if metadata.attributes.directory() {
+                    let clusters = self.check_chain(vfat, &child, start)?;
+                    if clusters.is_empty() {
+                        if self.repair {
+                            entry_slots.push(location);
+                            Dir::<HANDLE>::mark_deleted(vfat, &entry_slots)?;
+                        }
+                    } else {
+                        pending.push((child, clusters));
+                    }
+                } else {
+                    self.check_file(vfat, &child, location, start, regular.size())?;
+                }
+            }
+        }
+        self.orphan_lfn(vfat, path, run.map(LfnRun::into_slots))
+    }
+
+    /// Reports the long file name entries at `slots`, if any, as orphans and
+    /// deletes them if repairing.
+    fn orphan_lfn<HANDLE: VFatHandle>(
+        &mut self,
+        vfat: &mut VFat<HANDLE>,
+        path: &str,
+        slots: Option<Vec<EntryLocation>>,
+    ) -> io::Result<()> {
+        let slots = match slots {
+            Some(slots) => slots,
+            None => return Ok(()),
+        };
+
+        for location in &slots {
+            self.report.problems.push(Problem::OrphanLfn {
+                path: String::from(path),
+                cluster: location.cluster.cluster_number(),
+                offset: location.offset,
+            });
+        }
+        if self.repair {
+            Dir::<HANDLE>::mark_deleted(vfat, &slots)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if `cluster`, whose FAT entry is `entry`, is allocated
+    /// but belongs to no chain walked so far.
+    fn is_lost(&self, cluster: u32, entry: u32) -> bool {
+        !self.used.get(cluster) && entry != 0 && entry != BAD_CLUSTER
+    }
+
+    /// Finds every allocated cluster that no entry refers to, grouped into
+    /// chains. Lost chains are reported and their ends repaired.
+    fn find_lost_chains<HANDLE: VFatHandle>(&mut self, vfat: &mut VFat<HANDLE>) -> io::Result<Vec<LostChain>> {
+        let mut linked = Bitmap::new(self.fat_len);
+        for cluster in 2..self.fat_len {
+            let next = fat_entry(vfat, cluster)?;
+            if self.is_lost(cluster, next) && self.is_data(next) {
+                linked.set(next);
+            }
+        }
+
+        // Chains are followed from their heads first; whatever is left over
+        // belongs to chains that loop back to themselves.
+        let mut lost_chains = Vec::new();
+        let heads = (2..self.fat_len).filter(|&cluster| !linked.get(cluster)).chain(2..self.fat_len);
+        for head in heads {
+            if !self.is_lost(head, fat_entry(vfat, head)?) {
+                continue;
+            }
+
+            let (clusters, end) = self.walk(vfat, head)?;
+            match end {
+                ChainEnd::End => {}
+                _ => self.set_fat_entry(vfat, *clusters.last().unwrap(), END_OF_CHAIN)?,
+            }
+
+            self.report.problems.push(Problem::LostChain { start: head, clusters: clusters.len() as u32 });
+            lost_chains.push((head, clusters));
+        }
+        Ok(lost_chains)
+    }
+}
+
+/// The FAT entry of `cluster`, upper four bits masked off.
+fn fat_entry<HANDLE: VFatHandle>(vfat: &mut VFat<HANDLE>, cluster: u32) -> io::Result<u32> {
+    Ok(vfat.fat_entry(Cluster::from(cluster))?.0 & 0x0FFFFFFF)
+}
@@ -0,0 +1,196 @@
+//! Creating new FAT32 volumes, like `mkfs.vfat`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use shim::io;
+
+use crate::mbr::{MasterBootRecord, PartitionEntry};
+use crate::traits::BlockDevice;
+use crate::util::SliceExt;
+use crate::vfat::{Attributes, BiosParameterBlock, FsInfo, Metadata, Timestamp, VFatRegularDirEntry};
+
+/// The fewest clusters a FAT32 volume may have. Volumes with fewer clusters
+/// are FAT16 volumes by definition.
+pub const MIN_CLUSTERS: u32 = 65525;
+/// The most clusters a FAT32 volume may have.
+pub const MAX_CLUSTERS: u32 = 0x0FFFFFF5;
+
+/// Partitions start at a multiple of this many bytes.
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+/// The number of reserved sectors in front of the first FAT.
+const RESERVED_SECTORS: u16 = 32;
+/// Partition type of a FAT32 partition addressed by LBA.
+const FAT32_LBA: u8 = 0x0C;
+
+/// Options for `format()`.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// The size of a cluster in bytes: a power of two that is at least the
+    /// device's sector size and at most 32 KiB. If `None`, it is picked from
+    /// the size of the volume.
+    pub cluster_size: Option<u32>,
+    /// The volume label, at most 11 characters. Letters are stored in upper
+    /// case.
+    pub volume_label: Option<String>,
+    /// The volume serial number.
+    pub volume_id: u32,
+    /// The number of copies of the FAT, 1 or 2.
+    pub number_of_fats: u8,
+    /// Whether to write an MBR with a single FAT32 partition covering the
+    /// device. If `false`, the whole device is formatted as one volume.
+    pub partition_table: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            cluster_size: None,
+            volume_label: None,
+            volume_id: 0,
+            number_of_fats: 2,
+            partition_table: true,
+        }
+    }
+}
+
+/// Formats the `sectors` sectors of `device` as an empty FAT32 volume
+/// according to `options`. Any data on the device is lost.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidInput` if an option is invalid, or if the
+/// device is too small or too large for a FAT32 volume with the chosen
+/// cluster size. Returns an error if writing to the device fails.
+pub fn format<T: BlockDevice>(mut device: T, sectors: u64, options: &FormatOptions) -> io::Result<()> {
+    let bytes_per_sector = device.sector_size();
+    if ![512, 1024, 2048, 4096].contains(&bytes_per_sector) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported sector size"));
+    }
+    if options.number_of_fats != 1 && options.number_of_fats != 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "number of FATs must be 1 or 2"));
+    }
+    let volume_label = match options.volume_label {
+        Some(ref label) => label_bytes(label)?,
+        None => *b"NO NAME    ",
+    };
+
+    let start = if options.partition_table { PARTITION_ALIGNMENT / bytes_per_sector } else { 0 };
+    if sectors <= start {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "device too small for FAT32"));
+    }
+    let total_sectors = ::core::cmp::min(sectors - start, u32::MAX as u64);
+
+    let cluster_size = match options.cluster_size {
+        Some(size) => size as u64,
+        None => ::core::cmp::max(default_cluster_size(total_sectors * bytes_per_sector) as u64, bytes_per_sector),
+    };
+    if !cluster_size.is_power_of_two() || cluster_size < bytes_per_sector || cluster_size > 32 * 1024 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid cluster size"));
+    }
+    let sectors_per_cluster = cluster_size / bytes_per_sector;
+
+    // Sizing the FAT for every cluster outside the reserved area overestimates
+    // it slightly, which is harmless.
+    let number_of_fats = options.number_of_fats as u64;
+    let entries_per_sector = bytes_per_sector / size_of::<u32>() as u64;
+    let usable = total_sectors.saturating_sub(RESERVED_SECTORS as u64);
+    let sectors_per_fat = (usable / sectors_per_cluster + 2).div_ceil(entries_per_sector);
+    let data_start = RESERVED_SECTORS as u64 + number_of_fats * sectors_per_fat;
+    let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+    if clusters < MIN_CLUSTERS as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "device too small for FAT32"));
+    }
+    if clusters > MAX_CLUSTERS as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "device too large for FAT32"));
+    }
+
+    let mut bpb = BiosParameterBlock::new_fat32();
+    bpb.bytes_per_sector = bytes_per_sector as u16;
+    bpb.sectors_per_cluster = sectors_per_cluster as u8;
+    bpb.reserved_sectors = RESERVED_SECTORS;
+    bpb.number_of_fat = options.number_of_fats;
+    bpb.total_logical_sectors_2 = total_sectors as u32;
+    bpb.sectors_per_fat_32 = sectors_per_fat as u32;
+    bpb.num_hidden_sectors = start as u32;
+    bpb.volume_id = options.volume_id;
+    bpb.volume_label = volume_label;
+
+    let fs_info = FsInfo::new(Some(clusters as u32 - 1), Some(3));
+
+    let zeroes = vec![0u8; bytes_per_sector as usize];
+    for sector in 0..RESERVED_SECTORS as u64 {
+        device.write_sector(start + sector, &zeroes)?;
+    }
+    let backup = bpb.back_up_boot_sector_number as u64;
+    for &sector in &[0, backup] {
+        device.write_sector(start + sector, &as_sector(&bpb, bytes_per_sector))?;
+        device.write_sector(start + sector + 1, &as_sector(&fs_info, bytes_per_sector))?;
+    }
+
+    // Entries 0 and 1 are reserved; entry 2 ends the root directory's chain.
+    let mut first_fat_sector = zeroes.clone();
+    let reserved: [u32; 3] = [0x0FFFFF00 | 0xF8, 0x0FFFFFFF, 0x0FFFFFFF];
+    first_fat_sector[..12].copy_from_slice(unsafe { reserved.cast() });
+    for copy in 0..number_of_fats {
+        let fat_start = start + RESERVED_SECTORS as u64 + copy * sectors_per_fat;
+        device.write_sector(fat_start, &first_fat_sector)?;
+        for sector in 1..sectors_per_fat {
+            device.write_sector(fat_start + sector, &zeroes)?;
+        }
+    }
+
+    let mut root = vec![0u8; cluster_size as usize];
+    if options.volume_label.is_some() {
+        let label = VFatRegularDirEntry::new(volume_label, Metadata::new(Attributes::VOLUME_ID, 0, Timestamp::EPOCH));
+        let bytes: &[u8] = unsafe { ::core::slice::from_ref(&label).cast() };
+        root[..bytes.len()].copy_from_slice(bytes);
+    }
+    for (i, chunk) in root.chunks(bytes_per_sector as usize).enumerate() {
+        device.write_sector(start + data_start + i as u64, chunk)?;
+    }
+
+    if options.partition_table {
+        let partition = PartitionEntry::new(FAT32_LBA, start as u32, total_sectors as u32);
+        let mbr = MasterBootRecord::with_partition(partition);
+        device.write_sector(0, &as_sector(&mbr, bytes_per_sector))?;
+    }
+    Ok(())
+}
+
+/// Returns the 512 byte on-disk structure `value` padded with zeroes to a
+/// sector of `bytes_per_sector` bytes.
+fn as_sector<S>(value: &S, bytes_per_sector: u64) -> Vec<u8> {
+    let mut sector = vec![0u8; bytes_per_sector as usize];
+    let bytes: &[u8] = unsafe { ::core::slice::from_ref(value).cast() };
+    sector[..bytes.len()].copy_from_slice(bytes);
+    sector
+}
+
+/// The cluster size Microsoft's tools pick for a FAT32 volume of
+/// `volume_bytes` bytes.
+fn default_cluster_size(volume_bytes: u64) -> u32 {
+    const MIB: u64 = 1024 * 1024;
+    match volume_bytes {
+        bytes if bytes <= 260 * MIB => 512,
+        bytes if bytes <= 8 * 1024 * MIB => 4 * 1024,
+        bytes if bytes <= 16 * 1024 * MIB => 8 * 1024,
+        bytes if bytes <= 32 * 1024 * MIB => 16 * 1024,
+        _ => 32 * 1024,
+    }
+}
+
+/// Converts `label` into the space padded form stored on disk.
+fn label_bytes(label: &str) -> io::Result<[u8; 11]> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || " !#$%&'()-@^_`{}~".contains(c);
+    if label.is_empty() || label.len() > 11 || !label.chars().all(valid) || label.starts_with(' ') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid volume label"));
+    }
+
+    let mut bytes = [b' '; 11];
+    for (byte, c) in bytes.iter_mut().zip(label.bytes()) {
+        *byte = c.to_ascii_uppercase();
+    }
+    Ok(bytes)
+}
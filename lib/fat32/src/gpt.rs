@@ -0,0 +1,336 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+
+use shim::const_assert_size;
+use shim::io;
+
+use crate::mbr::{self, MasterBootRecord};
+use crate::traits::BlockDevice;
+
+/// A GUID as stored in GPT structures: the first three fields are little
+/// endian, the last two big endian.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The partition type of an unused partition entry.
+    pub const UNUSED: Guid = Guid([0; 16]);
+
+    /// The partition type of FAT and NTFS data partitions,
+    /// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7.
+    pub const MICROSOFT_BASIC_DATA: Guid = Guid([
+        0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+    ]);
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The GPT header, found at LBA 1 and, as a backup, at the last LBA of the
+/// disk.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    partition_entry_lba: u64,
+    number_of_partition_entries: u32,
+    size_of_partition_entry: u32,
+    partition_entry_array_crc32: u32,
+}
+
+const_assert_size!(GptHeader, 92);
+
+/// An entry of the GPT partition entry array.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct GptPartitionEntry {
+    partition_type: Guid,
+    unique_guid: Guid,
+    starting_lba: u64,
+    ending_lba: u64,
+    attributes: u64,
+    name: [u16; 36],
+}
+
+const_assert_size!(GptPartitionEntry, 128);
+
+const SIGNATURE: [u8; 8] = *b"EFI PART";
+/// MBR partition type of the protective partition covering a GPT disk.
+const PROTECTIVE_TYPE: u8 = 0xEE;
+/// Upper bound on the size of the partition entry array we're willing to read.
+const MAX_ENTRY_ARRAY_BYTES: u64 = 1024 * 1024;
+
+/// A partition of a GPT disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// The type of the partition, such as `Guid::MICROSOFT_BASIC_DATA`.
+    pub partition_type: Guid,
+    /// The GUID unique to this partition.
+    pub unique_guid: Guid,
+    /// The first sector of the partition.
+    pub starting_lba: u64,
+    /// The last sector of the partition, inclusive. Never before
+    /// `starting_lba`.
+    pub ending_lba: u64,
+    /// The partition's attribute flags.
+    pub attributes: u64,
+    /// The name of the partition.
+    pub name: String,
+}
+
+impl GptPartition {
+    /// The number of sectors in the partition.
+    pub fn num_sectors(&self) -> u64 {
+        self.ending_lba - self.starting_lba + 1
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// There was an I/O error while reading the partition table.
+    Io(io::Error),
+    /// The protective MBR is invalid.
+    Mbr(mbr::Error),
+    /// The MBR has no protective GPT partition.
+    NotGpt,
+    /// Neither the primary nor the backup header has a valid signature.
+    BadSignature,
+    /// Neither the primary nor the backup header, along with its partition
+    /// entry array, has valid CRC32 checksums and fields.
+    BadChecksum,
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<mbr::Error> for Error {
+    fn from(error: mbr::Error) -> Self {
+        Error::Mbr(error)
+    }
+}
+
+/// A GUID partition table (GPT).
+#[derive(Debug)]
+pub struct GuidPartitionTable {
+    disk_guid: Guid,
+    partitions: Vec<GptPartition>,
+    from_backup: bool,
+}
+
+impl GuidPartitionTable {
+    /// Reads and returns the GUID partition table from `device`.
+    ///
+    /// The primary header at LBA 1 is used if it and its partition entry
+    /// array are valid. Otherwise the backup header is used. It is looked for
+    /// where a valid primary header points to, and otherwise on the last
+    /// sector of the protective partition.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotGpt` if the MBR has no protective GPT partition.
+    /// Returns `BadSignature` if neither header has a valid signature and
+    /// `BadChecksum` if neither header is valid otherwise. Returns `Mbr(err)`
+    /// if the protective MBR is invalid and `Io(err)` if the I/O error `err`
+    /// occured.
+    pub fn from<T: BlockDevice>(mut device: T) -> Result<GuidPartitionTable, Error> {
+        let mbr = MasterBootRecord::from(&mut device)?;
+        let protective = match (0..4).map(|i| mbr.get_partition(i)).find(|p| p.partition_type == PROTECTIVE_TYPE) {
+            Some(partition) => partition,
+            None => return Err(Error::NotGpt),
+        };
+
+        // The backup header is on the last sector of the disk, which the
+        // protective partition ends at. Only a primary header whose checksum
+        // holds can be trusted to say otherwise.
+        let mut backup_lba = protective.relative_sector as u64 + protective.total_sectors as u64 - 1;
+        let primary = read_header(&mut device, 1)?;
+        if let Some((ref header, ref raw)) = primary {
+            if is_valid_header(header, raw, 1) {
+                if let Some(partitions) = read_partitions(&mut device, header)? {
+                    return Ok(GuidPartitionTable {
+                        disk_guid: header.disk_guid,
+                        partitions,
+                        from_backup: false,
+                    });
+                }
+                backup_lba = header.alternate_lba;
+            }
+        }
+
+        let backup = read_header(&mut device, backup_lba)?;
+        if let Some((ref header, ref raw)) = backup {
+            let partitions = match is_valid_header(header, raw, backup_lba) {
+                true => read_partitions(&mut device, header)?,
+                false => None,
+            };
+            if let Some(partitions) = partitions {
+                return Ok(GuidPartitionTable {
+                    disk_guid: header.disk_guid,
+                    partitions,
+                    from_backup: true,
+                });
+            }
+        }
+
+        match (primary, backup) {
+            (None, None) => Err(Error::BadSignature),
+            _ => Err(Error::BadChecksum),
+        }
+    }
+
+    /// The GUID of the disk.
+    pub fn disk_guid(&self) -> Guid {
+        self.disk_guid
+    }
+
+    /// Every used partition, in partition entry array order. Entries that end
+    /// before they start or reach outside the usable sectors are left out.
+    pub fn partitions(&self) -> &[GptPartition] {
+        &self.partitions
+    }
+
+    /// Whether the primary header or its partition entry array was invalid,
+    /// so the table was read from the backup.
+    pub fn from_backup(&self) -> bool {
+        self.from_backup
+    }
+}
+
+/// Returns `true` if `mbr` has a protective GPT partition, which means the
+/// disk's partitions are in a GUID partition table.
+pub fn is_protective_mbr(mbr: &MasterBootRecord) -> bool {
+    (0..4).any(|i| mbr.get_partition(i).partition_type == PROTECTIVE_TYPE)
+}
+
+/// Reads the GPT header at sector `lba`, along with the whole sector, whose
+/// first `header_size` bytes the header's checksum covers. Returns `None` if
+/// its signature is invalid.
+fn read_header<T: BlockDevice>(device: &mut T, lba: u64) -> io::Result<Option<(GptHeader, Vec<u8>)>> {
+    let mut sector = Vec::new();
+    device.read_all_sector(lba, &mut sector)?;
+    if sector.len() < size_of::<GptHeader>() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GPT header should be 92 bytes"));
+    }
+
+    let mut buf = [0u8; size_of::<GptHeader>()];
+    buf.copy_from_slice(&sector[..size_of::<GptHeader>()]);
+    let header: GptHeader = unsafe { core::mem::transmute(buf) };
+    Ok(match header.signature == SIGNATURE {
+        true => Some((header, sector)),
+        false => None,
+    })
+}
+
+/// Returns `true` if `header`, read from sector `lba` whose contents are
+/// `raw`, has a valid checksum and describes a partition entry array that can
+/// be read.
+fn is_valid_header(header: &GptHeader, raw: &[u8], lba: u64) -> bool {
+    // Later revisions may extend the header; the checksum covers all of it.
+    let header_size = header.header_size as usize;
+    if header_size < size_of::<GptHeader>() || header_size > raw.len() {
+        return false;
+    }
+    let mut header_bytes = raw[..header_size].to_vec();
+    header_bytes[16..20].copy_from_slice(&[0; 4]);
+
+    let entry_size = header.size_of_partition_entry as u64;
+    crc32(&header_bytes) == header.header_crc32
+        && header.my_lba == lba
+        && entry_size >= size_of::<GptPartitionEntry>() as u64
+        && entry_size.is_multiple_of(size_of::<GptPartitionEntry>() as u64)
+        && header.number_of_partition_entries as u64 * entry_size <= MAX_ENTRY_ARRAY_BYTES
+}
+
+/// Reads the partition entry array of the valid header `header`. Returns
+/// `None` if the array's checksum is invalid.
+fn read_partitions<T: BlockDevice>(device: &mut T, header: &GptHeader) -> io::Result<Option<Vec<GptPartition>>> {
+    let entry_size = header.size_of_partition_entry as u64;
+    let array_bytes = header.number_of_partition_entries as u64 * entry_size;
+
+    let sector_size = device.sector_size();
+    let mut array = Vec::with_capacity(array_bytes as usize);
+    for sector in 0..array_bytes.div_ceil(sector_size) {
+        device.read_all_sector(header.partition_entry_lba + sector, &mut array)?;
+    }
+    if (array.len() as u64) < array_bytes {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GPT partition entry array is truncated"));
+    }
+    array.truncate(array_bytes as usize);
+    if crc32(&array) != header.partition_entry_array_crc32 {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for raw in array.chunks(entry_size as usize) {
+        let mut buf = [0u8; size_of::<GptPartitionEntry>()];
+        buf.copy_from_slice(&raw[..size_of::<GptPartitionEntry>()]);
+        let entry: GptPartitionEntry = unsafe { core::mem::transmute(buf) };
+        // Entries outside the usable sectors would overlap the GPT itself.
+        let (starting_lba, ending_lba) = (entry.starting_lba, entry.ending_lba);
+        if entry.partition_type == Guid::UNUSED
+            || starting_lba > ending_lba
+            || starting_lba < header.first_usable_lba
+            || ending_lba > header.last_usable_lba
+        {
+            continue;
+        }
+
+        let name = entry.name;
+        let len = name.iter().position(|&unit| unit == 0).unwrap_or(name.len());
+        partitions.push(GptPartition {
+            partition_type: entry.partition_type,
+            unique_guid: entry.unique_guid,
+            starting_lba,
+            ending_lba,
+            attributes: entry.attributes,
+            name: String::from_utf16_lossy(&name[..len]),
+        });
+    }
+    Ok(Some(partitions))
+}
+
+/// The CRC32 (IEEE 802.3) checksum of `data`, as used by GPT.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,104 @@
+//! Support for mounting volumes on a hosted platform: a thread-safe
+//! `VFatHandle` and `BlockDevice`s backed by `std` streams. Enabled by the
+//! `std` feature.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::traits::BlockDevice;
+use crate::vfat::{VFat, VFatHandle};
+
+/// A `VFatHandle` that shares the file system between threads behind a
+/// mutex.
+#[derive(Clone)]
+pub struct StdVFatHandle(Arc<Mutex<VFat<Self>>>);
+
+impl fmt::Debug for StdVFatHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StdVFatHandle")
+    }
+}
+
+impl VFatHandle for StdVFatHandle {
+    fn new(val: VFat<StdVFatHandle>) -> Self {
+        StdVFatHandle(Arc::new(Mutex::new(val)))
+    }
+
+    /// Runs `f` with the file system locked.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the lock.
+    fn lock<R>(&self, f: impl FnOnce(&mut VFat<StdVFatHandle>) -> R) -> R {
+        f(&mut self.0.lock().expect("file system lock poisoned"))
+    }
+}
+
+/// A `BlockDevice` over a seekable stream, such as a disk image file, with a
+/// sector size of the caller's choosing. Sector `n` starts at byte
+/// `n * sector_size` of the stream.
+#[derive(Debug)]
+pub struct StreamDevice<T> {
+    inner: T,
+    sector_size: u64,
+}
+
+impl<T> StreamDevice<T> {
+    /// Returns a device reading and writing `inner` in sectors of
+    /// `sector_size` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `sector_size` is not a power
+    /// of two of at least 512.
+    pub fn new(inner: T, sector_size: u64) -> io::Result<StreamDevice<T>> {
+        if sector_size < 512 || !sector_size.is_power_of_two() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sector size must be a power of two of at least 512"));
+        }
+        Ok(StreamDevice { inner, sector_size })
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read + Write + Seek + Send> BlockDevice for StreamDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let to_read = ::core::cmp::min(self.sector_size as usize, buf.len());
+        self.read_sectors(n, &mut buf[..to_read])
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let to_write = ::core::cmp::min(self.sector_size as usize, buf.len());
+        self.write_sectors(n, &buf[..to_write])
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(n * self.sector_size))?;
+        self.inner.read_exact(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(n * self.sector_size))?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+}
@@ -10,11 +10,21 @@ extern crate alloc;
 #[cfg(not(target_endian = "little"))]
 compile_error!("only little endian platforms supported");
 
+#[cfg(all(feature = "std", feature = "no_std"))]
+compile_error!("the `std` and `no_std` features are mutually exclusive");
+
 mod mbr;
 #[cfg(test)]
 mod tests;
 mod util;
 
+pub mod blockdev;
+pub mod check;
+pub mod format;
+pub mod gpt;
+#[cfg(any(test, feature = "std"))]
+pub mod host;
+pub mod partition;
 pub mod traits;
 pub mod vfat;
 
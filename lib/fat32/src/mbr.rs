@@ -13,11 +13,30 @@ pub struct CHS {
     cylinder: u8
 }
 
-// FIXME: implement Debug for CHS
+impl CHS {
+    /// The cylinder, combining the high two bits stored with the sector.
+    pub fn cylinder(&self) -> u16 {
+        ((self.sector as u16 & 0xC0) << 2) | self.cylinder as u16
+    }
+
+    /// The head.
+    pub fn head(&self) -> u8 {
+        self.header
+    }
+
+    /// The sector, numbered from 1.
+    pub fn sector(&self) -> u8 {
+        self.sector & 0x3F
+    }
+}
 
 impl fmt::Debug for CHS {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("CHS").finish()
+        f.debug_struct("CHS")
+            .field("cylinder", &self.cylinder())
+            .field("head", &self.head())
+            .field("sector", &self.sector())
+            .finish()
     }
 }
 
@@ -34,11 +53,18 @@ pub struct PartitionEntry {
     pub total_sectors: u32,
 }
 
-// FIXME: implement Debug for PartitionEntry
-
 impl fmt::Debug for PartitionEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("PartitionEntry").finish()
+        let (starting_chs, ending_chs) = (self.starting_chs, self.ending_chs);
+        let (relative_sector, total_sectors) = (self.relative_sector, self.total_sectors);
+        f.debug_struct("PartitionEntry")
+            .field("bootable", &self.is_bootable())
+            .field("partition_type", &format_args!("{:#04x}", self.partition_type))
+            .field("starting_chs", &starting_chs)
+            .field("ending_chs", &ending_chs)
+            .field("relative_sector", &relative_sector)
+            .field("total_sectors", &total_sectors)
+            .finish()
     }
 }
 
@@ -54,14 +80,13 @@ pub struct MasterBootRecord {
     signature: u16,
 }
 
-// FIXME: implemente Debug for MaterBootRecord
-
 impl fmt::Debug for MasterBootRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let signature = self.signature;
         f.debug_struct("MasterBootRecord")
             .field("disk id", &self.disk_id)
             .field("partitions", &self.partition_table)
-            .field("signature", &self.signature)
+            .field("signature", &format_args!("{:#06x}", signature))
             .finish()
     }
 }
@@ -84,7 +109,37 @@ impl From<io::Error> for Error {
     }
 }
 
+impl PartitionEntry {
+    /// Returns `true` if the partition is marked bootable (active).
+    pub fn is_bootable(&self) -> bool {
+        self.boot_indicator == 0x80
+    }
+
+    /// Returns a non-bootable partition entry of type `partition_type` that
+    /// covers `total_sectors` sectors starting at `relative_sector`. The CHS
+    /// addresses are set to the values meaning "use LBA".
+    pub(crate) fn new(partition_type: u8, relative_sector: u32, total_sectors: u32) -> PartitionEntry {
+        let lba = CHS { header: 0xFE, sector: 0xFF, cylinder: 0xFF };
+        PartitionEntry {
+            boot_indicator: 0,
+            starting_chs: lba,
+            partition_type,
+            ending_chs: lba,
+            relative_sector,
+            total_sectors,
+        }
+    }
+}
+
 impl MasterBootRecord {
+    /// Returns an MBR without boot code whose only partition is `partition`.
+    pub(crate) fn with_partition(partition: PartitionEntry) -> MasterBootRecord {
+        let mut mbr: MasterBootRecord = unsafe { core::mem::zeroed() };
+        mbr.partition_table[0] = partition;
+        mbr.signature = 0xAA55;
+        mbr
+    }
+
     /// Reads and returns the master boot record (MBR) from `device`.
     ///
     /// # Errors
@@ -93,9 +148,15 @@ impl MasterBootRecord {
     /// Returns `UnknownBootIndicator(n)` if partition `n` contains an invalid
     /// boot indicator. Returns `Io(err)` if the I/O error `err` occured while
     /// reading the MBR.
-    pub fn from<T: BlockDevice>(mut device: T) -> Result<MasterBootRecord, Error> {
+    pub fn from<T: BlockDevice>(device: T) -> Result<MasterBootRecord, Error> {
+        MasterBootRecord::read_at(device, 0)
+    }
+
+    /// Reads and returns the boot record at sector `sector` of `device`, such
+    /// as an extended boot record (EBR). Errors are as for `from()`.
+    pub(crate) fn read_at<T: BlockDevice>(mut device: T, sector: u64) -> Result<MasterBootRecord, Error> {
         let mut data = [0u8; 512];
-        let bytes = device.read_sector(0, &mut data)?;
+        let bytes = device.read_sector(sector, &mut data)?;
 
         if bytes != 512 {
             return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "MBR should be 512 bytes")));
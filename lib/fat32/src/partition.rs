@@ -0,0 +1,194 @@
+//! Finding the partitions of a device, whatever its layout: an MBR with
+//! primary and logical partitions, a GPT, or a single volume without a
+//! partition table.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::gpt::{self, Guid, GuidPartitionTable};
+use crate::mbr::MasterBootRecord;
+use crate::traits::BlockDevice;
+use crate::vfat::{BiosParameterBlock, Error};
+
+/// MBR partition types that hold FAT volumes.
+const FAT_TYPES: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+/// MBR partition types of extended partitions, which hold a chain of EBRs.
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Upper bound on the number of logical partitions.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// The type of a partition, as recorded in its partition table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionType {
+    /// A primary or logical partition with this MBR partition type.
+    Mbr(u8),
+    /// A GPT partition with this partition type GUID.
+    Gpt(Guid),
+    /// A volume covering the whole device, which has no partition table.
+    Superfloppy,
+}
+
+impl PartitionType {
+    /// Returns `true` if partitions of this type hold FAT volumes.
+    pub fn is_fat(&self) -> bool {
+        match *self {
+            PartitionType::Mbr(partition_type) => FAT_TYPES.contains(&partition_type),
+            PartitionType::Gpt(guid) => guid == Guid::MICROSOFT_BASIC_DATA,
+            PartitionType::Superfloppy => true,
+        }
+    }
+}
+
+/// A partition found on a device by `partitions()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// The type of the partition.
+    pub partition_type: PartitionType,
+    /// The first sector of the partition.
+    pub start: u64,
+    /// The number of sectors in the partition.
+    pub num_sectors: u64,
+}
+
+/// A partition found on a device by `probe()`, along with the identity of the
+/// FAT volume it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    /// The type of the partition.
+    pub partition_type: PartitionType,
+    /// The first sector of the partition.
+    pub start: u64,
+    /// The number of sectors in the partition.
+    pub num_sectors: u64,
+    /// The volume label, if the partition holds a FAT volume with one.
+    pub volume_label: Option<String>,
+    /// The volume serial number, if the partition holds a FAT volume with one.
+    pub volume_id: Option<u32>,
+}
+
+/// Returns every partition of `device`, in the order of `partitions()`, with
+/// the label and serial number of the FAT volume on each FAT partition.
+///
+/// # Errors
+///
+/// Returns the same errors as `partitions()`. A FAT partition whose boot
+/// sector can't be read or is invalid has no label or serial number.
+pub fn probe<T: BlockDevice>(mut device: T) -> Result<Vec<VolumeInfo>, Error> {
+    let mut volumes = Vec::new();
+    for partition in partitions(&mut device)? {
+        let bpb = match partition.partition_type.is_fat() {
+            true => BiosParameterBlock::from(&mut device, partition.start).ok().filter(|bpb| bpb.is_plausible()),
+            false => None,
+        };
+        volumes.push(VolumeInfo {
+            partition_type: partition.partition_type,
+            start: partition.start,
+            num_sectors: partition.num_sectors,
+            volume_label: bpb.as_ref().and_then(|bpb| bpb.label()),
+            volume_id: bpb.as_ref().and_then(|bpb| bpb.serial_number()),
+        });
+    }
+    Ok(volumes)
+}
+
+/// Returns every partition of `device`.
+///
+/// On an MBR disk the primary partitions come first, then the logical
+/// partitions of the extended partition in EBR chain order. Empty entries and
+/// extended partitions themselves aren't listed. On a GPT disk the partitions
+/// are in partition entry array order. A device whose first sector holds no
+/// partition table but a FAT boot sector has a single `Superfloppy`
+/// partition.
+///
+/// # Errors
+///
+/// Returns `Mbr(err)` if the MBR or an EBR is invalid, `Gpt(err)` if the disk
+/// has a protective MBR but no valid GPT, and `Io(err)` if the I/O error `err`
+/// occured.
+pub fn partitions<T: BlockDevice>(mut device: T) -> Result<Vec<PartitionInfo>, Error> {
+    let mbr = MasterBootRecord::from(&mut device);
+    if let Ok(ref mbr) = mbr {
+        if gpt::is_protective_mbr(mbr) || has_partition_table(mbr) {
+            return table_partitions(&mut device, mbr);
+        }
+    }
+
+    if let Ok(bpb) = BiosParameterBlock::from(&mut device, 0) {
+        if bpb.is_plausible() {
+            return Ok(vec![PartitionInfo {
+                partition_type: PartitionType::Superfloppy,
+                start: 0,
+                num_sectors: bpb.total_sectors(),
+            }]);
+        }
+    }
+    mbr?;
+    Ok(Vec::new())
+}
+
+/// Returns `true` if `mbr` lists a partition and every partition it lists is
+/// plausible. The boot code in the same place of a FAT boot sector seldom
+/// passes for such a table.
+fn has_partition_table(mbr: &MasterBootRecord) -> bool {
+    let mut entries = (0..4).map(|i| mbr.get_partition(i)).filter(|entry| entry.partition_type != 0).peekable();
+    entries.peek().is_some() && entries.all(|entry| entry.relative_sector != 0 && entry.total_sectors != 0)
+}
+
+/// Returns the partitions listed by `mbr` and the EBRs and GPT it leads to.
+fn table_partitions<T: BlockDevice>(mut device: T, mbr: &MasterBootRecord) -> Result<Vec<PartitionInfo>, Error> {
+    if gpt::is_protective_mbr(mbr) {
+        let table = GuidPartitionTable::from(&mut device)?;
+        return Ok(table
+            .partitions()
+            .iter()
+            .map(|partition| PartitionInfo {
+                partition_type: PartitionType::Gpt(partition.partition_type),
+                start: partition.starting_lba,
+                num_sectors: partition.num_sectors(),
+            })
+            .collect());
+    }
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for i in 0..4 {
+        let entry = mbr.get_partition(i);
+        match entry.partition_type {
+            0 => {}
+            partition_type if EXTENDED_TYPES.contains(&partition_type) => {
+                extended = extended.or(Some(entry.relative_sector as u64));
+            }
+            partition_type => partitions.push(PartitionInfo {
+                partition_type: PartitionType::Mbr(partition_type),
+                start: entry.relative_sector as u64,
+                num_sectors: entry.total_sectors as u64,
+            }),
+        }
+    }
+
+    // The first entry of each EBR is a logical partition relative to the EBR,
+    // the second links to the next EBR relative to the extended partition.
+    if let Some(extended_start) = extended {
+        let mut ebr_sector = extended_start;
+        let mut visited = Vec::new();
+        while !visited.contains(&ebr_sector) && visited.len() < MAX_LOGICAL_PARTITIONS {
+            visited.push(ebr_sector);
+            let ebr = MasterBootRecord::read_at(&mut device, ebr_sector)?;
+            let logical = ebr.get_partition(0);
+            if logical.partition_type != 0 && logical.total_sectors != 0 {
+                partitions.push(PartitionInfo {
+                    partition_type: PartitionType::Mbr(logical.partition_type),
+                    start: ebr_sector + logical.relative_sector as u64,
+                    num_sectors: logical.total_sectors as u64,
+                });
+            }
+
+            let next = ebr.get_partition(1);
+            if !EXTENDED_TYPES.contains(&next.partition_type) || next.relative_sector == 0 {
+                break;
+            }
+            ebr_sector = extended_start + next.relative_sector as u64;
+        }
+    }
+    Ok(partitions)
+}
@@ -1,12 +1,15 @@
 extern crate rand;
 
-use std::fmt::{self, Debug};
 use std::io;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+use crate::check;
+use crate::format::{self, FormatOptions};
+use crate::gpt;
+use crate::host::{StdVFatHandle, StreamDevice};
 use crate::mbr;
 use crate::traits::*;
 use crate::vfat;
@@ -14,25 +17,6 @@ use crate::vfat;
 use mbr::{MasterBootRecord, PartitionEntry, CHS};
 use vfat::{BiosParameterBlock, VFat, VFatHandle};
 
-#[derive(Clone)]
-struct StdVFatHandle(Arc<Mutex<VFat<Self>>>);
-
-impl Debug for StdVFatHandle {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "StdVFatHandle")
-    }
-}
-
-impl VFatHandle for StdVFatHandle {
-    fn new(val: VFat<StdVFatHandle>) -> Self {
-        StdVFatHandle(Arc::new(Mutex::new(val)))
-    }
-
-    fn lock<R>(&self, f: impl FnOnce(&mut VFat<StdVFatHandle>) -> R) -> R {
-        f(&mut self.0.lock().expect("all okay"))
-    }
-}
-
 macro check_size($T:ty, $size:expr) {
     assert_eq!(
         ::std::mem::size_of::<$T>(),
@@ -265,7 +249,7 @@ fn hash_dir_recursive<P: AsRef<Path>>(
     let path = path.as_ref();
     let dir = vfat.open_dir(path).expect("directory");
 
-    write!(hash, "{}\n", path.display())?;
+    writeln!(hash, "{}", path.display())?;
     let entries = hash_dir(hash, dir)?;
     if entries.iter().any(|e| e.is_dir()) {
         hash.push_str("\n\n");
@@ -474,3 +458,2208 @@ fn shuffle_test() {
     let hash = hash_files_recursive_from(vfat, "/");
     assert_hash_eq!("mock 1 file hashes", hash, hash_for!("files-1"));
 }
+
+/// A `Cursor<Vec<u8>>` image that stays inspectable after a `VFat` takes
+/// ownership of a handle to it.
+#[derive(Clone)]
+struct SharedImage(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl SharedImage {
+    fn new(bytes: Vec<u8>) -> SharedImage {
+        SharedImage(Arc::new(Mutex::new(Cursor::new(bytes))))
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().get_ref().clone()
+    }
+}
+
+impl BlockDevice for SharedImage {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read_sectors(n, buf)
+    }
+}
+
+/// The layout of a FAT volume built by `DiskImage::fat_volume`, in 512 byte
+/// sectors from the start of the volume, with one sector per cluster.
+struct FatLayout {
+    bits: usize,
+    reserved_sectors: usize,
+    sectors_per_fat: usize,
+    root_entries: usize,
+    total_sectors: usize,
+}
+
+impl FatLayout {
+    fn end_of_chain(&self) -> u32 {
+        match self.bits {
+            12 => 0xFFF,
+            16 => 0xFFFF,
+            _ => 0x0FFFFFFF,
+        }
+    }
+
+    fn fat_sector(&self, copy: usize) -> usize {
+        self.reserved_sectors + copy * self.sectors_per_fat
+    }
+
+    fn cluster_sector(&self, cluster: usize) -> usize {
+        self.fat_sector(2) + (self.root_entries * 32).div_ceil(512) + cluster - 2
+    }
+
+    /// The first sector of the root directory: its first cluster on FAT32.
+    fn root_sector(&self) -> usize {
+        if self.bits == 32 { self.cluster_sector(2) } else { self.fat_sector(2) }
+    }
+}
+
+/// A disk image under construction, shared by the fixtures below. Offsets are
+/// in bytes from the start of the disk and numbers are little-endian.
+struct DiskImage {
+    bytes: Vec<u8>,
+}
+
+impl DiskImage {
+    fn new(sectors: usize) -> DiskImage {
+        DiskImage { bytes: vec![0u8; sectors * 512] }
+    }
+
+    fn put(&mut self, at: usize, bytes: &[u8]) {
+        self.bytes[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn put_u16(&mut self, at: usize, value: u16) {
+        self.put(at, &value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, at: usize, value: u32) {
+        self.put(at, &value.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, at: usize, value: u64) {
+        self.put(at, &value.to_le_bytes());
+    }
+
+    /// Writes entry `index` of the partition table in the MBR or EBR at sector
+    /// `record`, and the record's signature. `start` is relative to `record`.
+    fn partition(&mut self, record: usize, index: usize, partition_type: u8, start: usize, sectors: usize) {
+        let entry = record * 512 + 446 + index * 16;
+        self.bytes[entry + 4] = partition_type;
+        self.put_u32(entry + 8, start as u32);
+        self.put_u32(entry + 12, sectors as u32);
+        self.put_u16(record * 512 + 510, 0xAA55);
+    }
+
+    /// Writes the boot sector of a FAT volume laid out as `layout` from sector
+    /// `volume`, and the reserved entries of its two FATs.
+    fn fat_volume(&mut self, volume: usize, layout: &FatLayout) {
+        let bpb = volume * 512;
+        self.put(bpb, if layout.bits == 32 { &[0xEB, 0x58, 0x90] } else { &[0xEB, 0x3C, 0x90] });
+        self.put_u16(bpb + 11, 512);
+        self.bytes[bpb + 13] = 1;
+        self.put_u16(bpb + 14, layout.reserved_sectors as u16);
+        self.bytes[bpb + 16] = 2;
+        self.bytes[bpb + 21] = 0xF8;
+        if layout.bits == 32 {
+            self.put_u32(bpb + 32, layout.total_sectors as u32);
+            self.put_u32(bpb + 36, layout.sectors_per_fat as u32);
+            self.put_u32(bpb + 44, 2);
+            self.put_u16(bpb + 48, 1);
+            self.bytes[bpb + 66] = 0x29;
+        } else {
+            self.put_u16(bpb + 17, layout.root_entries as u16);
+            self.put_u16(bpb + 19, layout.total_sectors as u16);
+            self.put_u16(bpb + 22, layout.sectors_per_fat as u16);
+            self.bytes[bpb + 38] = 0x29;
+            self.put(bpb + 54, if layout.bits == 12 { b"FAT12   " } else { b"FAT16   " });
+        }
+        self.put_u16(bpb + 510, 0xAA55);
+
+        let end_of_chain = layout.end_of_chain();
+        self.set_fat_entry(volume, layout, 0, end_of_chain - 7);
+        self.set_fat_entry(volume, layout, 1, end_of_chain);
+    }
+
+    /// Sets the entry for `cluster` to `value` in both FATs of the volume laid
+    /// out as `layout` from sector `volume`.
+    fn set_fat_entry(&mut self, volume: usize, layout: &FatLayout, cluster: usize, value: u32) {
+        for copy in 0..2 {
+            let fat = (volume + layout.fat_sector(copy)) * 512;
+            match layout.bits {
+                12 => {
+                    let at = fat + cluster + cluster / 2;
+                    let old = u16::from_le_bytes([self.bytes[at], self.bytes[at + 1]]);
+                    let value = value as u16;
+                    let new = if cluster % 2 == 1 { (old & 0x000F) | (value << 4) } else { (old & 0xF000) | value };
+                    self.put_u16(at, new);
+                }
+                16 => self.put_u16(fat + cluster * 2, value as u16),
+                _ => self.put_u32(fat + cluster * 4, value),
+            }
+        }
+    }
+
+    /// Adds a file named `HELLO.TXT` with the contents `contents`, stored
+    /// contiguously from `first_cluster`, as the first root directory entry
+    /// of the volume laid out as `layout` from sector `volume`.
+    fn hello_file(&mut self, volume: usize, layout: &FatLayout, first_cluster: usize, contents: &[u8]) {
+        let clusters = contents.len().div_ceil(512);
+        for cluster in first_cluster..first_cluster + clusters {
+            let next = if cluster + 1 == first_cluster + clusters { layout.end_of_chain() } else { cluster as u32 + 1 };
+            self.set_fat_entry(volume, layout, cluster, next);
+        }
+
+        let entry = (volume + layout.root_sector()) * 512;
+        self.put(entry, b"HELLO   TXT");
+        self.bytes[entry + 11] = 0x20;
+        self.put_u16(entry + 16, 0x0021);
+        if clusters > 0 {
+            self.put_u16(entry + 26, first_cluster as u16);
+        }
+        self.put_u32(entry + 28, contents.len() as u32);
+        self.put((volume + layout.cluster_sector(first_cluster)) * 512, contents);
+    }
+}
+
+const IMG_PARTITION_START: usize = 1;
+const IMG_RESERVED_SECTORS: usize = 32;
+const IMG_SECTORS_PER_FAT: usize = 520;
+const IMG_DATA_START: usize = IMG_RESERVED_SECTORS + 2 * IMG_SECTORS_PER_FAT;
+/// Just above the fewest clusters a FAT32 volume may have.
+const IMG_CLUSTERS: usize = 66000;
+const IMG_TOTAL_SECTORS: usize = IMG_DATA_START + IMG_CLUSTERS;
+
+/// Builds a FAT32 image with 512 byte clusters whose root directory (cluster
+/// 2) holds one file named `HELLO.TXT` with the contents `contents`, stored
+/// contiguously from cluster 3. The FSInfo sector is accurate.
+fn fat32_image(contents: &[u8]) -> Vec<u8> {
+    let layout = FatLayout {
+        bits: 32,
+        reserved_sectors: IMG_RESERVED_SECTORS,
+        sectors_per_fat: IMG_SECTORS_PER_FAT,
+        root_entries: 0,
+        total_sectors: IMG_TOTAL_SECTORS,
+    };
+    let mut img = DiskImage::new(IMG_PARTITION_START + IMG_TOTAL_SECTORS);
+    img.partition(0, 0, 0x0C, IMG_PARTITION_START, IMG_TOTAL_SECTORS);
+    img.fat_volume(IMG_PARTITION_START, &layout);
+    img.set_fat_entry(IMG_PARTITION_START, &layout, 2, layout.end_of_chain());
+    img.hello_file(IMG_PARTITION_START, &layout, 3, contents);
+
+    let clusters = contents.len().div_ceil(512);
+    let fs_info = (IMG_PARTITION_START + 1) * 512;
+    img.put_u32(fs_info, 0x41615252);
+    img.put_u32(fs_info + 484, 0x61417272);
+    img.put_u32(fs_info + 488, (IMG_CLUSTERS - 1 - clusters) as u32);
+    img.put_u32(fs_info + 492, 3 + clusters as u32);
+    img.put_u32(fs_info + 508, 0xAA550000);
+    img.bytes
+}
+
+fn vfat_from_image(image: &SharedImage) -> StdVFatHandle {
+    VFat::<StdVFatHandle>::from(image.clone()).expect("failed to initialize VFAT from image")
+}
+
+fn read_to_vec(vfat: &StdVFatHandle, path: &str) -> Vec<u8> {
+    let mut file = vfat.open_file(path).expect("file exists");
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1000];
+    loop {
+        match file.read(&mut buf).expect("read file") {
+            0 => break,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+    assert_eq!(data.len() as u64, file.size());
+    data
+}
+
+#[test]
+fn test_write_overwrite() {
+    let image = SharedImage::new(fat32_image(b"Hello, world!"));
+    let vfat = vfat_from_image(&image);
+
+    let mut file = vfat.open_file("/hello.txt").expect("file exists");
+    file.seek(io::SeekFrom::Start(7)).unwrap();
+    assert_eq!(file.write(b"there").unwrap(), 5);
+    assert_eq!(file.size(), 13);
+    file.sync().unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(read_to_vec(&remounted, "/HELLO.TXT"), b"Hello, there!");
+}
+
+#[test]
+fn test_write_extend_across_clusters() {
+    let original: Vec<u8> = (0..700).map(|i| i as u8).collect();
+    let image = SharedImage::new(fat32_image(&original));
+    let vfat = vfat_from_image(&image);
+
+    let extra: Vec<u8> = (0..1500).map(|i| (i * 7) as u8).collect();
+    let mut file = vfat.open_file("/HELLO.TXT").expect("file exists");
+    file.seek(io::SeekFrom::Start(600)).unwrap();
+    file.write_all(&extra).unwrap();
+    assert_eq!(file.size(), 2100);
+    file.flush().unwrap();
+
+    let mut expected = original[..600].to_vec();
+    expected.extend_from_slice(&extra);
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(read_to_vec(&remounted, "/HELLO.TXT"), expected);
+
+    // The original two clusters were extended by three freshly allocated ones.
+    let file = remounted.open_file("/HELLO.TXT").unwrap();
+    let chain = remounted.lock(|vfat| vfat.cluster_chain(file.start_cluster)).unwrap();
+    let chain: Vec<u32> = chain.iter().map(|c| c.cluster_number()).collect();
+    assert_eq!(chain, vec![3, 4, 5, 6, 7]);
+
+    // Offsets at the end of a cluster read and write nothing; past it they
+    // are rejected.
+    let mut buf = [0u8; 16];
+    remounted.lock(|vfat| -> io::Result<()> {
+        assert_eq!(vfat.read_cluster(vfat::Cluster::from(3), 512, &mut buf)?, 0);
+        assert_eq!(vfat.write_cluster(vfat::Cluster::from(3), 512, &buf)?, 0);
+        expect_variant!(vfat.read_cluster(vfat::Cluster::from(3), 513, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        expect_variant!(vfat.write_cluster(vfat::Cluster::from(3), 513, &buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn test_write_empty_file() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let mut file = vfat.open_file("/HELLO.TXT").expect("file exists");
+    assert_eq!(file.size(), 0);
+    file.write_all(b"first").unwrap();
+    file.write_all(&[b'!'; 1024]).unwrap();
+    file.sync().unwrap();
+
+    let mut expected = b"first".to_vec();
+    expected.extend_from_slice(&[b'!'; 1024]);
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(read_to_vec(&remounted, "/HELLO.TXT"), expected);
+}
+
+#[test]
+fn test_write_without_sync_leaves_disk_untouched() {
+    let image = SharedImage::new(fat32_image(b"Hello, world!"));
+    let before = image.bytes();
+    let vfat = vfat_from_image(&image);
+
+    let mut file = vfat.open_file("/HELLO.TXT").expect("file exists");
+    file.write_all(b"Jello").unwrap();
+    assert!(image.bytes() == before);
+
+    file.sync().unwrap();
+    assert!(image.bytes() != before);
+}
+
+fn entry_names(vfat: &StdVFatHandle, path: &str) -> Vec<String> {
+    let mut names: Vec<String> = vfat
+        .open_dir(path)
+        .expect("directory exists")
+        .entries()
+        .expect("entries interator")
+        .map(|e| e.name().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_create_file() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let mut file = vfat.create_file("/A rather long file name.text").unwrap();
+    assert_eq!(file.short_name, "ARATHE~1.TEX");
+    file.write_all(b"created").unwrap();
+    file.sync().unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(
+        entry_names(&remounted, "/"),
+        vec!["A rather long file name.text", "HELLO.TXT"]
+    );
+    assert_eq!(read_to_vec(&remounted, "/a RATHER long file name.TEXT"), b"created");
+}
+
+#[test]
+fn test_create_short_and_lowercase_names() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let upper = vfat.create_file("/README.MD").unwrap();
+    assert_eq!((upper.short_name.as_str(), upper.long_name.as_str()), ("README.MD", ""));
+
+    let lower = vfat.create_file("/notes.txt").unwrap();
+    assert_eq!((lower.short_name.as_str(), lower.long_name.as_str()), ("NOTES.TXT", "notes.txt"));
+
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/"), vec!["HELLO.TXT", "README.MD", "notes.txt"]);
+}
+
+#[test]
+fn test_create_unique_short_names() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let names = ["Quarterly report.txt", "Quarterly report 2.txt", "quarterly.report.txt", "hello.txt.bak"];
+    let short_names: Vec<String> = names
+        .iter()
+        .map(|name| vfat.create_file(format!("/{}", name)).unwrap().short_name)
+        .collect();
+    assert_eq!(short_names, vec!["QUARTE~1.TXT", "QUARTE~2.TXT", "QUARTE~3.TXT", "HELLOT~1.BAK"]);
+}
+
+#[test]
+fn test_create_lfn_checksum() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+    vfat.create_file("/lowercase.txt").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    // The root directory holds HELLO.TXT, then one LFN entry and the short
+    // entry of the new file.
+    let bytes = image.bytes();
+    let root = (IMG_PARTITION_START + IMG_DATA_START) * 512;
+    let lfn = &bytes[root + 32..root + 64];
+    let short = &bytes[root + 64..root + 96];
+    assert_eq!(lfn[0], 0x41);
+    assert_eq!(lfn[11], 0x0F);
+    assert_eq!(&short[..11], b"LOWERC~1TXT");
+
+    let checksum = short[..11]
+        .iter()
+        .fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b));
+    assert_eq!(lfn[13], checksum);
+}
+
+#[test]
+fn test_create_errors() {
+    let vfat = vfat_from_image(&SharedImage::new(fat32_image(b"")));
+
+    expect_variant!(vfat.create_file("/hello.TXT").map(|_| ()).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    expect_variant!(vfat.create_dir("/HELLO.TXT").map(|_| ()).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    expect_variant!(vfat.create_file("/bad:name").map(|_| ()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.create_file("relative").map(|_| ()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.create_file("/").map(|_| ()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.create_file("/missing/file").map(|_| ()).unwrap_err().kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_create_dir() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let outer = vfat.create_dir("/Outer Directory").unwrap();
+    let inner = vfat.create_dir("/Outer Directory/inner").unwrap();
+    vfat.create_file("/outer directory/inner/leaf.txt").unwrap().write_all(b"leaf").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/Outer Directory"), vec![".", "..", "inner"]);
+    assert_eq!(entry_names(&remounted, "/Outer Directory/inner"), vec![".", "..", "leaf.txt"]);
+    assert_eq!(read_to_vec(&remounted, "/Outer Directory/inner/../inner/leaf.txt"), b"leaf");
+
+    let dots: Vec<(String, u32)> = remounted
+        .open_dir("/Outer Directory/inner")
+        .unwrap()
+        .entries()
+        .unwrap()
+        .filter(|e| e.name().starts_with('.'))
+        .map(|e| (e.name().to_string(), e.metadata().start_cluster()))
+        .collect();
+    assert_eq!(
+        dots,
+        vec![(".".to_string(), inner.cluster.cluster_number()), ("..".to_string(), outer.cluster.cluster_number())]
+    );
+
+    // `..` of a directory in the root refers to cluster 0.
+    let dotdot = remounted
+        .open_dir("/Outer Directory")
+        .unwrap()
+        .find("..")
+        .unwrap();
+    assert_eq!(dotdot.metadata().start_cluster(), 0);
+}
+
+#[test]
+fn test_create_grows_directory() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    // A 512 byte cluster holds 16 entries; every name here takes 3 of them.
+    let mut expected = vec!["HELLO.TXT".to_string()];
+    for i in 0..40 {
+        let name = format!("generated file {:02}.txt", i);
+        vfat.create_file(format!("/{}", name)).unwrap();
+        expected.push(name);
+    }
+    expected.sort();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/"), expected);
+
+    let root = remounted.lock(|vfat| vfat.root_dir_cluster);
+    let chain = remounted.lock(|vfat| vfat.cluster_chain(root)).unwrap();
+    assert_eq!(chain.len(), 8);
+}
+
+fn fat_status(vfat: &StdVFatHandle, cluster: u32) -> vfat::Status {
+    vfat.lock(|vfat| vfat.fat_entry(vfat::Cluster::from(cluster)).map(|e| e.status()))
+        .unwrap()
+}
+
+#[test]
+fn test_remove_file() {
+    let image = SharedImage::new(fat32_image(&[7u8; 1200]));
+    let vfat = vfat_from_image(&image);
+
+    vfat.create_file("/Some long name.bin").unwrap().write_all(&[1u8; 600]).unwrap();
+    let start = vfat.open_file("/Some long name.bin").unwrap().start_cluster;
+    vfat.remove("/some LONG name.bin").unwrap();
+    vfat.remove("/HELLO.TXT").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert!(entry_names(&remounted, "/").is_empty());
+    expect_variant!(remounted.open("/HELLO.TXT").map(|_| ()).unwrap_err().kind(), io::ErrorKind::NotFound);
+    for cluster in [3, 4, 5, start.cluster_number(), start.cluster_number() + 1].iter() {
+        assert_eq!(fat_status(&remounted, *cluster), vfat::Status::Free);
+    }
+
+    // Every slot of the entries, long file name ones included, is deleted.
+    let bytes = image.bytes();
+    let root = (IMG_PARTITION_START + IMG_DATA_START) * 512;
+    for slot in 0..4 {
+        assert_eq!(bytes[root + slot * 32], 0xE5);
+    }
+}
+
+#[test]
+fn test_remove_errors() {
+    let vfat = vfat_from_image(&SharedImage::new(fat32_image(b"")));
+    vfat.create_dir("/dir").unwrap();
+    vfat.create_file("/dir/file").unwrap();
+
+    expect_variant!(vfat.remove("/missing").unwrap_err().kind(), io::ErrorKind::NotFound);
+    expect_variant!(vfat.remove("/dir").unwrap_err().kind(), io::ErrorKind::Other);
+    expect_variant!(vfat.remove_dir("/HELLO.TXT").unwrap_err().kind(), io::ErrorKind::Other);
+    expect_variant!(vfat.remove_dir("/dir").unwrap_err().kind(), io::ErrorKind::Other);
+    expect_variant!(vfat.remove_dir("/dir/..").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.remove_dir("/").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_remove_dir() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let dir = vfat.create_dir("/Empty Directory").unwrap();
+    vfat.create_file("/Empty Directory/temp").unwrap();
+    vfat.remove("/Empty Directory/temp").unwrap();
+    vfat.remove_dir("/empty directory").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/"), vec!["HELLO.TXT"]);
+    assert_eq!(fat_status(&remounted, dir.cluster.cluster_number()), vfat::Status::Free);
+}
+
+#[test]
+fn test_rename_in_place() {
+    let image = SharedImage::new(fat32_image(b"Hello, world!"));
+    let vfat = vfat_from_image(&image);
+
+    vfat.rename("/HELLO.TXT", "/greeting with a long name.txt").unwrap();
+    vfat.rename("/greeting with a long name.txt", "/Greeting With A Long Name.txt").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/"), vec!["Greeting With A Long Name.txt"]);
+    assert_eq!(read_to_vec(&remounted, "/Greeting With A Long Name.txt"), b"Hello, world!");
+}
+
+#[test]
+fn test_move_across_directories() {
+    let image = SharedImage::new(fat32_image(b"Hello, world!"));
+    let vfat = vfat_from_image(&image);
+
+    let a = vfat.create_dir("/a").unwrap();
+    let b = vfat.create_dir("/a/b").unwrap();
+    vfat.create_file("/a/b/data.log").unwrap().write_all(b"logged").unwrap();
+
+    vfat.rename("/HELLO.TXT", "/a/b/moved.txt").unwrap();
+    vfat.rename("/a/b", "/b").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/"), vec!["a", "b"]);
+    assert_eq!(entry_names(&remounted, "/a"), vec![".", ".."]);
+    assert_eq!(entry_names(&remounted, "/b"), vec![".", "..", "data.log", "moved.txt"]);
+    assert_eq!(read_to_vec(&remounted, "/b/moved.txt"), b"Hello, world!");
+    assert_eq!(read_to_vec(&remounted, "/b/data.log"), b"logged");
+
+    let b_dir = remounted.open_dir("/b").unwrap();
+    assert_eq!(b_dir.cluster, b.cluster);
+    assert_eq!(b_dir.find("..").unwrap().metadata().start_cluster(), 0);
+
+    // Moving `a` below `b` points its `..` at `b`.
+    remounted.rename("/a", "/b/a").unwrap();
+    let dotdot = remounted.open_dir("/b/a").unwrap().find("..").unwrap();
+    assert_eq!(dotdot.metadata().start_cluster(), b.cluster.cluster_number());
+    assert_eq!(remounted.open_dir("/b/a").unwrap().cluster, a.cluster);
+}
+
+#[test]
+fn test_rename_errors() {
+    let vfat = vfat_from_image(&SharedImage::new(fat32_image(b"")));
+    vfat.create_dir("/outer").unwrap();
+    vfat.create_dir("/outer/inner").unwrap();
+    vfat.create_file("/other").unwrap();
+
+    expect_variant!(vfat.rename("/missing", "/x").unwrap_err().kind(), io::ErrorKind::NotFound);
+    expect_variant!(vfat.rename("/other", "/hello.txt").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    expect_variant!(vfat.rename("/outer", "/outer/inner/outer").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.rename("/outer", "/outer/outer").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.rename("/outer/..", "/up").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.rename("/other", "/missing/other").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    // Failed renames leave the entries where they were.
+    assert_eq!(entry_names(&vfat, "/"), vec!["HELLO.TXT", "other", "outer"]);
+    assert_eq!(entry_names(&vfat, "/outer"), vec![".", "..", "inner"]);
+}
+
+fn cached_partition(image: &SharedImage, capacity: usize) -> vfat::CachedPartition {
+    let partition = vfat::Partition { start: 2, num_sectors: 64, sector_size: 1024 };
+    vfat::CachedPartition::with_capacity(image.clone(), partition, capacity)
+}
+
+#[test]
+fn test_cache_lru_eviction_writes_back() {
+    let image = SharedImage::new(vec![0u8; 65 * 1024]);
+    let mut cache = cached_partition(&image, 2);
+
+    cache.get_mut(0).unwrap()[0] = 0xAA;
+    cache.get(1).unwrap();
+    cache.get(0).unwrap();
+    assert_eq!(image.bytes()[1024], 0);
+
+    // Sector 1 is the least recently used one, so it is evicted; sector 0
+    // stays cached and dirty.
+    cache.get(2).unwrap();
+    assert_eq!(image.bytes()[1024], 0);
+    assert_eq!(cache.stats(), vfat::CacheStats { hits: 1, misses: 3, writebacks: 0 });
+
+    // Now the dirty sector 0 has to go and is written back first.
+    cache.get(3).unwrap();
+    cache.get(4).unwrap();
+    assert_eq!(image.bytes()[1024], 0xAA);
+    assert_eq!(cache.stats().writebacks, 1);
+}
+
+#[test]
+fn test_cache_write_sector_and_flush() {
+    let image = SharedImage::new(vec![0u8; 65 * 1024]);
+    let mut cache = cached_partition(&image, 8);
+
+    assert_eq!(cache.write_sector(3, &[0x11; 1024]).unwrap(), 1024);
+    assert_eq!(cache.write_sector(5, &[0x22; 100]).unwrap(), 100);
+    let mut buf = [0u8; 1024];
+    cache.read_sector(3, &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0x11; 1024][..]);
+    assert!(image.bytes().iter().all(|&b| b == 0));
+
+    // A full sector write needn't read the sector first; a partial one does.
+    assert_eq!(cache.stats(), vfat::CacheStats { hits: 1, misses: 1, writebacks: 0 });
+
+    cache.flush().unwrap();
+    let bytes = image.bytes();
+    assert!(bytes[4 * 1024..5 * 1024].iter().all(|&b| b == 0x11));
+    assert!(bytes[6 * 1024..6 * 1024 + 100].iter().all(|&b| b == 0x22));
+    assert!(bytes[6 * 1024 + 100..7 * 1024].iter().all(|&b| b == 0));
+    assert_eq!(cache.stats().writebacks, 2);
+
+    // Nothing is dirty anymore.
+    cache.flush().unwrap();
+    assert_eq!(cache.stats().writebacks, 2);
+
+    expect_variant!(cache.get(64).map(|_| ()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_cache_flushes_on_drop() {
+    let image = SharedImage::new(vec![0u8; 65 * 1024]);
+    let mut cache = cached_partition(&image, 8);
+    cache.get_mut(0).unwrap()[0] = 0x5A;
+    drop(cache);
+    assert_eq!(image.bytes()[1024], 0x5A);
+}
+
+#[test]
+fn test_vfat_with_tiny_cache() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+    vfat.lock(|vfat| vfat.set_cache_capacity(3)).unwrap();
+
+    let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    let mut file = vfat.create_file("/big.bin").unwrap();
+    file.write_all(&data).unwrap();
+    assert!(vfat.lock(|vfat| vfat.cache_stats()).writebacks > 0);
+    file.sync().unwrap();
+
+    assert_eq!(read_to_vec(&vfat, "/big.bin"), data);
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(read_to_vec(&remounted, "/big.bin"), data);
+
+    // Bypass the lookup cache so that the second lookup reads sectors too.
+    vfat.lock(|vfat| vfat.set_lookup_capacity(0));
+    let before = vfat.lock(|vfat| vfat.cache_stats());
+    vfat.open_file("/HELLO.TXT").unwrap();
+    vfat.open_file("/HELLO.TXT").unwrap();
+    let after = vfat.lock(|vfat| vfat.cache_stats());
+    assert!(after.hits > before.hits);
+}
+
+fn statfs(vfat: &StdVFatHandle) -> vfat::FsStats {
+    vfat.lock(|vfat| vfat.statfs()).unwrap()
+}
+
+#[test]
+fn check_fs_info_size() {
+    check_size!(vfat::FsInfo, 512);
+}
+
+#[test]
+fn test_statfs_from_fs_info() {
+    let vfat = vfat_from_image(&SharedImage::new(fat32_image(&[1u8; 1500])));
+    let stats = statfs(&vfat);
+    assert_eq!(stats.cluster_size, 512);
+    assert_eq!(stats.total_clusters, IMG_CLUSTERS as u32);
+    assert_eq!(stats.free_clusters, IMG_CLUSTERS as u32 - 4);
+    assert_eq!(stats.next_free, 6);
+}
+
+#[test]
+fn test_statfs_tracks_allocations() {
+    let image = SharedImage::new(fat32_image(&[1u8; 1500]));
+    let vfat = vfat_from_image(&image);
+
+    vfat.create_file("/three.bin").unwrap().write_all(&[2u8; 1400]).unwrap();
+    assert_eq!(statfs(&vfat).free_clusters, IMG_CLUSTERS as u32 - 7);
+    assert_eq!(statfs(&vfat).next_free, 9);
+
+    // Freed clusters are counted, but allocation carries on at the hint.
+    vfat.remove("/HELLO.TXT").unwrap();
+    assert_eq!(statfs(&vfat).free_clusters, IMG_CLUSTERS as u32 - 4);
+    let mut file = vfat.create_file("/one.bin").unwrap();
+    file.write_all(b"x").unwrap();
+    assert_eq!(file.start_cluster.cluster_number(), 9);
+    file.sync().unwrap();
+
+    let bytes = image.bytes();
+    let fs_info = &bytes[(IMG_PARTITION_START + 1) * 512..];
+    let read_u32 = |at: usize| u32::from_le_bytes([fs_info[at], fs_info[at + 1], fs_info[at + 2], fs_info[at + 3]]);
+    assert_eq!(read_u32(488), IMG_CLUSTERS as u32 - 5);
+    assert_eq!(read_u32(492), 10);
+    let fs_info = vfat.lock(|vfat| vfat.fs_info()).unwrap().unwrap();
+    assert_eq!((fs_info.free_count(), fs_info.next_free()), (Some(IMG_CLUSTERS as u32 - 5), Some(10)));
+
+    let remounted = vfat_from_image(&SharedImage::new(bytes));
+    assert_eq!(statfs(&remounted), statfs(&vfat));
+}
+
+#[test]
+fn test_statfs_without_valid_fs_info() {
+    // An invalid signature, and a free count larger than the volume, both
+    // make the FAT the source of truth.
+    let mut bad_signature = fat32_image(&[1u8; 1500]);
+    bad_signature[(IMG_PARTITION_START + 1) * 512] = 0;
+    let mut bad_count = fat32_image(&[1u8; 1500]);
+    bad_count[(IMG_PARTITION_START + 1) * 512 + 488..][..4].copy_from_slice(&[0xF0, 0xFF, 0x0F, 0]);
+    let mut no_fs_info = fat32_image(&[1u8; 1500]);
+    no_fs_info[IMG_PARTITION_START * 512 + 48..][..2].copy_from_slice(&[0xFF, 0xFF]);
+
+    for bytes in [bad_signature, bad_count, no_fs_info] {
+        let image = SharedImage::new(bytes);
+        let vfat = vfat_from_image(&image);
+        assert_eq!(statfs(&vfat).free_clusters, IMG_CLUSTERS as u32 - 4);
+
+        vfat.create_file("/new.bin").unwrap().write_all(b"data").unwrap();
+        assert_eq!(statfs(&vfat).free_clusters, IMG_CLUSTERS as u32 - 5);
+        vfat.lock(|vfat| vfat.sync()).unwrap();
+        assert_eq!(read_to_vec(&vfat_from_image(&SharedImage::new(image.bytes())), "/new.bin"), b"data");
+    }
+}
+
+#[test]
+fn test_alloc_fails_when_full() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+
+    let mut file = vfat.create_file("/fill.bin").unwrap();
+    let chunk = vec![0u8; 64 * 1024];
+    let err = loop {
+        if let Err(e) = file.write_all(&chunk) {
+            break e;
+        }
+    };
+    expect_variant!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(statfs(&vfat).free_clusters, 0);
+    assert_eq!(file.size() as usize, (IMG_CLUSTERS - 1) * 512);
+}
+
+/// Byte offset in the image of the FAT entry for `cluster` in FAT `copy`.
+fn fat_entry_offset(copy: usize, cluster: usize) -> usize {
+    (IMG_PARTITION_START + IMG_RESERVED_SECTORS + copy * IMG_SECTORS_PER_FAT) * 512 + cluster * 4
+}
+
+fn image_fat_entry(bytes: &[u8], copy: usize, cluster: usize) -> u32 {
+    let at = fat_entry_offset(copy, cluster);
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+#[test]
+fn test_fat_updates_are_mirrored() {
+    let image = SharedImage::new(fat32_image(&[1u8; 100]));
+    let vfat = vfat_from_image(&image);
+    assert_eq!(vfat.lock(|vfat| vfat.number_of_fats()), 2);
+    assert!(vfat.lock(|vfat| vfat.fat_mirroring()));
+
+    vfat.create_file("/big.bin").unwrap().write_all(&[2u8; 2000]).unwrap();
+    vfat.remove("/HELLO.TXT").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let bytes = image.bytes();
+    for cluster in 0..IMG_CLUSTERS + 2 {
+        assert_eq!(image_fat_entry(&bytes, 0, cluster), image_fat_entry(&bytes, 1, cluster));
+    }
+    assert_eq!(image_fat_entry(&bytes, 1, 3), 0);
+    assert!(vfat.lock(|vfat| vfat.verify_fat_mirrors()).unwrap().is_empty());
+}
+
+#[test]
+fn test_fat_mirror_divergence() {
+    let mut bytes = fat32_image(&[1u8; 1500]);
+    let at = fat_entry_offset(1, 4);
+    bytes[at..at + 4].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+    let at = fat_entry_offset(0, 100);
+    bytes[at..at + 4].copy_from_slice(&7u32.to_le_bytes());
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+
+    let mismatches = vfat.lock(|vfat| vfat.verify_fat_mirrors()).unwrap();
+    assert_eq!(mismatches, vec![
+        vfat::FatMismatch { cluster: 4, entries: vec![5, 0x0FFFFFFF] },
+        vfat::FatMismatch { cluster: 100, entries: vec![7, 0] },
+    ]);
+
+    // The backup is good except for cluster 4; the primary wins for that.
+    vfat.lock(|vfat| vfat.restore_fat(1)).unwrap();
+    let mismatches = vfat.lock(|vfat| vfat.verify_fat_mirrors()).unwrap();
+    assert!(mismatches.is_empty());
+    assert_eq!(fat_status(&vfat, 100), vfat::Status::Free);
+
+    let err = vfat.lock(|vfat| vfat.restore_fat(2)).unwrap_err();
+    expect_variant!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_fat_mirroring_disabled() {
+    // Bit 7 of the extended flags disables mirroring; FAT 1 is active.
+    let mut bytes = fat32_image(&[1u8; 100]);
+    bytes[IMG_PARTITION_START * 512 + 40] = 0x81;
+    let at = fat_entry_offset(1, 3);
+    let entry = bytes[fat_entry_offset(0, 3)..][..4].to_vec();
+    bytes[at..at + 4].copy_from_slice(&entry);
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+    assert!(!vfat.lock(|vfat| vfat.fat_mirroring()));
+
+    vfat.create_file("/new.bin").unwrap().write_all(b"data").unwrap();
+    let start = vfat.open_file("/new.bin").unwrap().start_cluster;
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let bytes = image.bytes();
+    assert_eq!(image_fat_entry(&bytes, 1, start.cluster_number() as usize), 0x0FFFFFFF);
+    assert_eq!(image_fat_entry(&bytes, 0, start.cluster_number() as usize), 0);
+    assert_eq!(read_to_vec(&vfat, "/new.bin"), b"data");
+}
+
+fn set_image_fat_entry(bytes: &mut [u8], cluster: usize, value: u32) {
+    for copy in 0..2 {
+        let at = fat_entry_offset(copy, cluster);
+        bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[test]
+fn test_check_clean_volume() {
+    let image = SharedImage::new(fat32_image(&[1u8; 1500]));
+    let vfat = vfat_from_image(&image);
+    vfat.create_dir("/dir").unwrap();
+    vfat.create_file("/dir/A long file name.txt").unwrap().write_all(&[2u8; 700]).unwrap();
+
+    let report = check::check(&vfat).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert!(!report.repaired);
+    assert_eq!((report.files, report.directories), (2, 2));
+    assert_eq!(report.used_clusters, 1 + 3 + 1 + 2);
+}
+
+#[test]
+fn test_check_chain_loop() {
+    let mut bytes = fat32_image(&[1u8; 1500]);
+    set_image_fat_entry(&mut bytes, 5, 3);
+    let image = SharedImage::new(bytes.clone());
+    let vfat = vfat_from_image(&image);
+
+    let report = check::check(&vfat).unwrap();
+    assert_eq!(report.problems, vec![check::Problem::ChainLoop { path: "/HELLO.TXT".into(), cluster: 3 }]);
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    assert!(image.bytes() == bytes, "checking modified the volume");
+
+    assert!(check::repair(&vfat, check::LostChains::Reclaim).unwrap().repaired);
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert!(check::check(&remounted).unwrap().is_clean());
+    assert_eq!(read_to_vec(&remounted, "/HELLO.TXT"), vec![1u8; 1500]);
+}
+
+#[test]
+fn test_check_cross_linked() {
+    let image = SharedImage::new(fat32_image(&[1u8; 1500]));
+    let vfat = vfat_from_image(&image);
+    vfat.create_file("/b.bin").unwrap().write_all(&[2u8; 1000]).unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let mut bytes = image.bytes();
+    set_image_fat_entry(&mut bytes, 7, 4);
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+
+    let report = check::check(&vfat).unwrap();
+    assert_eq!(report.problems, vec![check::Problem::CrossLinked {
+        path: "/b.bin".into(),
+        other: "/HELLO.TXT".into(),
+        cluster: 4,
+    }]);
+
+    check::repair(&vfat, check::LostChains::Reclaim).unwrap();
+    assert!(check::check(&vfat).unwrap().is_clean());
+    assert_eq!(read_to_vec(&vfat, "/b.bin"), vec![2u8; 1000]);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), vec![1u8; 1500]);
+}
+
+#[test]
+fn test_check_saves_lost_chains() {
+    // HELLO.TXT loses its last cluster, and two more clusters are allocated
+    // without any entry referring to them.
+    let mut bytes = fat32_image(&[1u8; 1500]);
+    set_image_fat_entry(&mut bytes, 4, 0x0FFFFFFF);
+    set_image_fat_entry(&mut bytes, 100, 101);
+    set_image_fat_entry(&mut bytes, 101, 0x0FFFFFFF);
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+
+    let report = check::check(&vfat).unwrap();
+    assert_eq!(report.problems, vec![
+        check::Problem::FreeCountMismatch { recorded: IMG_CLUSTERS as u32 - 4, actual: IMG_CLUSTERS as u32 - 6 },
+        check::Problem::SizeMismatch { path: "/HELLO.TXT".into(), size: 1500, clusters: 2 },
+        check::Problem::LostChain { start: 5, clusters: 1 },
+        check::Problem::LostChain { start: 100, clusters: 2 },
+    ]);
+
+    check::repair(&vfat, check::LostChains::Save).unwrap();
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert!(check::check(&remounted).unwrap().is_clean());
+    assert_eq!(entry_names(&remounted, "/FOUND.000"), vec![".", "..", "FILE0000.CHK", "FILE0001.CHK"]);
+    assert_eq!(read_to_vec(&remounted, "/HELLO.TXT"), vec![1u8; 1024]);
+
+    let mut lost = vec![1u8; 476];
+    lost.resize(512, 0);
+    assert_eq!(read_to_vec(&remounted, "/FOUND.000/FILE0000.CHK"), lost);
+    assert_eq!(read_to_vec(&remounted, "/FOUND.000/FILE0001.CHK"), vec![0u8; 1024]);
+}
+
+#[test]
+fn test_check_bad_link_and_orphan_lfn() {
+    let image = SharedImage::new(fat32_image(&[1u8; 1500]));
+    let vfat = vfat_from_image(&image);
+    vfat.create_file("/Some long name.txt").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    // The first of the two LFN entries gets a wrong checksum, and the second
+    // cluster of HELLO.TXT is marked bad.
+    let mut bytes = image.bytes();
+    let root = (IMG_PARTITION_START + IMG_DATA_START) * 512;
+    bytes[root + 32 + 13] ^= 0xFF;
+    set_image_fat_entry(&mut bytes, 4, 0x0FFFFFF7);
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+
+    let report = check::check(&vfat).unwrap();
+    assert_eq!(report.problems, vec![
+        check::Problem::BadLink { path: "/HELLO.TXT".into(), cluster: 4, entry: 0x0FFFFFF7 },
+        check::Problem::SizeMismatch { path: "/HELLO.TXT".into(), size: 1500, clusters: 1 },
+        check::Problem::OrphanLfn { path: "/".into(), cluster: 2, offset: 32 },
+        check::Problem::OrphanLfn { path: "/".into(), cluster: 2, offset: 64 },
+        check::Problem::LostChain { start: 5, clusters: 1 },
+    ]);
+
+    check::repair(&vfat, check::LostChains::Reclaim).unwrap();
+    assert!(check::check(&vfat).unwrap().is_clean());
+    assert_eq!(entry_names(&vfat, "/"), vec!["HELLO.TXT", "SOMELO~1.TXT"]);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), vec![1u8; 512]);
+    assert_eq!(statfs(&vfat).free_clusters, IMG_CLUSTERS as u32 - 3);
+}
+
+#[test]
+fn test_check_chain_longer_than_size() {
+    // HELLO.TXT keeps its three clusters but claims to be empty.
+    let mut bytes = fat32_image(&[1u8; 1500]);
+    let root = (IMG_PARTITION_START + IMG_DATA_START) * 512;
+    bytes[root + 28..root + 32].copy_from_slice(&[0; 4]);
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+
+    let report = check::check(&vfat).unwrap();
+    assert_eq!(report.problems, vec![
+        check::Problem::SizeMismatch { path: "/HELLO.TXT".into(), size: 0, clusters: 3 },
+    ]);
+    assert_eq!(report.reserved_clusters, 0);
+    let options = check::CheckOptions { preallocated: true };
+    assert_eq!(check::check_with(&vfat, &options).unwrap().reserved_clusters, 3);
+
+    check::repair(&vfat, check::LostChains::Reclaim).unwrap();
+    assert!(check::check(&vfat).unwrap().is_clean());
+    assert_eq!(vfat.open_file("/HELLO.TXT").unwrap().start_cluster.cluster_number(), 0);
+    assert_eq!(statfs(&vfat).free_clusters, IMG_CLUSTERS as u32 - 1);
+}
+
+/// Sectors of the devices formatted in tests: a 1 MiB aligned partition with
+/// room for a little over the minimum number of FAT32 clusters.
+const FORMAT_SECTORS: u64 = 2048 + 70_000;
+
+fn formatted_image(options: &FormatOptions) -> SharedImage {
+    let image = SharedImage::new(vec![0u8; FORMAT_SECTORS as usize * 512]);
+    format::format(image.clone(), FORMAT_SECTORS, options).expect("format device");
+    image
+}
+
+#[test]
+fn test_format_mounts_empty_volume() {
+    let image = formatted_image(&FormatOptions {
+        volume_label: Some("rust os".into()),
+        ..FormatOptions::default()
+    });
+    let vfat = vfat_from_image(&image);
+
+    assert!(entry_names(&vfat, "/").is_empty());
+    let stats = statfs(&vfat);
+    assert_eq!(stats.cluster_size, 512);
+    assert!(stats.total_clusters >= format::MIN_CLUSTERS);
+    assert_eq!(stats.free_clusters, stats.total_clusters - 1);
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    vfat.create_dir("/dir").unwrap();
+    vfat.create_file("/dir/data.bin").unwrap().write_all(&[9u8; 3000]).unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(read_to_vec(&remounted, "/dir/data.bin"), vec![9u8; 3000]);
+    assert!(check::check(&remounted).unwrap().is_clean());
+
+    // The label is stored in the boot sector and the root directory.
+    let bytes = image.bytes();
+    assert_eq!(&bytes[2048 * 512 + 71..][..11], b"RUST OS    ");
+}
+
+#[test]
+fn test_format_layout() {
+    let image = formatted_image(&FormatOptions {
+        cluster_size: Some(512),
+        number_of_fats: 1,
+        volume_id: 0xCAFEF00D,
+        ..FormatOptions::default()
+    });
+    let bytes = image.bytes();
+
+    let mbr = MasterBootRecord::from(Cursor::new(bytes.clone())).expect("valid MBR");
+    assert_eq!(mbr.get_partition(0).partition_type, 0x0C);
+    assert_eq!({ mbr.get_partition(0).relative_sector }, 2048);
+    assert_eq!({ mbr.get_partition(0).total_sectors }, 70_000);
+
+    let boot = 2048 * 512;
+    assert_eq!(&bytes[boot..boot + 512], &bytes[boot + 6 * 512..boot + 7 * 512]);
+    assert_eq!(&bytes[boot + 67..boot + 71], &0xCAFEF00Du32.to_le_bytes());
+    assert_eq!(&bytes[boot + 82..boot + 90], b"FAT32   ");
+    assert_eq!(&bytes[boot + 512..boot + 1024], &bytes[boot + 7 * 512..boot + 8 * 512]);
+    assert_eq!(vfat_from_image(&image).lock(|vfat| vfat.number_of_fats()), 1);
+
+    // 70000 sectors hold too few 4 KiB clusters for FAT32.
+    let image = SharedImage::new(vec![0u8; FORMAT_SECTORS as usize * 512]);
+    let err = format::format(image, FORMAT_SECTORS, &FormatOptions {
+        cluster_size: Some(4096),
+        ..FormatOptions::default()
+    });
+    expect_variant!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_stream_device_sector_size() {
+    for &sector_size in &[0, 256, 500, 1000, 1536] {
+        let err = StreamDevice::new(Cursor::new(Vec::<u8>::new()), sector_size).unwrap_err();
+        expect_variant!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // A volume on a device with 1 KiB sectors round trips through a plain
+    // stream.
+    let stream = Cursor::new(vec![0u8; FORMAT_SECTORS as usize * 1024]);
+    let mut device = StreamDevice::new(stream, 1024).unwrap();
+    format::format(&mut device, FORMAT_SECTORS, &FormatOptions::default()).unwrap();
+    let bytes = device.into_inner().into_inner();
+    assert_eq!(&bytes[1024 * 1024 + 11..][..2], &1024u16.to_le_bytes());
+
+    let vfat = VFat::<StdVFatHandle>::from(StreamDevice::new(Cursor::new(bytes), 1024).unwrap()).unwrap();
+    assert_eq!(statfs(&vfat).cluster_size, 1024);
+    vfat.create_file("/data.bin").unwrap().write_all(&[5u8; 5000]).unwrap();
+    assert_eq!(read_to_vec(&vfat, "/data.bin"), vec![5u8; 5000]);
+    assert!(check::check(&vfat).unwrap().is_clean());
+}
+
+#[test]
+fn test_format_errors() {
+    let invalid = vec![
+        (FORMAT_SECTORS, FormatOptions { cluster_size: Some(3000), ..FormatOptions::default() }),
+        (FORMAT_SECTORS, FormatOptions { cluster_size: Some(64 * 1024), ..FormatOptions::default() }),
+        (FORMAT_SECTORS, FormatOptions { number_of_fats: 3, ..FormatOptions::default() }),
+        (FORMAT_SECTORS, FormatOptions { volume_label: Some("much too long".into()), ..FormatOptions::default() }),
+        (FORMAT_SECTORS, FormatOptions { volume_label: Some("bad/label".into()), ..FormatOptions::default() }),
+        (40_000, FormatOptions::default()),
+        (1000, FormatOptions::default()),
+    ];
+    for (sectors, options) in invalid {
+        let image = SharedImage::new(vec![0u8; FORMAT_SECTORS as usize * 512]);
+        let err = format::format(image, sectors, &options).unwrap_err();
+        expect_variant!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+/// Builds a FAT12 or FAT16 image, as the spec dictates for `clusters` 512 byte
+/// clusters, in a partition of type `partition_type`. The fixed root directory
+/// has room for 16 entries and holds one file named `HELLO.TXT` with the
+/// contents `contents`, stored contiguously from cluster 2.
+fn fat16_image(partition_type: u8, clusters: usize, contents: &[u8]) -> Vec<u8> {
+    let bits = if clusters < 4085 { 12 } else { 16 };
+    let fat_bytes = if bits == 12 { (clusters + 2) * 3 / 2 + 1 } else { (clusters + 2) * 2 };
+    let sectors_per_fat = fat_bytes.div_ceil(512);
+    let total_sectors = 1 + 2 * sectors_per_fat + 1 + clusters;
+    let layout = FatLayout { bits, reserved_sectors: 1, sectors_per_fat, root_entries: 16, total_sectors };
+
+    let mut img = DiskImage::new(1 + total_sectors);
+    img.partition(0, 0, partition_type, 1, total_sectors);
+    img.fat_volume(1, &layout);
+    img.hello_file(1, &layout, 2, contents);
+    img.bytes
+}
+
+#[test]
+fn test_fat_type_from_cluster_count() {
+    use vfat::FatType;
+
+    assert_eq!(FatType::from_cluster_count(1), FatType::Fat12);
+    assert_eq!(FatType::from_cluster_count(4084), FatType::Fat12);
+    assert_eq!(FatType::from_cluster_count(4085), FatType::Fat16);
+    assert_eq!(FatType::from_cluster_count(65524), FatType::Fat16);
+    assert_eq!(FatType::from_cluster_count(65525), FatType::Fat32);
+
+    // The partition type doesn't matter; the cluster count does.
+    let vfat = vfat_from_image(&SharedImage::new(fat16_image(0x0C, 5000, b"")));
+    assert_eq!(vfat.lock(|vfat| vfat.fat_type()), FatType::Fat16);
+    let vfat = vfat_from_image(&SharedImage::new(fat32_image(b"")));
+    assert_eq!(vfat.lock(|vfat| vfat.fat_type()), FatType::Fat32);
+}
+
+#[test]
+fn test_fat16_volume() {
+    let hello: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let image = SharedImage::new(fat16_image(0x0E, 5000, &hello));
+    let vfat = vfat_from_image(&image);
+    assert_eq!(vfat.lock(|vfat| vfat.fat_type()), vfat::FatType::Fat16);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), hello);
+
+    vfat.create_dir("/A directory").unwrap();
+    vfat.create_file("/A directory/data.bin").unwrap().write_all(&[5u8; 3000]).unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let remounted = vfat_from_image(&SharedImage::new(image.bytes()));
+    assert_eq!(entry_names(&remounted, "/"), vec!["A directory", "HELLO.TXT"]);
+    assert_eq!(read_to_vec(&remounted, "/A directory/data.bin"), vec![5u8; 3000]);
+    assert!(check::check(&remounted).unwrap().is_clean());
+    assert_eq!(statfs(&remounted).free_clusters, 5000 - 3 - 1 - 6);
+
+    // `..` of a subdirectory of the fixed root directory is cluster 0.
+    remounted.rename("/A directory/data.bin", "/data.bin").unwrap();
+    assert_eq!(read_to_vec(&remounted, "/data.bin"), vec![5u8; 3000]);
+}
+
+#[test]
+fn test_fat12_volume() {
+    let image = SharedImage::new(fat16_image(0x01, 1000, &[3u8; 1500]));
+    let vfat = vfat_from_image(&image);
+    assert_eq!(vfat.lock(|vfat| vfat.fat_type()), vfat::FatType::Fat12);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), vec![3u8; 1500]);
+
+    vfat.create_file("/new.bin").unwrap().write_all(&[4u8; 2000]).unwrap();
+    vfat.remove("/HELLO.TXT").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    // Two FAT12 entries share three bytes.
+    let bytes = image.bytes();
+    let fat12_entry = |copy: usize, cluster: usize| {
+        let at = (2 + copy * 3) * 512 + cluster + cluster / 2;
+        let value = u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        if cluster % 2 == 1 { value >> 4 } else { value & 0xFFF }
+    };
+    for copy in 0..2 {
+        let entries: Vec<u16> = (2..9).map(|cluster| fat12_entry(copy, cluster)).collect();
+        assert_eq!(entries, vec![0, 0, 0, 6, 7, 8, 0xFFF]);
+    }
+
+    let remounted = vfat_from_image(&SharedImage::new(bytes));
+    assert_eq!(read_to_vec(&remounted, "/new.bin"), vec![4u8; 2000]);
+    assert!(check::check(&remounted).unwrap().is_clean());
+    assert_eq!(statfs(&remounted).free_clusters, 1000 - 4);
+
+    // The entry for cluster 341 straddles the first two sectors of the FAT.
+    remounted.lock(|vfat| vfat.set_fat_entry(vfat::Cluster::from(341), 0xABC)).unwrap();
+    remounted.lock(|vfat| vfat.set_fat_entry(vfat::Cluster::from(340), 0x0FFFFFFF)).unwrap();
+    assert_eq!(fat_status(&remounted, 341), vfat::Status::Data(vfat::Cluster::from(0xABC)));
+    assert_eq!(fat_status(&remounted, 342), vfat::Status::Free);
+}
+
+#[test]
+fn test_fixed_root_dir_full() {
+    let vfat = vfat_from_image(&SharedImage::new(fat16_image(0x06, 5000, b"")));
+    for i in 0..15 {
+        vfat.create_file(format!("/FILE{}.TXT", i)).unwrap();
+    }
+    let err = vfat.create_file("/ONE.TOO").map(|_| ()).unwrap_err();
+    expect_variant!(err.kind(), io::ErrorKind::Other);
+
+    // A lower case name needs a long file name entry too, which doesn't fit.
+    vfat.remove("/FILE0.TXT").unwrap();
+    expect_variant!(vfat.create_dir("/dir").map(|_| ()).unwrap_err().kind(), io::ErrorKind::Other);
+
+    // Subdirectories still grow.
+    vfat.create_dir("/DIR").unwrap();
+    for i in 0..40 {
+        vfat.create_file(format!("/DIR/file number {}", i)).unwrap();
+    }
+    assert_eq!(entry_names(&vfat, "/DIR").len(), 42);
+    let report = check::check(&vfat).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+const LINUX_FILESYSTEM: gpt::Guid = gpt::Guid([
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+]);
+
+/// Builds a GPT disk with two partitions: a small Linux filesystem partition
+/// and, after it, a Microsoft basic data partition holding `volume`. Both the
+/// primary and the backup table are valid.
+fn gpt_image(volume: &[u8]) -> Vec<u8> {
+    const ENTRY_SECTORS: usize = 32;
+    let linux_start = 2 + ENTRY_SECTORS;
+    let volume_start = linux_start + 8;
+    let volume_sectors = volume.len() / 512;
+    let backup_entries = volume_start + volume_sectors;
+    let last_lba = backup_entries + ENTRY_SECTORS;
+    let mut img = DiskImage::new(last_lba + 1);
+
+    // Protective MBR covering the whole disk.
+    img.partition(0, 0, 0xEE, 1, last_lba);
+
+    let partitions = [
+        (LINUX_FILESYSTEM, linux_start, volume_start - 1, "linux"),
+        (gpt::Guid::MICROSOFT_BASIC_DATA, volume_start, backup_entries - 1, "Basic data partition"),
+    ];
+    for (i, &(partition_type, first, last, name)) in partitions.iter().enumerate() {
+        let entry = 2 * 512 + i * 128;
+        img.put(entry, &partition_type.0);
+        img.put(entry + 16, &[i as u8 + 1; 16]);
+        img.put_u64(entry + 32, first as u64);
+        img.put_u64(entry + 40, last as u64);
+        for (j, unit) in name.encode_utf16().enumerate() {
+            img.put_u16(entry + 56 + 2 * j, unit);
+        }
+    }
+    let entries = img.bytes[2 * 512..(2 + ENTRY_SECTORS) * 512].to_vec();
+    img.put(backup_entries * 512, &entries);
+    let entries_crc = gpt::crc32(&entries);
+
+    for &(my_lba, alternate_lba, entries_lba) in &[(1, last_lba, 2), (last_lba, 1, backup_entries)] {
+        let header = my_lba * 512;
+        img.put(header, b"EFI PART");
+        img.put_u32(header + 8, 0x00010000);
+        img.put_u32(header + 12, 92);
+        img.put_u64(header + 24, my_lba as u64);
+        img.put_u64(header + 32, alternate_lba as u64);
+        img.put_u64(header + 40, linux_start as u64);
+        img.put_u64(header + 48, backup_entries as u64 - 1);
+        img.put(header + 56, &[0x42; 16]);
+        img.put_u64(header + 72, entries_lba as u64);
+        img.put_u32(header + 80, 128);
+        img.put_u32(header + 84, 128);
+        img.put_u32(header + 88, entries_crc);
+        let crc = gpt::crc32(&img.bytes[header..header + 92]);
+        img.put_u32(header + 16, crc);
+    }
+    img.put(volume_start * 512, volume);
+    img.bytes
+}
+
+/// Recomputes the checksums of both headers of the GPT disk `disk`, and of
+/// their partition entry arrays, after the arrays were changed.
+fn seal_gpt(disk: &mut [u8]) {
+    let read_u32 = |disk: &[u8], at: usize| u32::from_le_bytes([disk[at], disk[at + 1], disk[at + 2], disk[at + 3]]);
+    for &lba in &[1, disk.len() / 512 - 1] {
+        let header = lba * 512;
+        let mut entries_lba = [0u8; 8];
+        entries_lba.copy_from_slice(&disk[header + 72..header + 80]);
+        let entries = u64::from_le_bytes(entries_lba) as usize * 512;
+        let array_bytes = (read_u32(disk, header + 80) * read_u32(disk, header + 84)) as usize;
+        let entries_crc = gpt::crc32(&disk[entries..entries + array_bytes]);
+        disk[header + 88..header + 92].copy_from_slice(&entries_crc.to_le_bytes());
+
+        let header_size = read_u32(disk, header + 12) as usize;
+        disk[header + 16..header + 20].copy_from_slice(&[0; 4]);
+        let crc = gpt::crc32(&disk[header..header + header_size]);
+        disk[header + 16..header + 20].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+#[test]
+fn test_gpt_crc32_and_guid() {
+    assert_eq!(gpt::crc32(b"123456789"), 0xCBF43926);
+    assert_eq!(gpt::crc32(b""), 0);
+    assert_eq!(gpt::Guid::MICROSOFT_BASIC_DATA.to_string(), "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+    assert_eq!(LINUX_FILESYSTEM.to_string(), "0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+}
+
+#[test]
+fn test_gpt_partitions() {
+    let mut image = fat32_image(b"on a GPT disk");
+    let mut disk = gpt_image(&image[IMG_PARTITION_START * 512..]);
+    let table = gpt::GuidPartitionTable::from(Cursor::new(&mut disk[..])).unwrap();
+    assert!(!table.from_backup());
+    assert_eq!(table.disk_guid(), gpt::Guid([0x42; 16]));
+
+    let partitions = table.partitions();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].partition_type, LINUX_FILESYSTEM);
+    assert_eq!(partitions[0].name, "linux");
+    assert_eq!((partitions[0].starting_lba, partitions[0].num_sectors()), (34, 8));
+    assert_eq!(partitions[1].partition_type, gpt::Guid::MICROSOFT_BASIC_DATA);
+    assert_eq!(partitions[1].unique_guid, gpt::Guid([2; 16]));
+    assert_eq!(partitions[1].name, "Basic data partition");
+    assert_eq!((partitions[1].starting_lba, partitions[1].num_sectors()), (42, IMG_TOTAL_SECTORS as u64));
+
+    let vfat = vfat_from_image(&SharedImage::new(disk));
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"on a GPT disk");
+    vfat.create_file("/new.txt").unwrap().write_all(b"written").unwrap();
+    assert_eq!(read_to_vec(&vfat, "/new.txt"), b"written");
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    // An MBR disk without a protective partition isn't a GPT disk.
+    let err = gpt::GuidPartitionTable::from(Cursor::new(&mut image[..])).unwrap_err();
+    expect_variant!(err, gpt::Error::NotGpt);
+}
+
+#[test]
+fn test_gpt_backup_table() {
+    let image = fat32_image(b"backup");
+    let disk = gpt_image(&image[IMG_PARTITION_START * 512..]);
+
+    // A corrupt primary header, primary entry array or primary signature each
+    // make the backup table be used. A corrupt pointer to the backup header
+    // isn't followed.
+    for &at in &[512 + 24, 2 * 512 + 130, 512, 512 + 32] {
+        let mut corrupt = disk.clone();
+        corrupt[at] ^= 0xFF;
+        let table = gpt::GuidPartitionTable::from(Cursor::new(&mut corrupt[..])).unwrap();
+        assert!(table.from_backup());
+        assert_eq!(table.partitions().len(), 2);
+
+        let vfat = vfat_from_image(&SharedImage::new(corrupt));
+        assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"backup");
+    }
+
+    // A header extended by a later revision is checksummed in full. One
+    // larger than its sector is invalid.
+    let mut extended = disk.clone();
+    extended[512 + 12..512 + 16].copy_from_slice(&96u32.to_le_bytes());
+    extended[512 + 16..512 + 20].copy_from_slice(&[0; 4]);
+    extended[512 + 92..512 + 96].copy_from_slice(&[0xA5; 4]);
+    let crc = gpt::crc32(&extended[512..512 + 96]);
+    extended[512 + 16..512 + 20].copy_from_slice(&crc.to_le_bytes());
+    let table = gpt::GuidPartitionTable::from(Cursor::new(&mut extended[..])).unwrap();
+    assert!(!table.from_backup());
+    extended[512 + 12..512 + 16].copy_from_slice(&513u32.to_le_bytes());
+    let table = gpt::GuidPartitionTable::from(Cursor::new(&mut extended[..])).unwrap();
+    assert!(table.from_backup());
+
+    let last = disk.len() - 512;
+    let mut corrupt = disk.clone();
+    corrupt[512 + 24] ^= 0xFF;
+    corrupt[last + 24] ^= 0xFF;
+    let err = gpt::GuidPartitionTable::from(Cursor::new(&mut corrupt[..])).unwrap_err();
+    expect_variant!(err, gpt::Error::BadChecksum);
+    match VFat::<StdVFatHandle>::from(Cursor::new(corrupt)) {
+        Err(vfat::Error::Gpt(gpt::Error::BadChecksum)) => {}
+        other => panic!("expected a GPT checksum error, got {:?}", other.map(|_| ())),
+    }
+
+    let mut corrupt = disk;
+    corrupt[512] ^= 0xFF;
+    corrupt[last] ^= 0xFF;
+    let err = gpt::GuidPartitionTable::from(Cursor::new(&mut corrupt[..])).unwrap_err();
+    expect_variant!(err, gpt::Error::BadSignature);
+}
+
+/// Builds an MBR disk whose only primary partition besides a small Linux one
+/// is an extended partition. Its EBR chain holds another small Linux partition
+/// and then a FAT32 logical partition holding `volume`.
+fn extended_image(volume: &[u8]) -> Vec<u8> {
+    let volume_sectors = volume.len() / 512;
+    let extended_start = 9;
+    let second_ebr = extended_start + 9;
+    let total = second_ebr + 1 + volume_sectors;
+    let mut img = DiskImage::new(total);
+
+    img.partition(0, 0, 0x83, 1, 8);
+    img.partition(0, 1, 0x0F, extended_start, total - extended_start);
+    img.partition(extended_start, 0, 0x83, 1, 8);
+    img.partition(extended_start, 1, 0x05, second_ebr - extended_start, volume_sectors + 1);
+    img.partition(second_ebr, 0, 0x0C, 1, volume_sectors);
+    img.put((second_ebr + 1) * 512, volume);
+    img.bytes
+}
+
+#[test]
+fn test_logical_partitions() {
+    use crate::partition::{self, PartitionInfo, PartitionType};
+
+    let image = fat32_image(b"logical");
+    let mut disk = extended_image(&image[IMG_PARTITION_START * 512..]);
+    let partitions = partition::partitions(Cursor::new(&mut disk[..])).unwrap();
+    assert_eq!(
+        partitions,
+        vec![
+            PartitionInfo { partition_type: PartitionType::Mbr(0x83), start: 1, num_sectors: 8 },
+            PartitionInfo { partition_type: PartitionType::Mbr(0x83), start: 10, num_sectors: 8 },
+            PartitionInfo {
+                partition_type: PartitionType::Mbr(0x0C),
+                start: 19,
+                num_sectors: IMG_TOTAL_SECTORS as u64
+            },
+        ]
+    );
+
+    let vfat = vfat_from_image(&SharedImage::new(disk.clone()));
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"logical");
+
+    // An EBR chain that loops back on itself ends.
+    let mut looped = disk;
+    looped[(9 + 9) * 512 + 446 + 16 + 4] = 0x05;
+    looped[(9 + 9) * 512 + 446 + 16 + 8] = 9;
+    assert_eq!(partition::partitions(Cursor::new(&mut looped[..])).unwrap(), partitions);
+}
+
+#[test]
+fn test_superfloppy() {
+    use crate::partition::{self, PartitionInfo, PartitionType};
+
+    let mut volume = fat32_image(b"no partition table")[IMG_PARTITION_START * 512..].to_vec();
+    let partitions = partition::partitions(Cursor::new(&mut volume[..])).unwrap();
+    assert_eq!(
+        partitions,
+        vec![PartitionInfo {
+            partition_type: PartitionType::Superfloppy,
+            start: 0,
+            num_sectors: IMG_TOTAL_SECTORS as u64
+        }]
+    );
+
+    // A partition table wins over a boot sector in the same sector.
+    let mut both = volume.clone();
+    both[446 + 4] = 0x0C;
+    both[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+    both[446 + 12..446 + 16].copy_from_slice(&100u32.to_le_bytes());
+    assert_eq!(
+        partition::partitions(Cursor::new(&mut both[..])).unwrap(),
+        vec![PartitionInfo { partition_type: PartitionType::Mbr(0x0C), start: 2048, num_sectors: 100 }]
+    );
+
+    let image = SharedImage::new(volume);
+    let vfat = vfat_from_image(&image);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"no partition table");
+    vfat.create_file("/new.txt").unwrap().write_all(b"data").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    assert_eq!(read_to_vec(&vfat_from_image(&image), "/new.txt"), b"data");
+
+    let fat16 = fat16_image(0x06, 5000, b"fat16");
+    let vfat = vfat_from_image(&SharedImage::new(fat16[512..].to_vec()));
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"fat16");
+}
+
+#[test]
+fn test_mount_chosen_partition() {
+    use crate::partition::{self, PartitionType};
+
+    // A FAT16 boot partition, a FAT32 data partition and a Linux partition.
+    let mut boot = fat16_image(0x06, 5000, b"boot")[512..].to_vec();
+    boot[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    boot[43..54].copy_from_slice(b"BOOT       ");
+    let mut data = fat32_image(b"data")[IMG_PARTITION_START * 512..].to_vec();
+    data[67..71].copy_from_slice(&0xCAFE_F00Du32.to_le_bytes());
+    data[71..82].copy_from_slice(b"MY DATA    ");
+
+    let mut disk = vec![0u8; 512];
+    disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+    let mut start = 1;
+    for (i, &(partition_type, sectors)) in [(0x06, boot.len() / 512), (0x0C, data.len() / 512), (0x83, 8)].iter().enumerate() {
+        let entry = &mut disk[446 + i * 16..446 + (i + 1) * 16];
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+        start += sectors;
+    }
+    disk.extend_from_slice(&boot);
+    disk.extend_from_slice(&data);
+    disk.extend_from_slice(&[0u8; 8 * 512]);
+
+    let volumes = partition::probe(Cursor::new(&mut disk[..])).unwrap();
+    assert_eq!(volumes.len(), 3);
+    assert_eq!(volumes[0].partition_type, PartitionType::Mbr(0x06));
+    assert_eq!((volumes[0].start, volumes[0].num_sectors), (1, boot.len() as u64 / 512));
+    assert_eq!(volumes[0].volume_label.as_ref().map(|label| &label[..]), Some("BOOT"));
+    assert_eq!(volumes[0].volume_id, Some(0x1234_5678));
+    assert_eq!(volumes[1].partition_type, PartitionType::Mbr(0x0C));
+    assert_eq!(volumes[1].start, 1 + boot.len() as u64 / 512);
+    assert_eq!(volumes[1].volume_label.as_ref().map(|label| &label[..]), Some("MY DATA"));
+    assert_eq!(volumes[1].volume_id, Some(0xCAFE_F00D));
+    assert_eq!(volumes[2].partition_type, PartitionType::Mbr(0x83));
+    assert_eq!((volumes[2].volume_label.clone(), volumes[2].volume_id), (None, None));
+
+    let vfat = VFat::<StdVFatHandle>::from_partition(Cursor::new(disk.clone()), 1).unwrap();
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"data");
+    let vfat = VFat::<StdVFatHandle>::from_partition(Cursor::new(disk.clone()), 0).unwrap();
+    assert_eq!(vfat.lock(|vfat| vfat.fat_type()), vfat::FatType::Fat16);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"boot");
+
+    let err = VFat::<StdVFatHandle>::from_partition(Cursor::new(disk.clone()), 2).map(|_| ()).unwrap_err();
+    expect_variant!(err, vfat::Error::BadSignature);
+    let err = VFat::<StdVFatHandle>::from_partition(Cursor::new(disk), 3).map(|_| ()).unwrap_err();
+    expect_variant!(err, vfat::Error::NotFound);
+}
+
+#[test]
+fn test_large_directory_streaming() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+    vfat.create_dir("/logs").unwrap();
+    // Each name takes three slots, so some entries span two clusters.
+    for i in 0..400 {
+        vfat.create_file(format!("/logs/service log {:04}.txt", i)).unwrap();
+    }
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let vfat = vfat_from_image(&image);
+    let names = entry_names(&vfat, "/logs");
+    assert_eq!(names.len(), 402);
+    assert_eq!(names[2], "service log 0000.txt");
+    assert_eq!(names[401], "service log 0399.txt");
+    assert!(vfat.open_file("/logs/SERVICE LOG 0399.TXT").is_ok());
+
+    // Entries after a removed one that crossed a cluster boundary are found.
+    for i in (0..400).step_by(7) {
+        vfat.remove(format!("/logs/service log {:04}.txt", i)).unwrap();
+    }
+    for i in 0..400 {
+        let found = vfat.open_file(format!("/logs/service log {:04}.txt", i)).is_ok();
+        assert_eq!(found, i % 7 != 0, "service log {:04}.txt", i);
+    }
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    // A directory that can't be read to its end isn't taken to lack an entry.
+    let first = vfat.open_dir("/logs").unwrap().cluster;
+    vfat.lock(|vfat| vfat.set_fat_entry(first, 0x0FFFFF00)).unwrap();
+    let kind = vfat.open_file("/logs/service log 0000.txt").map(|_| ()).unwrap_err().kind();
+    assert_ne!(kind, io::ErrorKind::NotFound);
+    let err = vfat.create_file("/logs/service log 0000.txt").map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), kind);
+    let mut entries = vfat.open_dir("/logs").unwrap().entries().unwrap();
+    assert!(entries.by_ref().count() < 402);
+    assert_eq!(entries.take_error().unwrap().kind(), kind);
+}
+
+#[test]
+fn test_lookup_cache() {
+    let image = SharedImage::new(fat32_image(b"hello"));
+    let vfat = vfat_from_image(&image);
+    vfat.create_dir("/var").unwrap();
+    vfat.create_dir("/var/log").unwrap();
+    vfat.create_file("/var/log/Kernel.log").unwrap().write_all(b"boot").unwrap();
+
+    // Repeated lookups of a deep path don't touch the sector cache.
+    vfat.open_file("/var/log/kernel.log").unwrap();
+    let (sectors, lookups) = vfat.lock(|vfat| (vfat.cache_stats(), vfat.lookup_stats()));
+    let mut file = vfat.open_file("/VAR/LOG/KERNEL.LOG").unwrap();
+    assert_eq!(vfat.lock(|vfat| vfat.cache_stats()), sectors);
+    let stats = vfat.lock(|vfat| vfat.lookup_stats());
+    assert_eq!((stats.hits - lookups.hits, stats.misses), (3, lookups.misses));
+
+    // Cached entries follow writes, removals and renames.
+    file.write_all(b"boot done").unwrap();
+    file.flush().unwrap();
+    assert_eq!(vfat.open_file("/var/log/kernel.log").unwrap().size(), 9);
+    assert_eq!(read_to_vec(&vfat, "/var/log/kernel.log"), b"boot done");
+
+    vfat.rename("/var/log/kernel.log", "/var/log/old.log").unwrap();
+    expect_variant!(vfat.open("/var/log/kernel.log").map(|_| ()).unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(read_to_vec(&vfat, "/var/log/old.log"), b"boot done");
+
+    vfat.remove("/var/log/old.log").unwrap();
+    vfat.remove_dir("/var/log").unwrap();
+    expect_variant!(vfat.open("/var/log").map(|_| ()).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    // A new directory reusing the freed cluster doesn't inherit old entries.
+    vfat.create_dir("/var/new").unwrap();
+    expect_variant!(vfat.open("/var/new/old.log").map(|_| ()).unwrap_err().kind(), io::ErrorKind::NotFound);
+    vfat.create_file("/var/new/kernel.log").unwrap();
+    assert_eq!(vfat.open_file("/var/new/kernel.log").unwrap().size(), 0);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"hello");
+
+    // The cache is bounded. HELLO.TXT, looked up last, is the entry kept.
+    vfat.lock(|vfat| vfat.set_lookup_capacity(1));
+    let before = vfat.lock(|vfat| vfat.lookup_stats());
+    vfat.open_file("/HELLO.TXT").unwrap();
+    vfat.open_file("/HELLO.TXT").unwrap();
+    vfat.open_dir("/var").unwrap();
+    vfat.open_file("/HELLO.TXT").unwrap();
+    let after = vfat.lock(|vfat| vfat.lookup_stats());
+    assert_eq!((after.hits - before.hits, after.misses - before.misses), (2, 2));
+}
+
+#[test]
+fn test_invalid_lfn_runs() {
+    let image = SharedImage::new(fat32_image(b"hi"));
+    let vfat = vfat_from_image(&image);
+    for name in &["/A long file name.txt", "/Another long name.txt", "/Third long name.txt", "/Fourth long name.txt"] {
+        vfat.create_file(name).unwrap();
+    }
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    assert!(entry_names(&vfat, "/").contains(&"Fourth long name.txt".to_string()));
+
+    // Each name takes two long file name slots and a short one after the
+    // HELLO.TXT slot of the root directory.
+    let mut bytes = image.bytes();
+    let slot = |i: usize| (IMG_PARTITION_START + IMG_DATA_START) * 512 + i * 32;
+    // An orphaned run, whose short entry was deleted.
+    bytes[slot(3)] = 0xE5;
+    // A run out of order.
+    for i in 0..32 {
+        bytes.swap(slot(4) + i, slot(5) + i);
+    }
+    // A run with an out of range sequence number.
+    bytes[slot(7)] = 0x40 | 31;
+    // A run whose checksum doesn't match the short name.
+    bytes[slot(12)] = b'X';
+
+    let image = SharedImage::new(bytes);
+    let vfat = vfat_from_image(&image);
+    assert_eq!(entry_names(&vfat, "/"), vec!["ANOTHE~1.TXT", "HELLO.TXT", "THIRDL~1.TXT", "XOURTH~1.TXT"]);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"hi");
+
+    // A new short entry in the deleted slot doesn't take the orphaned name.
+    vfat.create_file("/NEW.TXT").unwrap();
+    assert_eq!(
+        entry_names(&vfat, "/"),
+        vec!["ANOTHE~1.TXT", "HELLO.TXT", "NEW.TXT", "THIRDL~1.TXT", "XOURTH~1.TXT"]
+    );
+    expect_variant!(vfat.open("/A long file name.txt").map(|_| ()).unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert!(vfat.open_file("/new.txt").is_ok());
+}
+
+#[test]
+fn test_code_pages_and_case_flags() {
+    use vfat::{CodePage, MountOptions};
+
+    let mut bytes = fat32_image(b"hello");
+    let root = (IMG_PARTITION_START + IMG_DATA_START) * 512;
+    // NT case flags: lower case base name, upper case extension.
+    bytes[root + 12] = 0x08;
+    // A second, empty file whose name has a byte above 0x7F.
+    bytes[root + 32..root + 43].copy_from_slice(b"BL\x9DB    TXT");
+    bytes[root + 32 + 11] = 0x20;
+    let image = SharedImage::new(bytes);
+
+    let vfat = vfat_from_image(&image);
+    assert_eq!(vfat.lock(|vfat| vfat.code_page()), CodePage::Cp437);
+    assert_eq!(entry_names(&vfat, "/"), vec!["BL¥B.TXT", "hello.TXT"]);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"hello");
+    assert!(vfat.open_file("/bl¥b.txt").is_ok());
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    let options = MountOptions { code_page: CodePage::Cp850, ..MountOptions::default() };
+    let vfat = VFat::<StdVFatHandle>::from_options(image.clone(), &options).unwrap();
+    assert_eq!(entry_names(&vfat, "/"), vec!["BLØB.TXT", "hello.TXT"]);
+
+    // Both flags, and a lower case extension alone.
+    image.0.lock().unwrap().get_mut()[root + 12] = 0x18;
+    image.0.lock().unwrap().get_mut()[root + 32 + 12] = 0x10;
+    let vfat = VFat::<StdVFatHandle>::from_options(image.clone(), &options).unwrap();
+    assert_eq!(entry_names(&vfat, "/"), vec!["BLØB.txt", "hello.txt"]);
+    let entry = vfat.open("/hello.txt").unwrap();
+    assert!(entry.metadata().lowercase_base() && entry.metadata().lowercase_extension());
+}
+
+#[test]
+fn test_timestamp_unix_conversions() {
+    use std::time::Duration;
+    use vfat::Timestamp as FatTimestamp;
+
+    assert_eq!(FatTimestamp::EPOCH.unix_time(), 315532800);
+    assert_eq!(FatTimestamp::from_unix_time(315532800), Some(FatTimestamp::EPOCH));
+    assert_eq!(FatTimestamp::from_unix_time(315532799), None);
+    assert_eq!(FatTimestamp::MAX.to_duration(), Duration::from_millis(4354819199990));
+    assert_eq!(FatTimestamp::from_duration(Duration::from_millis(4354819199990)), Some(FatTimestamp::MAX));
+    assert_eq!(FatTimestamp::from_unix_time(4354819200), None);
+
+    // 02/29/2024 12:34:57.25 keeps the odd second in the hundredths.
+    let ts = FatTimestamp::from_duration(Duration::from_millis(1709210097250)).unwrap();
+    let fields = (ts.year(), ts.month(), ts.day(), ts.hour(), ts.minute(), ts.second(), ts.hundredths);
+    assert_eq!(fields, (2024, 2, 29, 12, 34, 56, 125));
+    assert_eq!(ts.to_duration(), Duration::from_millis(1709210097250));
+    assert_eq!(ts.unix_time(), 1709210097);
+
+    // Corrupt on-disk dates don't panic.
+    assert_eq!(FatTimestamp::default().unix_time(), 315532800);
+}
+
+#[test]
+fn test_clock_and_access_times() {
+    use vfat::{AccessTimePolicy, FixedClock, Timestamp as FatTimestamp};
+
+    let day = |n: u64| FatTimestamp::from_unix_time(1700000000 + n * 86400 + 1).unwrap();
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+    vfat.lock(|vfat| vfat.set_clock(FixedClock(day(0))));
+    vfat.create_file("/log.txt").unwrap();
+    vfat.lock(|vfat| vfat.set_clock(FixedClock(day(2))));
+    vfat.open_file("/log.txt").unwrap().write_all(b"entry").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let entry = vfat_from_image(&image).open("/log.txt").unwrap();
+    assert_eq!(entry.metadata().created(), day(0));
+    assert_eq!(entry.metadata().created().hundredths, 100);
+    // Only creation times record the odd second.
+    assert_eq!(entry.metadata().modified(), FatTimestamp { hundredths: 0, ..day(2) });
+    assert_eq!(entry.metadata().accessed().date, day(2).date);
+
+    // Reads only update the access date as the policy says, and never by
+    // default.
+    let accessed = |vfat: &StdVFatHandle| vfat.open("/log.txt").unwrap().metadata().accessed().date;
+    let vfat = vfat_from_image(&image);
+    assert_eq!(vfat.lock(|vfat| vfat.access_time_policy()), AccessTimePolicy::Never);
+    vfat.lock(|vfat| vfat.set_clock(FixedClock(day(5))));
+    read_to_vec(&vfat, "/log.txt");
+    assert_eq!(accessed(&vfat), day(2).date);
+
+    vfat.lock(|vfat| vfat.set_access_time_policy(AccessTimePolicy::Relatime));
+    read_to_vec(&vfat, "/log.txt");
+    assert_eq!(accessed(&vfat), day(5).date);
+
+    // A clock set back doesn't move the date back under `Relatime`.
+    vfat.lock(|vfat| vfat.set_clock(FixedClock(day(3))));
+    read_to_vec(&vfat, "/log.txt");
+    assert_eq!(accessed(&vfat), day(5).date);
+    vfat.lock(|vfat| vfat.set_access_time_policy(AccessTimePolicy::Always));
+    read_to_vec(&vfat, "/log.txt");
+    assert_eq!(accessed(&vfat), day(3).date);
+}
+
+#[test]
+fn test_set_metadata() {
+    use vfat::{Attributes, Timestamp as FatTimestamp};
+
+    let image = SharedImage::new(fat32_image(b"boot"));
+    let vfat = vfat_from_image(&image);
+    vfat.create_dir("/boot").unwrap();
+    vfat.create_file("/boot/kernel8.img").unwrap().write_all(b"kernel").unwrap();
+
+    let stamp = FatTimestamp::from_unix_time(1600000000).unwrap();
+    let lock = MetadataChanges {
+        read_only: Some(true),
+        hidden: Some(true),
+        system: Some(true),
+        archive: Some(false),
+        created: Some(stamp),
+        accessed: Some(stamp),
+        modified: Some(stamp),
+    };
+    vfat.set_metadata("/boot/kernel8.img", &lock).unwrap();
+    vfat.set_metadata("/boot", &MetadataChanges { hidden: Some(true), ..MetadataChanges::default() }).unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+
+    let vfat = vfat_from_image(&image);
+    let entry = vfat.open("/boot/kernel8.img").unwrap();
+    let attributes = entry.metadata().attributes;
+    assert_eq!(attributes.bits(), Attributes::READ_ONLY.bits() | Attributes::HIDDEN.bits() | Attributes::SYSTEM.bits());
+    assert!(entry.metadata().read_only() && entry.metadata().hidden() && !attributes.archive());
+    assert_eq!(entry.metadata().created(), stamp);
+    assert_eq!(entry.metadata().modified(), stamp);
+    assert_eq!(entry.metadata().accessed().date, stamp.date);
+    let dir = vfat.open("/boot").unwrap();
+    assert!(dir.is_dir() && dir.metadata().hidden() && !dir.metadata().read_only());
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    // Read only files refuse writes until the flag is cleared.
+    let mut file = vfat.open_file("/boot/kernel8.img").unwrap();
+    expect_variant!(file.write(b"patched").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(read_to_vec(&vfat, "/boot/kernel8.img"), b"kernel");
+    vfat.set_metadata("/boot/kernel8.img", &MetadataChanges { read_only: Some(false), ..MetadataChanges::default() })
+        .unwrap();
+    vfat.open_file("/boot/kernel8.img").unwrap().write_all(b"patched").unwrap();
+    assert_eq!(read_to_vec(&vfat, "/boot/kernel8.img"), b"patched");
+    assert!(vfat.open("/boot/kernel8.img").unwrap().metadata().hidden());
+
+    let unchanged = MetadataChanges::default();
+    expect_variant!(vfat.set_metadata("/", &unchanged).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(vfat.set_metadata("/boot/missing", &unchanged).unwrap_err().kind(), io::ErrorKind::NotFound);
+}
+
+/// A `SharedImage` that counts the read calls made to it.
+#[derive(Clone)]
+struct CountingImage {
+    image: SharedImage,
+    reads: Arc<Mutex<usize>>,
+}
+
+impl CountingImage {
+    fn reads(&self) -> usize {
+        *self.reads.lock().unwrap()
+    }
+}
+
+impl BlockDevice for CountingImage {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        *self.reads.lock().unwrap() += 1;
+        self.image.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.image.write_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        *self.reads.lock().unwrap() += 1;
+        self.image.read_sectors(n, buf)
+    }
+}
+
+#[test]
+fn test_contiguous_reads() {
+    let contents: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+    let device = CountingImage { image: SharedImage::new(fat32_image(&contents)), reads: Arc::new(Mutex::new(0)) };
+    let vfat = VFat::<StdVFatHandle>::from(device.clone()).unwrap();
+
+    // The file's 79 clusters are contiguous: one device call reads all but
+    // the partial last sector, which is read through the cache, as is the FAT
+    // sector describing the chain.
+    let mut file = vfat.open_file("/HELLO.TXT").unwrap();
+    let before = device.reads();
+    let mut buf = vec![0u8; 64 * 1024];
+    assert_eq!(file.read(&mut buf).unwrap(), contents.len());
+    assert_eq!(&buf[..contents.len()], &contents[..]);
+    assert_eq!(device.reads() - before, 3);
+
+    // Unaligned reads that start and end inside sectors.
+    let mut file = vfat.open_file("/HELLO.TXT").unwrap();
+    file.seek(io::SeekFrom::Start(700)).unwrap();
+    let mut buf = vec![0u8; 30001];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &contents[700..30701]);
+
+    // Interleaved files are fragmented; neither they nor writes that are
+    // still only cached get lost.
+    let mut a = vfat.create_file("/a.bin").unwrap();
+    let mut b = vfat.create_file("/b.bin").unwrap();
+    for i in 0..20u8 {
+        a.write_all(&[i; 512]).unwrap();
+        b.write_all(&[100 + i; 512]).unwrap();
+    }
+    let expected_a: Vec<u8> = (0..20u8).flat_map(|i| vec![i; 512]).collect();
+    let expected_b: Vec<u8> = (0..20u8).flat_map(|i| vec![100 + i; 512]).collect();
+    assert_eq!(read_to_vec(&vfat, "/a.bin"), expected_a);
+    assert_eq!(read_to_vec(&vfat, "/b.bin"), expected_b);
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    let vfat = vfat_from_image(&device.image);
+    assert_eq!(read_to_vec(&vfat, "/a.bin"), expected_a);
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), contents);
+}
+
+#[test]
+fn test_block_device_adapters() {
+    use crate::blockdev::{ReadAhead, Slice, Translator};
+
+    let bytes: Vec<u8> = (0..16 * 512).map(|i| (i / 512) as u8).collect();
+    let mut sector = [0u8; 512];
+
+    // Sector 0 of the slice is sector 4 of the device; the slice ends at
+    // device sector 12.
+    let mut slice = Slice::new(Cursor::new(bytes.clone()), 4, 8);
+    slice.read_sector(2, &mut sector).unwrap();
+    assert_eq!(sector, [6u8; 512]);
+    slice.write_sector(7, &[0xEE; 512]).unwrap();
+    let mut two = [0u8; 1024];
+    slice.read_sectors(6, &mut two).unwrap();
+    assert_eq!(&two[..512], &[10u8; 512][..]);
+    assert_eq!(&two[512..], &[0xEE; 512][..]);
+    expect_variant!(slice.read_sector(8, &mut sector).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(slice.read_sectors(7, &mut two).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(slice.write_sector(u64::MAX, &sector).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(&slice.into_inner().into_inner()[11 * 512..12 * 512], &[0xEE; 512][..]);
+
+    // Logical sector 1 of 4 KiB covers device sectors 8 to 15.
+    let mut translator = Translator::new(Cursor::new(bytes.clone()), 4096);
+    assert_eq!(translator.sector_size(), 4096);
+    let mut logical = vec![0u8; 4096];
+    translator.read_sector(1, &mut logical).unwrap();
+    assert_eq!(&logical[..], &bytes[4096..]);
+    translator.write_sector(0, &[0xAB; 1024]).unwrap();
+    let bytes_after = translator.into_inner().into_inner();
+    assert_eq!(&bytes_after[..1024], &[0xAB; 1024][..]);
+    assert_eq!(&bytes_after[1024..], &bytes[1024..]);
+
+    // Reading in order takes one device call per window of 4 sectors; the
+    // last window runs past the end of the device and falls back to single
+    // sectors.
+    let device = CountingImage { image: SharedImage::new(bytes[..14 * 512].to_vec()), reads: Arc::new(Mutex::new(0)) };
+    let mut read_ahead = ReadAhead::new(device.clone(), 4);
+    for n in 0..8 {
+        read_ahead.read_sector(n, &mut sector).unwrap();
+        assert_eq!(sector, [n as u8; 512]);
+    }
+    assert_eq!(device.reads(), 2);
+    read_ahead.write_sector(5, &[0xCD; 512]).unwrap();
+    read_ahead.read_sector(5, &mut sector).unwrap();
+    assert_eq!(sector, [0xCD; 512]);
+    assert_eq!(device.reads(), 2);
+    read_ahead.read_sector(12, &mut sector).unwrap();
+    read_ahead.read_sector(13, &mut sector).unwrap();
+    assert_eq!(sector, [13u8; 512]);
+    expect_variant!(read_ahead.read_sector(14, &mut sector).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    // `CachedPartition` slices and translates on top of the read-ahead window.
+    let image = SharedImage::new(fat32_image(b"adapters"));
+    let vfat = VFat::<StdVFatHandle>::from(ReadAhead::new(image.clone(), 8)).unwrap();
+    assert_eq!(read_to_vec(&vfat, "/HELLO.TXT"), b"adapters");
+
+    // A volume whose sectors aren't made of whole device sectors isn't mounted.
+    let mut volume = fat32_image(b"")[IMG_PARTITION_START * 512..].to_vec();
+    volume[11..13].copy_from_slice(&2048u16.to_le_bytes());
+    match VFat::<StdVFatHandle>::from(Translator::new(Cursor::new(volume), 1536)) {
+        Err(vfat::Error::InvalidBpb) => {}
+        other => panic!("expected an invalid BPB error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_seek_with_extent_map() {
+    use io::SeekFrom;
+
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+    // Interleaving the writes fragments both files into 2 cluster extents.
+    let mut log = vfat.create_file("/service.log").unwrap();
+    let mut other = vfat.create_file("/other.log").unwrap();
+    for _ in 0..30 {
+        for _ in 0..1024 / 4 {
+            let at = log.size() as u32;
+            log.write_all(&at.to_le_bytes()).unwrap();
+        }
+        other.write_all(&[0xAA; 1024]).unwrap();
+    }
+
+    let read_u32_at = |file: &mut vfat::File<StdVFatHandle>, at: u64| {
+        assert_eq!(file.seek(SeekFrom::Start(at)).unwrap(), at);
+        let mut word = [0u8; 4];
+        file.read_exact(&mut word).unwrap();
+        u32::from_le_bytes(word)
+    };
+    let mut log = vfat.open_file("/service.log").unwrap();
+    assert_eq!(read_u32_at(&mut log, 30716), 30716);
+    // Once the map covers the chain, seeking doesn't read the FAT again.
+    let stats = vfat.lock(|vfat| vfat.cache_stats());
+    for &at in &[4, 30000, 1020, 20480, 1024, 0, 17404] {
+        log.seek(SeekFrom::Start(at)).unwrap();
+    }
+    assert_eq!(vfat.lock(|vfat| vfat.cache_stats()), stats);
+    for &at in &[4, 30000, 1020, 20480, 1024, 0, 17404] {
+        assert_eq!(read_u32_at(&mut log, at), at as u32);
+    }
+
+    assert_eq!(log.seek(SeekFrom::Current(-8)).unwrap(), 17400);
+    assert_eq!(read_u32_at(&mut log, 17400), 17400);
+    expect_variant!(log.seek(SeekFrom::Current(-20000)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    expect_variant!(log.seek(SeekFrom::End(1)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    // Seeking to the end is allowed, including right after a full cluster,
+    // and writes there append.
+    assert_eq!(log.seek(SeekFrom::End(0)).unwrap(), 30720);
+    let mut buf = [0u8; 16];
+    assert_eq!(log.read(&mut buf).unwrap(), 0);
+    log.write_all(b"tail").unwrap();
+    log.seek(SeekFrom::End(-2)).unwrap();
+    log.write_all(b"ed").unwrap();
+    assert_eq!(log.stream_position().unwrap(), 30724);
+    let data = read_to_vec(&vfat, "/service.log");
+    assert_eq!(&data[30716..], b"\xFC\x77\x00\x00taed");
+    assert_eq!(read_to_vec(&vfat, "/other.log"), vec![0xAA; 30 * 1024]);
+
+    let mut empty = vfat.create_file("/empty").unwrap();
+    assert_eq!(empty.seek(SeekFrom::End(0)).unwrap(), 0);
+    empty.write_all(b"x").unwrap();
+    assert_eq!(read_to_vec(&vfat, "/empty"), b"x");
+    assert!(check::check(&vfat).unwrap().is_clean());
+}
+
+#[test]
+fn test_set_len_and_preallocate() {
+    let image = SharedImage::new(fat32_image(b""));
+    let vfat = vfat_from_image(&image);
+    let free = || vfat.lock(|vfat| vfat.statfs()).unwrap().free_clusters;
+    let initial_free = free();
+    assert_eq!(vfat.lock(|vfat| vfat.bytes_per_cluster()), 512);
+
+    // Shrinking frees the trailing clusters; growing again zeroes the stale
+    // bytes in the kept cluster as well as the new ones.
+    let mut file = vfat.create_file("/data.bin").unwrap();
+    file.write_all(&[7u8; 3000]).unwrap();
+    assert_eq!(free(), initial_free - 6);
+    file.set_len(1000).unwrap();
+    assert_eq!(free(), initial_free - 2);
+    assert_eq!(file.stream_position().unwrap(), 1000);
+    file.set_len(2500).unwrap();
+    assert_eq!(free(), initial_free - 5);
+    let mut expected = vec![7u8; 1000];
+    expected.resize(2500, 0);
+    assert_eq!(read_to_vec(&vfat, "/data.bin"), expected);
+    file.set_len(0).unwrap();
+    assert_eq!(free(), initial_free);
+    assert_eq!(vfat.open_file("/data.bin").unwrap().start_cluster.cluster_number(), 0);
+    file.write_all(b"again").unwrap();
+    assert_eq!(read_to_vec(&vfat, "/data.bin"), b"again");
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    // The cluster after the log's first one is taken, so the reserved run
+    // starts elsewhere but is contiguous, and filling it allocates nothing.
+    let mut log = vfat.create_file("/log.bin").unwrap();
+    log.write_all(&[1u8; 512]).unwrap();
+    vfat.create_file("/blocker").unwrap().write_all(b"b").unwrap();
+    let before = free();
+    log.preallocate(8 * 512).unwrap();
+    assert_eq!(log.size(), 512);
+    assert_eq!(free(), before - 7);
+    let chain = vfat.lock(|vfat| vfat.cluster_chain(log.start_cluster)).unwrap();
+    let numbers: Vec<u32> = chain.iter().map(|cluster| cluster.cluster_number()).collect();
+    assert_eq!(numbers.len(), 8);
+    assert!(numbers[1..].windows(2).all(|pair| pair[1] == pair[0] + 1));
+    let options = check::CheckOptions { preallocated: true };
+    let report = check::check_with(&vfat, &options).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(report.reserved_clusters, 7);
+    assert!(check::repair_with(&vfat, check::LostChains::Reclaim, &options).unwrap().is_clean());
+    assert_eq!(free(), before - 7);
+    assert_eq!(check::check(&vfat).unwrap().problems, vec![
+        check::Problem::SizeMismatch { path: "/log.bin".into(), size: 512, clusters: 8 },
+    ]);
+    log.preallocate(4 * 512).unwrap();
+    assert_eq!(free(), before - 7);
+    log.write_all(&[2u8; 7 * 512]).unwrap();
+    assert_eq!(free(), before - 7);
+    let mut expected = vec![1u8; 512];
+    expected.resize(8 * 512, 2);
+    assert_eq!(read_to_vec(&vfat, "/log.bin"), expected);
+    assert!(check::check(&vfat).unwrap().is_clean());
+
+    let mut empty = vfat.create_file("/empty.bin").unwrap();
+    empty.preallocate(1024).unwrap();
+    assert_eq!(empty.size(), 0);
+    empty.write_all(b"xy").unwrap();
+    assert_eq!(free(), before - 9);
+    expect_variant!(empty.preallocate(u64::from(free() + 8) * 512).unwrap_err().kind(), io::ErrorKind::Other);
+    expect_variant!(empty.set_len(1 << 32).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    // Growing into reserved clusters zeroes what they held before.
+    let mut stale = vfat.create_file("/stale.bin").unwrap();
+    stale.write_all(&[9u8; 1024]).unwrap();
+    stale.set_len(0).unwrap();
+    stale.preallocate(1024).unwrap();
+    stale.set_len(1000).unwrap();
+    assert_eq!(read_to_vec(&vfat, "/stale.bin"), vec![0u8; 1000]);
+    assert!(check::check_with(&vfat, &options).unwrap().is_clean());
+
+    let changes = MetadataChanges { read_only: Some(true), ..Default::default() };
+    vfat.set_metadata("/empty.bin", &changes).unwrap();
+    let mut empty = vfat.open_file("/empty.bin").unwrap();
+    expect_variant!(empty.set_len(0).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    expect_variant!(empty.preallocate(4096).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+}
+
+/// Lists every directory and reads the start of every file reachable from
+/// `dir`, ignoring errors. Corrupt volumes can have directory loops and
+/// endless directories, so the walk is bounded in depth and breadth.
+fn walk_ignoring_errors(dir: vfat::Dir<StdVFatHandle>, depth: usize) {
+    let entries = match dir.entries() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.take(512) {
+        let _ = hash_entry(&mut String::new(), &entry);
+        if entry.name() == "." || entry.name() == ".." {
+            continue;
+        }
+        if entry.is_dir() {
+            if depth > 0 {
+                walk_ignoring_errors(entry.into_dir().unwrap(), depth - 1);
+            }
+        } else if let Some(mut file) = entry.into_file() {
+            let mut buf = [0u8; 4096];
+            for _ in 0..16 {
+                match file.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Overwrites random bytes of `image` within `regions`, given as `(start,
+/// end)` byte offsets, `rounds` times, mounting and walking the mutated
+/// volume each time. The image is restored after every round. Mounting and
+/// walking may fail but must never panic.
+fn fuzz_image(image: &SharedImage, regions: &[(usize, usize)], seed: u32, rounds: usize) {
+    use crate::partition;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([seed, 0x9E37_79B9, 0x85EB_CA6B, 0xC2B2_AE35]);
+    for round in 0..rounds {
+        let mut saved = Vec::new();
+        {
+            let mut cursor = image.0.lock().unwrap();
+            let bytes = cursor.get_mut();
+            for _ in 0..rng.gen_range(1, 9) {
+                let region = &regions[rng.gen_range(0, regions.len())];
+                let at = rng.gen_range(region.0, region.1.min(bytes.len()));
+                saved.push((at, bytes[at]));
+                bytes[at] = match rng.gen_range(0, 3) {
+                    0 => bytes[at] ^ (1 << rng.gen_range(0, 8)),
+                    1 => [0x00, 0xFF, 0xE5, 0x0F][rng.gen_range(0, 4)],
+                    _ => rng.gen(),
+                };
+            }
+        }
+
+        let _ = partition::probe(image.clone());
+        if let Ok(vfat) = VFat::<StdVFatHandle>::from(image.clone()) {
+            if let Ok(root) = vfat.open_dir("/") {
+                walk_ignoring_errors(root, 8);
+            }
+            if round % 8 == 0 {
+                let _ = check::check(&vfat);
+            }
+        }
+
+        let mut cursor = image.0.lock().unwrap();
+        for (at, byte) in saved.into_iter().rev() {
+            cursor.get_mut()[at] = byte;
+        }
+    }
+}
+
+#[test]
+fn test_malformed_volumes_never_panic() {
+    // A FAT32 volume with nested directories, long names and multi-cluster
+    // files. Mutations hit the MBR, the boot sector, FSInfo, the head of the
+    // first FAT and the first data clusters.
+    let image = SharedImage::new(fat32_image(&[7u8; 1500]));
+    let vfat = vfat_from_image(&image);
+    vfat.create_dir("/A directory").unwrap();
+    vfat.create_dir("/A directory/nested").unwrap();
+    vfat.create_file("/A directory/nested/a long file name.bin").unwrap().write_all(&[1u8; 2000]).unwrap();
+    vfat.create_file("/A directory/short.txt").unwrap().write_all(b"short").unwrap();
+    vfat.lock(|vfat| vfat.sync()).unwrap();
+    let fat = (IMG_PARTITION_START + IMG_RESERVED_SECTORS) * 512;
+    let data = (IMG_PARTITION_START + IMG_DATA_START) * 512;
+    fuzz_image(&image, &[(0, 3 * 512), (fat, fat + 512), (data, data + 16 * 512)], 1, 300);
+
+    // FAT16 and FAT12 volumes, with fixed root directories.
+    for &(partition_type, clusters) in &[(0x06, 5000), (0x01, 1000)] {
+        let image = SharedImage::new(fat16_image(partition_type, clusters, &[9u8; 1200]));
+        let vfat = vfat_from_image(&image);
+        vfat.create_dir("/dir").unwrap();
+        vfat.create_file("/dir/Some file.txt").unwrap().write_all(&[2u8; 700]).unwrap();
+        vfat.lock(|vfat| vfat.sync()).unwrap();
+        let len = image.bytes().len();
+        fuzz_image(&image, &[(0, len.min(64 * 512))], clusters as u32, 300);
+    }
+
+    // GPT partitions that end before they start, or reach into the backup
+    // entry array, behind valid checksums.
+    let image = fat32_image(b"gpt");
+    let disk = gpt_image(&image[IMG_PARTITION_START * 512..]);
+    let backup_entries = disk.len() / 512 - 33;
+    for &(first, last) in &[(42, 41), (42, backup_entries as u64)] {
+        let mut bad = disk.clone();
+        for &entries in &[2, backup_entries] {
+            let entry = entries * 512 + 128;
+            bad[entry + 32..entry + 40].copy_from_slice(&u64::to_le_bytes(first));
+            bad[entry + 40..entry + 48].copy_from_slice(&u64::to_le_bytes(last));
+        }
+        seal_gpt(&mut bad);
+        let table = gpt::GuidPartitionTable::from(Cursor::new(&mut bad[..])).unwrap();
+        assert!(!table.from_backup());
+        assert_eq!(table.partitions().len(), 1);
+        let partitions = crate::partition::partitions(Cursor::new(&mut bad[..])).unwrap();
+        assert!(partitions.iter().all(|partition| !partition.partition_type.is_fat()));
+        assert!(VFat::<StdVFatHandle>::from(SharedImage::new(bad)).is_err());
+    }
+
+    // Whatever images are in `ext/fat32-imgs`.
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../../ext/fat32-imgs");
+    for path in std::fs::read_dir(dir).into_iter().flatten().flatten().map(|entry| entry.path()) {
+        if path.extension().is_some_and(|extension| extension == "img") {
+            let image = SharedImage::new(std::fs::read(&path).unwrap());
+            let len = image.bytes().len();
+            fuzz_image(&image, &[(0, len.min(1024 * 1024))], len as u32, 100);
+        }
+    }
+}
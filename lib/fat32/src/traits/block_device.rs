@@ -56,9 +56,53 @@ pub trait BlockDevice: Send {
     /// error of `UnexpectedEof` if the length of `buf` is less than
     /// `self.sector_size()`.
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize>;
+
+    /// Reads the contiguous sectors starting at sector `n` into `buf`.
+    ///
+    /// `buf.len()` bytes are read; if that isn't a multiple of
+    /// `self.sector_size()`, only the start of the last sector is read. The
+    /// number of bytes read is returned. The default implementation calls
+    /// `read_sector()` once per sector; devices that can transfer many sectors
+    /// at once should override it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or reading from `self` fails.
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+        let mut read = 0;
+        for (i, chunk) in buf.chunks_mut(sector_size).enumerate() {
+            read += self.read_sector(n + i as u64, chunk)?;
+        }
+        Ok(read)
+    }
+
+    /// Overwrites the contiguous sectors starting at sector `n` with the
+    /// contents of `buf`.
+    ///
+    /// `buf.len()` bytes are written; if that isn't a multiple of
+    /// `self.sector_size()`, the last sector is written like `write_sector()`
+    /// writes a short buffer. The number of bytes written is returned. The
+    /// default implementation calls `write_sector()` once per sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or writing to `self` fails.
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+        let mut written = 0;
+        for (i, chunk) in buf.chunks(sector_size).enumerate() {
+            written += self.write_sector(n + i as u64, chunk)?;
+        }
+        Ok(written)
+    }
 }
 
 impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
+    fn sector_size(&self) -> u64 {
+        (**self).sector_size()
+    }
+
     fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
         (*self).read_sector(n, buf)
     }
@@ -66,6 +110,14 @@ impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
         (*self).write_sector(n, buf)
     }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (*self).read_sectors(n, buf)
+    }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        (*self).write_sectors(n, buf)
+    }
 }
 
 macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
@@ -87,11 +139,23 @@ macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
             self.write_all(&buf[..to_write])?;
             Ok(to_write)
         }
+
+        fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+            self.seek(io::SeekFrom::Start(n * self.sector_size()))?;
+            self.read_exact(buf)?;
+            Ok(buf.len())
+        }
+
+        fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+            self.seek(io::SeekFrom::Start(n * self.sector_size()))?;
+            self.write_all(buf)?;
+            Ok(buf.len())
+        }
     }
 }
 
 impl_for_read_write_seek!(<'a> shim::io::Cursor<&'a mut [u8]>);
 impl_for_read_write_seek!(shim::io::Cursor<Vec<u8>>);
 impl_for_read_write_seek!(shim::io::Cursor<Box<[u8]>>);
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 impl_for_read_write_seek!(::std::fs::File);
@@ -1,6 +1,6 @@
 use shim::{io, path::Path};
 
-use crate::traits::Metadata;
+use crate::traits::{Metadata, MetadataChanges};
 
 /// Trait implemented by files in the file system.
 pub trait File: io::Read + io::Write + io::Seek + Sized {
@@ -113,4 +113,71 @@ pub trait FileSystem: Sized {
             .into_dir()
             .ok_or(io::Error::new(io::ErrorKind::Other, "not a directory"))
     }
+
+    /// Creates an empty regular file at `path` and returns it. `path` must be
+    /// absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute or has no file name, an error kind of
+    /// `InvalidInput` is returned.
+    ///
+    /// If the parent of `path` does not exist or is not a directory, the error
+    /// conditions of `open_dir()` apply.
+    ///
+    /// If an entry already exists at `path`, an error kind of `AlreadyExists`
+    /// is returned.
+    ///
+    /// All other error values are implementation defined.
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File>;
+
+    /// Creates an empty directory at `path` and returns it. `path` must be
+    /// absolute.
+    ///
+    /// # Errors
+    ///
+    /// The error conditions are the same as for `create_file()`.
+    fn create_dir<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Dir>;
+
+    /// Removes the regular file at `path`. `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()`, this method returns an
+    /// error kind of `Other` if the entry at `path` is a directory.
+    fn remove<P: AsRef<Path>>(self, path: P) -> io::Result<()>;
+
+    /// Removes the empty directory at `path`. `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()`, this method returns an
+    /// error kind of `Other` if the entry at `path` is not a directory or if
+    /// the directory is not empty. The root directory cannot be removed.
+    fn remove_dir<P: AsRef<Path>>(self, path: P) -> io::Result<()>;
+
+    /// Renames the entry at `from` to `to`, moving it to another directory if
+    /// the parents of the paths differ. Both paths must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// The error conditions for `open()` apply to `from` and those for
+    /// `create_file()` apply to `to`. An existing entry at `to` is never
+    /// replaced. Moving a directory into itself results in an error kind of
+    /// `InvalidInput`.
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()>;
+
+    /// Changes the attributes and timestamps of the entry at `path` as
+    /// described by `changes`. `path` must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()`, this method returns an
+    /// error kind of `InvalidInput` if `path` is the root directory, which has
+    /// no metadata of its own.
+    fn set_metadata<P: AsRef<Path>>(
+        self,
+        path: P,
+        changes: &MetadataChanges<<<Self::Entry as Entry>::Metadata as Metadata>::Timestamp>,
+    ) -> io::Result<()>;
 }
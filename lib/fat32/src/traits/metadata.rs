@@ -43,3 +43,23 @@ pub trait Metadata: Sized {
     /// The timestamp for the entry's last modification.
     fn modified(&self) -> Self::Timestamp;
 }
+
+/// Changes to the attributes and timestamps of an entry, made with
+/// `FileSystem::set_metadata()`. Fields that are `None` are left unchanged.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MetadataChanges<T> {
+    /// Whether the entry is read only.
+    pub read_only: Option<bool>,
+    /// Whether the entry is hidden from directory traversals.
+    pub hidden: Option<bool>,
+    /// Whether the entry belongs to the operating system.
+    pub system: Option<bool>,
+    /// Whether the entry changed since it was last backed up.
+    pub archive: Option<bool>,
+    /// The timestamp when the entry was created.
+    pub created: Option<T>,
+    /// The timestamp for the entry's last access.
+    pub accessed: Option<T>,
+    /// The timestamp for the entry's last modification.
+    pub modified: Option<T>,
+}
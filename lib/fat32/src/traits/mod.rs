@@ -6,4 +6,4 @@ mod metadata;
 pub use self::block_device::BlockDevice;
 pub use self::dummy::Dummy;
 pub use self::fs::{Dir, Entry, File, FileSystem};
-pub use self::metadata::{Metadata, Timestamp};
+pub use self::metadata::{Metadata, MetadataChanges, Timestamp};
@@ -1,15 +1,34 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::fmt;
 use hashbrown::HashMap;
 use shim::io;
 
+use crate::blockdev::{Slice, Translator};
 use crate::traits::BlockDevice;
 
+/// The number of logical sectors a `CachedPartition` keeps in memory unless
+/// configured otherwise.
+pub const DEFAULT_CACHE_CAPACITY: usize = 512;
+
 #[derive(Debug)]
 struct CacheEntry {
     data: Vec<u8>,
     dirty: bool,
+    /// Value of the cache's access clock when the sector was last used.
+    last_used: u64,
+}
+
+/// Counters describing how well a `CachedPartition` is doing.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Sector accesses served from memory.
+    pub hits: u64,
+    /// Sector accesses that had to read the sector from the disk.
+    pub misses: u64,
+    /// Dirty sectors written back to the disk, by eviction or flushing.
+    pub writebacks: u64,
 }
 
 pub struct Partition {
@@ -22,9 +41,16 @@ pub struct Partition {
 }
 
 pub struct CachedPartition {
+    /// The partition, addressed in logical sectors.
     device: Box<dyn BlockDevice>,
     cache: HashMap<u64, CacheEntry>,
+    /// The cached sectors keyed by their `last_used` value, least recently
+    /// used first.
+    order: BTreeMap<u64, u64>,
     partition: Partition,
+    capacity: usize,
+    clock: u64,
+    stats: CacheStats,
 }
 
 impl CachedPartition {
@@ -41,56 +67,111 @@ impl CachedPartition {
     /// `partition.sector_size` must be an integer multiple of
     /// `device.sector_size()`.
     ///
+    /// At most `DEFAULT_CACHE_CAPACITY` sectors are kept in memory.
+    ///
     /// # Panics
     ///
     /// Panics if the partition's sector size is < the device's sector size.
     pub fn new<T>(device: T, partition: Partition) -> CachedPartition
+    where
+        T: BlockDevice + 'static,
+    {
+        CachedPartition::with_capacity(device, partition, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Like `new()`, but keeps at most `capacity` sectors in memory. Once the
+    /// cache is full, the least recently used sector is evicted, and written
+    /// back to the disk first if it is dirty. At least one sector is always
+    /// cached.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the device's sector size.
+    pub fn with_capacity<T>(device: T, partition: Partition, capacity: usize) -> CachedPartition
     where
         T: BlockDevice + 'static,
     {
         assert!(partition.sector_size >= device.sector_size());
 
+        let factor = partition.sector_size / device.sector_size();
+        let slice = Slice::new(device, partition.start, partition.num_sectors * factor);
         CachedPartition {
-            device: Box::new(device),
+            device: Box::new(Translator::new(slice, partition.sector_size)),
             cache: HashMap::new(),
+            order: BTreeMap::new(),
             partition: partition,
+            capacity: ::core::cmp::max(capacity, 1),
+            clock: 0,
+            stats: CacheStats::default(),
         }
     }
 
-    /// Returns the number of physical sectors that corresponds to
-    /// one logical sector.
-    fn factor(&self) -> u64 {
-        self.partition.sector_size / self.device.sector_size()
+    /// Changes the maximum number of sectors kept in memory to `capacity`,
+    /// evicting sectors if there are more cached than that.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing an evicted dirty sector back fails.
+    pub fn set_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        self.capacity = ::core::cmp::max(capacity, 1);
+        while self.cache.len() > self.capacity {
+            self.evict()?;
+        }
+        Ok(())
     }
 
-    /// Maps a user's request for a sector `virt` to the physical sector.
-    /// Returns `None` if the virtual sector number is out of range.
-    fn virtual_to_physical(&self, virt: u64) -> Option<u64> {
-        if virt >= self.partition.num_sectors {
-            return None;
-        }
+    /// Returns the hit, miss and write-back counters of the cache.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
 
-        let physical_offset = virt * self.factor();
-        let physical_sector = self.partition.start + physical_offset;
+    /// Removes the least recently used sector from the cache, writing it back
+    /// first if it is dirty.
+    fn evict(&mut self) -> io::Result<()> {
+        let victim = self.order.iter().next().map(|(&last_used, &sector)| (last_used, sector));
 
-        Some(physical_sector)
+        if let Some((last_used, sector)) = victim {
+            if self.cache[&sector].dirty {
+                self.device.write_sector(sector, &self.cache[&sector].data)?;
+                self.stats.writebacks += 1;
+            }
+            self.cache.remove(&sector);
+            self.order.remove(&last_used);
+        }
+        Ok(())
     }
 
-    fn load_cache(&mut self, sector: u64) {
-        if !self.cache.contains_key(&sector) {
-            let physical_sector = self.virtual_to_physical(sector).unwrap(); // break point
-            let physical_sector_size = self.device.sector_size();
-            let logical_sector_size = (physical_sector_size * self.partition.num_sectors) as usize;
-            let mut data = Vec::new();
-
-            // println!("{}", physical_sector);
-            // println!("{}", self.partition.num_sectors);
-            for i in 0..self.partition.sector_size/self.device.sector_size() {
-                self.device.read_all_sector(physical_sector+i, &mut data).unwrap();
-                // self.device.read_sector(physical_sector+i, &mut data[(i*physical_sector_size) as usize ..]).unwrap();
+    /// Makes sure that `sector` is cached and marks it as the most recently
+    /// used one. If `load` is false, a newly cached sector is zero-filled
+    /// instead of read from the disk.
+    fn load_cache(&mut self, sector: u64, load: bool) -> io::Result<&mut CacheEntry> {
+        self.clock += 1;
+        if let Some(entry) = self.cache.get(&sector) {
+            self.stats.hits += 1;
+            self.order.remove(&entry.last_used);
+        } else {
+            if sector >= self.partition.num_sectors {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "sector out of partition range"));
             }
-            self.cache.insert(sector, CacheEntry{data, dirty: false});
+
+            let mut data = Vec::with_capacity(self.partition.sector_size as usize);
+            if load {
+                self.stats.misses += 1;
+                self.device.read_all_sector(sector, &mut data)?;
+            } else {
+                data.resize(self.partition.sector_size as usize, 0);
+            }
+
+            while self.cache.len() >= self.capacity {
+                self.evict()?;
+            }
+            self.cache.insert(sector, CacheEntry { data, dirty: false, last_used: 0 });
         }
+
+        self.order.insert(self.clock, sector);
+        let entry = self.cache.get_mut(&sector).unwrap();
+        entry.last_used = self.clock;
+        Ok(entry)
     }
 
     /// Returns a mutable reference to the cached sector `sector`. If the sector
@@ -104,8 +185,7 @@ impl CachedPartition {
     ///
     /// Returns an error if there is an error reading the sector from the disk.
     pub fn get_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
-        self.load_cache(sector);
-        let entry = self.cache.get_mut(&sector).unwrap();
+        let entry = self.load_cache(sector, true)?;
         entry.dirty = true;
         Ok(entry.data.as_mut_slice())
     }
@@ -117,32 +197,84 @@ impl CachedPartition {
     ///
     /// Returns an error if there is an error reading the sector from the disk.
     pub fn get(&mut self, sector: u64) -> io::Result<&[u8]> {
-        self.load_cache(sector);
-        let entry = self.cache.get_mut(&sector).unwrap();
+        let entry = self.load_cache(sector, true)?;
         Ok(entry.data.as_slice())
     }
+
+    /// Writes every dirty cached sector back to the underlying device and
+    /// marks it clean.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing a sector to the disk fails. Sectors that
+    /// were not written back yet stay dirty.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for (&sector, entry) in self.cache.iter_mut() {
+            if !entry.dirty {
+                continue;
+            }
+
+            self.device.write_sector(sector, &entry.data)?;
+            entry.dirty = false;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
 }
 
-// FIXME: Implement `BlockDevice` for `CacheDevice`. The `read_sector` and
-// `write_sector` methods should only read/write from/to cached sectors.
 impl BlockDevice for CachedPartition {
     fn sector_size(&self) -> u64 {
         self.partition.sector_size
     }
 
     fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<usize> {
-        use shim::io::Read;
-        if self.cache.contains_key(&sector) {
-            let cached_entry = &self.cache[&sector];
-            io::Cursor::new(&cached_entry.data).read(buf)
+        let data = self.get(sector)?;
+        let len = ::core::cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    /// Writes `buf` into the cached copy of `sector`. The sector only reaches
+    /// the disk once it is evicted or the cache is flushed.
+    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.partition.sector_size as usize;
+        let len = ::core::cmp::min(sector_size, buf.len());
+
+        // A sector that is overwritten completely needn't be read first.
+        let entry = self.load_cache(sector, len < sector_size)?;
+        entry.data[..len].copy_from_slice(&buf[..len]);
+        entry.dirty = true;
+        Ok(len)
+    }
+
+    /// Reads the sectors straight from the disk in one call, bypassing the
+    /// cache and leaving it untouched. Sectors with changes that weren't
+    /// written back yet are copied from the cache instead.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.partition.sector_size as usize;
+        let count = (buf.len() / sector_size) as u64;
+        let whole = count as usize * sector_size;
+        if count > 0 {
+            self.device.read_sectors(sector, &mut buf[..whole])?;
+            for (&cached, entry) in self.cache.iter() {
+                if entry.dirty && cached >= sector && cached < sector + count {
+                    let at = (cached - sector) as usize * sector_size;
+                    buf[at..at + sector_size].copy_from_slice(&entry.data);
+                }
+            }
         }
-        else {
-            Err(io::Error::new(io::ErrorKind::Interrupted, "data not cached yet"))
+        if whole < buf.len() {
+            self.read_sector(sector + count, &mut buf[whole..])?;
         }
+        Ok(buf.len())
     }
+}
 
-    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<usize> {
-        unimplemented!();
+impl Drop for CachedPartition {
+    /// Dirty sectors are written back when the cache goes away. Errors can't
+    /// be reported here; call `flush()` beforehand to observe them.
+    fn drop(&mut self) {
+        let _ = self.flush();
     }
 }
 
@@ -150,7 +282,9 @@ impl fmt::Debug for CachedPartition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("CachedPartition")
             .field("device", &"<block device>")
-            .field("cache", &self.cache)
+            .field("capacity", &self.capacity)
+            .field("cached", &self.cache.len())
+            .field("stats", &self.stats)
             .finish()
     }
 }
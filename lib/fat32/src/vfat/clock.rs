@@ -0,0 +1,54 @@
+use core::fmt::Debug;
+
+use crate::traits::Metadata as _;
+use crate::vfat::{Metadata, Time, Timestamp};
+
+/// A source of the current time, which a `VFat` stamps on the entries it
+/// creates and writes to.
+pub trait Clock: Debug + Send {
+    /// The current local time.
+    fn now(&self) -> Timestamp;
+}
+
+/// A clock that always reads the same time. A `VFat` uses a `FixedClock`
+/// reading `Timestamp::EPOCH` until it is given another clock.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FixedClock(pub Timestamp);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Timestamp {
+        self.0
+    }
+}
+
+/// When reading a file updates its last access date.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum AccessTimePolicy {
+    /// Every read that falls on a new day updates it.
+    Always,
+    /// A read updates it if it is before the last modification date or at
+    /// least a day old, like Linux's `relatime`.
+    Relatime,
+    /// Reads never update it, so reading never writes to the volume.
+    #[default]
+    Never,
+}
+
+impl AccessTimePolicy {
+    /// Whether reading the entry with `metadata` at `now` should update its
+    /// last access date.
+    pub(crate) fn should_update(&self, metadata: &Metadata, now: Timestamp) -> bool {
+        let accessed = metadata.accessed();
+        if accessed.date == now.date {
+            return false;
+        }
+        match *self {
+            AccessTimePolicy::Always => true,
+            AccessTimePolicy::Relatime => {
+                let modified = Timestamp { time: Time::default(), ..metadata.modified() };
+                accessed.unix_time() < modified.unix_time() || accessed.unix_time() + 24 * 60 * 60 <= now.unix_time()
+            }
+            AccessTimePolicy::Never => false,
+        }
+    }
+}
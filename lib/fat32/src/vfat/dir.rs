@@ -1,15 +1,15 @@
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::mem::size_of;
 
 use shim::const_assert_size;
 use shim::ffi::OsStr;
 use shim::io;
-use shim::newioerr;
 
-use crate::traits;
-use crate::util::VecExt;
-use crate::vfat::{Attributes, Date, Metadata, Time, Timestamp};
-use crate::vfat::{Cluster, Entry, File, VFatHandle};
+use crate::traits::{self, MetadataChanges};
+use crate::util::{SliceExt, VecExt};
+use crate::vfat::{Attributes, Metadata, Timestamp};
+use crate::vfat::{Cluster, CodePage, Entry, File, VFat, VFatHandle};
 
 #[derive(Debug)]
 pub struct Dir<HANDLE: VFatHandle> {
@@ -33,6 +33,163 @@ pub struct VFatRegularDirEntry {
 
 const_assert_size!(VFatRegularDirEntry, 32);
 
+impl VFatRegularDirEntry {
+    pub(crate) fn new(short_name: [u8; 11], metadata: Metadata) -> VFatRegularDirEntry {
+        let mut file_name = [0u8; 8];
+        let mut file_extension = [0u8; 3];
+        file_name.copy_from_slice(&short_name[..8]);
+        file_extension.copy_from_slice(&short_name[8..]);
+        VFatRegularDirEntry {
+            file_name,
+            file_extension,
+            metadata,
+            size: 0,
+        }
+    }
+
+    /// The raw 11 byte short name: 8 name bytes followed by 3 extension bytes.
+    pub(crate) fn short_name(&self) -> [u8; 11] {
+        let mut short_name = [0u8; 11];
+        short_name[..8].copy_from_slice(&self.file_name);
+        short_name[8..].copy_from_slice(&self.file_extension);
+        short_name
+    }
+
+    /// The size of the file in bytes.
+    pub(crate) fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        self.size = size;
+    }
+}
+
+/// A run of long file name entries, read in on-disk order: from the entry
+/// with the highest sequence number, flagged as the last, down to 1.
+pub(crate) struct LfnRun {
+    checksum: u8,
+    /// The sequence number the next entry of the run must have.
+    next_sequence: u8,
+    name: [u16; 260],
+    slots: Vec<EntryLocation>,
+}
+
+impl LfnRun {
+    /// Flag set in the sequence number of the first entry of a run, which
+    /// holds the last part of the name.
+    const LAST_ENTRY: u8 = 0x40;
+    /// The most entries a run may have: 20 entries of 13 characters each.
+    const MAX_ENTRIES: u8 = 20;
+
+    /// Extends `run` with `lfn`, found at `location`. Returns the extended run,
+    /// a new run if `lfn` starts one, or `None` if `lfn` doesn't fit `run`,
+    /// along with the slots of the entries that are left out of any run.
+    pub(crate) fn next(run: Option<LfnRun>, lfn: &VFatLfnDirEntry, location: EntryLocation) -> (Option<LfnRun>, Vec<EntryLocation>) {
+        let sequence = lfn.sequence & 0x1F;
+        let mut orphans = Vec::new();
+        let mut run = if lfn.sequence & LfnRun::LAST_ENTRY != 0 {
+            if let Some(run) = run {
+                orphans = run.slots;
+            }
+            LfnRun { checksum: lfn.checksum, next_sequence: sequence, name: [0; 260], slots: Vec::new() }
+        } else {
+            match run {
+                Some(run) => run,
+                None => return (None, vec![location]),
+            }
+        };
+        if sequence == 0 || sequence > LfnRun::MAX_ENTRIES || sequence != run.next_sequence || lfn.checksum != run.checksum {
+            orphans.extend(run.slots);
+            orphans.push(location);
+            return (None, orphans);
+        }
+
+        let start = (sequence as usize - 1) * 13;
+        let (name, name_2, name_3) = (lfn.name, lfn.name_2, lfn.name_3);
+        run.name[start..start + 5].copy_from_slice(&name);
+        run.name[start + 5..start + 11].copy_from_slice(&name_2);
+        run.name[start + 11..start + 13].copy_from_slice(&name_3);
+        run.next_sequence -= 1;
+        run.slots.push(location);
+        (Some(run), orphans)
+    }
+
+    /// Returns the long file name and the slots of the run if it is complete
+    /// and belongs to the regular entry with short name `short_name`, or else
+    /// just the slots.
+    pub(crate) fn finish(self, short_name: &[u8; 11]) -> Result<(String, Vec<EntryLocation>), Vec<EntryLocation>> {
+        if self.next_sequence != 0 || self.checksum != lfn_checksum(short_name) {
+            return Err(self.slots);
+        }
+
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(self.name.len());
+        match String::from_utf16(&self.name[..len]) {
+            Ok(long_name) => Ok((long_name, self.slots)),
+            Err(_) => Err(self.slots),
+        }
+    }
+
+    /// The slots of the entries in the run.
+    pub(crate) fn into_slots(self) -> Vec<EntryLocation> {
+        self.slots
+    }
+}
+
+/// Where a regular directory entry lives on disk: the directory cluster that
+/// holds it and its byte offset inside of that cluster.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EntryLocation {
+    pub cluster: Cluster,
+    pub offset: usize,
+}
+
+/// A directory entry as read from its directory, independent of any handle to
+/// the file system.
+#[derive(Debug, Clone)]
+pub(crate) struct DirEntryInfo {
+    short_name: String,
+    long_name: String,
+    metadata: Metadata,
+    size: u32,
+    /// The slots the entry occupies, long file name entries first and the
+    /// regular entry last.
+    slots: Vec<EntryLocation>,
+}
+
+impl DirEntryInfo {
+    fn name(&self) -> &str {
+        if self.long_name.is_empty() { &self.short_name } else { &self.long_name }
+    }
+
+    pub(crate) fn slots(&self) -> &[EntryLocation] {
+        &self.slots
+    }
+
+    fn to_entry<HANDLE: VFatHandle>(&self, vfat: HANDLE) -> Entry<HANDLE> {
+        let cluster = Cluster::from(self.metadata.start_cluster());
+        if self.metadata.attributes.directory() {
+            Entry::Dir(Dir {
+                cluster,
+                vfat,
+                short_name: self.short_name.clone(),
+                long_name: self.long_name.clone(),
+                metadata: self.metadata,
+            })
+        } else {
+            Entry::File(File::new(
+                self.short_name.clone(),
+                self.long_name.clone(),
+                self.metadata,
+                cluster,
+                vfat,
+                self.size,
+                *self.slots.last().unwrap(),
+            ))
+        }
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatLfnDirEntry {
@@ -49,6 +206,120 @@ pub struct VFatLfnDirEntry {
 
 const_assert_size!(VFatLfnDirEntry, 32);
 
+impl VFatLfnDirEntry {
+    /// Returns the LFN entry holding the 13 UTF-16 code units of `chunk`.
+    /// `sequence` is the 1-based index of the entry, or'd with `0x40` for the
+    /// last one.
+    fn new(sequence: u8, chunk: &[u16; 13], checksum: u8) -> VFatLfnDirEntry {
+        let (mut name, mut name_2, mut name_3) = ([0u16; 5], [0u16; 6], [0u16; 2]);
+        name.copy_from_slice(&chunk[..5]);
+        name_2.copy_from_slice(&chunk[5..11]);
+        name_3.copy_from_slice(&chunk[11..]);
+        VFatLfnDirEntry {
+            sequence,
+            name,
+            attributes: Attributes::LFN,
+            entry_type: 0,
+            checksum,
+            name_2,
+            reserved: 0,
+            name_3,
+        }
+    }
+}
+
+/// The LFN checksum of the 11 byte short name `short_name`.
+pub(crate) fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| (sum >> 1).wrapping_add(sum << 7).wrapping_add(byte))
+}
+
+/// Characters that may not appear in any file name.
+fn is_invalid_char(c: char) -> bool {
+    (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)
+}
+
+/// Converts the long name `name` into the basis of an 8.3 short name.
+///
+/// Returns the space padded 11 byte name and whether the conversion lost
+/// information, in which case the name needs a numeric tail.
+fn short_name_basis(name: &str) -> ([u8; 11], bool) {
+    fn convert(part: &str, max: usize, out: &mut [u8], lossy: &mut bool) {
+        let mut len = 0;
+        for c in part.chars() {
+            let byte = match c.to_ascii_uppercase() {
+                ' ' | '.' => {
+                    *lossy = true;
+                    continue;
+                }
+                c @ 'A'..='Z' | c @ '0'..='9' => c as u8,
+                c if "!#$%&'()-@^_`{}~".contains(c) => c as u8,
+                _ => {
+                    *lossy = true;
+                    b'_'
+                }
+            };
+            if len == max {
+                *lossy = true;
+                break;
+            }
+            out[len] = byte;
+            len += 1;
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut lossy = false;
+    let trimmed = name.trim_start_matches('.');
+    lossy |= trimmed.len() != name.len();
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    convert(base, 8, &mut short_name[..8], &mut lossy);
+    convert(ext, 3, &mut short_name[8..], &mut lossy);
+    (short_name, lossy)
+}
+
+/// Replaces the end of the basis name in `basis` with the numeric tail `~n`.
+fn with_numeric_tail(basis: &[u8; 11], n: usize) -> [u8; 11] {
+    let tail = format!("~{}", n);
+    let base_len = basis[..8].iter().position(|&b| b == b' ').unwrap_or(8);
+    let keep = ::core::cmp::min(base_len, 8 - tail.len());
+
+    let mut short_name = *basis;
+    short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    for byte in &mut short_name[keep + tail.len()..8] {
+        *byte = b' ';
+    }
+    short_name
+}
+
+/// Formats the 11 byte short name `short_name` as `NAME.EXT`, decoding it
+/// with `code_page` and lowering the case of the parts `case_flags`, the NT
+/// case flags of the entry, mark as lower case.
+pub(crate) fn display_short_name(short_name: &[u8; 11], case_flags: u8, code_page: CodePage) -> String {
+    fn decode(bytes: &[u8], lowercase: bool, code_page: CodePage, out: &mut String) {
+        let len = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+        for &byte in &bytes[..len] {
+            let c = code_page.decode(byte);
+            match lowercase {
+                true => out.extend(c.to_lowercase()),
+                false => out.push(c),
+            }
+        }
+    }
+
+    let mut display = String::new();
+    decode(&short_name[..8], case_flags & Metadata::LOWERCASE_BASE != 0, code_page, &mut display);
+    if short_name[8..].iter().any(|&byte| byte != b' ') {
+        display.push('.');
+        decode(&short_name[8..], case_flags & Metadata::LOWERCASE_EXTENSION != 0, code_page, &mut display);
+    }
+    display
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatUnknownDirEntry {
@@ -61,30 +332,34 @@ pub struct VFatUnknownDirEntry {
 
 const_assert_size!(VFatUnknownDirEntry, 32);
 
+#[derive(Copy, Clone)]
 pub union VFatDirEntry {
-    unknown: VFatUnknownDirEntry,
-    regular: VFatRegularDirEntry,
-    long_filename: VFatLfnDirEntry,
+    pub(crate) unknown: VFatUnknownDirEntry,
+    pub(crate) regular: VFatRegularDirEntry,
+    pub(crate) long_filename: VFatLfnDirEntry,
 }
 
 impl VFatUnknownDirEntry {
-    fn is_deleted_or_unused(&self) -> bool {
+    pub(crate) fn is_deleted_or_unused(&self) -> bool {
         self.entry_type == 0xE5
     }
 
-    fn prev_is_last_entry(&self) -> bool {
+    pub(crate) fn prev_is_last_entry(&self) -> bool {
         self.entry_type == 0x00
     }
 
-    fn is_regular_directory(&self) -> bool {
-        (self.attributes & 0x10) == 0x10
+    pub(crate) fn is_lnf(&self) -> bool {
+        self.attributes == (0x01 | 0x02 | 0x04 | 0x08)
     }
 
-    fn is_lnf(&self) -> bool {
-        self.attributes == (0x01 | 0x02 | 0x04 | 0x08)
+    pub(crate) fn is_volume_label(&self) -> bool {
+        (self.attributes & 0x08) == 0x08 && !self.is_lnf()
     }
 }
 
+/// The most entries a FAT directory may hold.
+const MAX_DIR_ENTRIES: usize = 65536;
+
 impl<HANDLE: VFatHandle> Dir<HANDLE> {
     /// Finds the entry named `name` in `self` and returns it. Comparison is
     /// case-insensitive.
@@ -95,168 +370,668 @@ impl<HANDLE: VFatHandle> Dir<HANDLE> {
     /// returned.
     ///
     /// If `name` contains invalid UTF-8 characters, an error of `InvalidInput`
-    /// is returned.
+    /// is returned. If reading the directory fails, that error is returned.
     pub fn find<P: AsRef<OsStr>>(&self, name: P) -> io::Result<Entry<HANDLE>> {
+        self.find_with_slots(name.as_ref()).map(|(entry, _)| entry)
+    }
+
+    pub fn name(&self) -> &str {
+        if !self.long_name.is_empty() {
+            self.long_name.as_str()
+        }
+        else {
+            self.short_name.as_str()
+        }
+    }
+
+    /// Creates an empty file named `name` in `self` and returns it.
+    ///
+    /// # Errors
+    ///
+    /// If an entry named `name` already exists, an error of `AlreadyExists` is
+    /// returned. If `name` is not a valid file name, an error of
+    /// `InvalidInput` is returned.
+    pub fn create_file<P: AsRef<OsStr>>(&self, name: P) -> io::Result<File<HANDLE>> {
+        let name = self.check_new_name(name.as_ref())?;
+        let metadata = self.vfat.lock(|vfat| Metadata::new(Attributes::ARCHIVE, 0, vfat.now()));
+        let (short_name, long_name, location) = self.add_entry(name, metadata, 0)?;
+        Ok(File::new(short_name, long_name, metadata, Cluster::from(0), self.vfat.clone(), 0, location))
+    }
+
+    /// Creates an empty directory named `name` in `self` and returns it. The
+    /// new directory holds `.` and `..` entries.
+    ///
+    /// # Errors
+    ///
+    /// If an entry named `name` already exists, an error of `AlreadyExists` is
+    /// returned. If `name` is not a valid file name, an error of
+    /// `InvalidInput` is returned.
+    pub fn create_dir<P: AsRef<OsStr>>(&self, name: P) -> io::Result<Dir<HANDLE>> {
+        let name = self.check_new_name(name.as_ref())?;
+        let parent = self.cluster;
+        let (cluster, metadata) = self.vfat.lock(|vfat| -> io::Result<_> {
+            let cluster = vfat.alloc_cluster(None)?;
+            let zeroes = vec![0u8; vfat.bytes_per_cluster()];
+            vfat.write_dir_cluster(cluster, 0, &zeroes)?;
+
+            let now = vfat.now();
+            let metadata = Metadata::new(Attributes::DIRECTORY, cluster.cluster_number(), now);
+            // `..` refers to the root directory with cluster 0.
+            let parent_number = if parent == vfat.root_dir_cluster { 0 } else { parent.cluster_number() };
+            let dots = [
+                VFatRegularDirEntry::new(*b".          ", metadata),
+                VFatRegularDirEntry::new(*b"..         ", Metadata::new(Attributes::DIRECTORY, parent_number, now)),
+            ];
+            vfat.write_dir_cluster(cluster, 0, unsafe { dots.cast() })?;
+            Ok((cluster, metadata))
+        })?;
+
+        let (short_name, long_name, _) = match self.add_entry(name, metadata, 0) {
+            Ok(names) => names,
+            Err(e) => {
+                // Don't leak the directory's cluster if it has no entry.
+                self.vfat.lock(|vfat| vfat.free_chain(cluster))?;
+                return Err(e);
+            }
+        };
+        Ok(Dir {
+            cluster,
+            vfat: self.vfat.clone(),
+            short_name,
+            long_name,
+            metadata,
+        })
+    }
+
+    /// Adds a file named `name` to `self` whose data is the existing cluster
+    /// chain starting at `start`, `size` bytes long.
+    ///
+    /// # Errors
+    ///
+    /// If an entry named `name` already exists, an error of `AlreadyExists` is
+    /// returned. If `name` is not a valid file name, an error of
+    /// `InvalidInput` is returned.
+    pub(crate) fn create_file_from_chain(&self, name: &str, start: Cluster, size: u32) -> io::Result<()> {
+        let name = self.check_new_name(OsStr::new(name))?;
+        let metadata = self.vfat.lock(|vfat| Metadata::new(Attributes::ARCHIVE, start.cluster_number(), vfat.now()));
+        self.add_entry(name, metadata, size)?;
+        Ok(())
+    }
+
+    /// Removes the file named `name` from `self` and frees its clusters.
+    ///
+    /// # Errors
+    ///
+    /// If no entry named `name` exists, an error of `NotFound` is returned. If
+    /// the entry is a directory, an error of `Other` is returned.
+    pub fn remove<P: AsRef<OsStr>>(&self, name: P) -> io::Result<()> {
+        let (entry, slots) = self.find_with_slots(name.as_ref())?;
+        let file = match entry {
+            Entry::File(file) => file,
+            Entry::Dir(_) => return Err(io::Error::new(io::ErrorKind::Other, "is a directory")),
+        };
+
+        self.vfat.lock(|vfat| {
+            Self::mark_deleted(vfat, &slots)?;
+            if file.start_cluster.is_valid() {
+                vfat.free_chain(file.start_cluster)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes the empty directory named `name` from `self` and frees its
+    /// clusters.
+    ///
+    /// # Errors
+    ///
+    /// If no entry named `name` exists, an error of `NotFound` is returned. If
+    /// `name` is `.` or `..`, an error of `InvalidInput` is returned. If the
+    /// entry is not a directory or the directory is not empty, an error of
+    /// `Other` is returned.
+    pub fn remove_dir<P: AsRef<OsStr>>(&self, name: P) -> io::Result<()> {
         use traits::{Dir, Entry};
 
-        let name_str = match name.as_ref().to_str() {
-            Some(name_str) => name_str,
-            None => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid UTF-8 name"));
+        let (entry, slots) = self.find_with_slots(name.as_ref())?;
+        let dir = match entry.into_dir() {
+            Some(dir) => dir,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "not a directory")),
+        };
+        if dir.name() == "." || dir.name() == ".." {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot remove `.` or `..`"));
+        }
+        let mut entries = dir.entries()?;
+        let not_empty = entries.any(|e| e.name() != "." && e.name() != "..");
+        if let Some(e) = entries.take_error() {
+            return Err(e);
+        }
+        if not_empty {
+            return Err(io::Error::new(io::ErrorKind::Other, "directory not empty"));
+        }
+
+        self.vfat.lock(|vfat| {
+            Self::mark_deleted(vfat, &slots)?;
+            vfat.free_chain(dir.cluster)
+        })
+    }
+
+    /// Renames the entry named `name` in `self` to `new_name` in the directory
+    /// `to`, which may be `self`. The entry's data and metadata are kept.
+    /// Handles to the entry opened before the rename must not be used after
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// If no entry named `name` exists, an error of `NotFound` is returned. If
+    /// an entry named `new_name` already exists in `to`, an error of
+    /// `AlreadyExists` is returned. Renaming `.` or `..`, or moving a
+    /// directory into itself or one of its subdirectories results in an error
+    /// of `InvalidInput`.
+    pub fn rename<P, Q>(&self, name: P, to: &Dir<HANDLE>, new_name: Q) -> io::Result<()>
+    where
+        P: AsRef<OsStr>,
+        Q: AsRef<OsStr>,
+    {
+        use traits::Entry as _;
+
+        let (entry, slots) = self.find_with_slots(name.as_ref())?;
+        if entry.name() == "." || entry.name() == ".." {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot rename `.` or `..`"));
+        }
+
+        let same_dir = self.cluster == to.cluster;
+        let new_name = to.check_name(new_name.as_ref())?;
+        if !(same_dir && new_name.eq_ignore_ascii_case(entry.name())) {
+            to.check_not_exists(new_name)?;
+        }
+
+        let (metadata, size) = match entry {
+            Entry::File(ref file) => (file.metadata, file.size),
+            Entry::Dir(ref dir) => {
+                if !same_dir && to.is_within(dir.cluster)? {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot move a directory into itself"));
+                }
+                (dir.metadata, 0)
+            }
+        };
+
+        // Free the old slots first so that a rename in place may reuse them,
+        // but restore them if the new entry can't be written.
+        let saved = self.vfat.lock(|vfat| -> io::Result<_> {
+            let mut saved = Vec::with_capacity(slots.len());
+            for location in slots.iter() {
+                let mut slot = [0u8; 32];
+                vfat.read_cluster(location.cluster, location.offset, &mut slot)?;
+                saved.push(slot);
+            }
+            Self::mark_deleted(vfat, &slots)?;
+            Ok(saved)
+        })?;
+
+        if let Err(e) = to.add_entry(new_name, metadata, size) {
+            self.vfat.lock(|vfat| -> io::Result<()> {
+                for (location, slot) in slots.iter().zip(saved.iter()) {
+                    vfat.write_dir_cluster(location.cluster, location.offset, slot)?;
+                }
+                Ok(())
+            })?;
+            return Err(e);
+        }
+
+        if let Entry::Dir(ref dir) = entry {
+            if !same_dir {
+                // Point the moved directory's `..` entry at its new parent.
+                let parent = to.cluster;
+                self.vfat.lock(|vfat| -> io::Result<()> {
+                    let parent_number = if parent == vfat.root_dir_cluster { 0 } else { parent.cluster_number() };
+                    let dotdot = EntryLocation { cluster: dir.cluster, offset: size_of::<VFatDirEntry>() };
+                    vfat.dir_entry_mut(dotdot)?.metadata.set_start_cluster(parent_number);
+                    Ok(())
+                })?;
             }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the entry named `name` like `find()` and also returns the
+    /// locations of every slot it occupies.
+    fn find_with_slots(&self, name: &OsStr) -> io::Result<(Entry<HANDLE>, Vec<EntryLocation>)> {
+        use traits::Dir;
+
+        let name_str = match name.to_str() {
+            Some(name_str) => name_str,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid UTF-8 name")),
         };
 
-        // let name_str = name.as_ref().to_str().unwrap();
+        if let Some(info) = self.vfat.lock(|vfat| vfat.lookups.get(self.cluster, name_str)) {
+            return Ok((info.to_entry(self.vfat.clone()), info.slots));
+        }
 
-        for t in self.entries()? {
-            if name_str.eq_ignore_ascii_case(t.name()) {
-                return Ok(t);
+        let mut entries = self.entries()?;
+        while let Some(info) = entries.next_info() {
+            if name_str.eq_ignore_ascii_case(info.name()) {
+                self.vfat.lock(|vfat| vfat.lookups.insert(self.cluster, name_str, info.clone()));
+                return Ok((info.to_entry(self.vfat.clone()), info.slots));
             }
         }
+        if let Some(e) = entries.take_error() {
+            return Err(e);
+        }
         Err(io::Error::new(io::ErrorKind::NotFound, format!("not found, {}", name_str)))
     }
 
-    pub fn name(&self) -> &str {
-        if !self.long_name.is_empty() {
-            self.long_name.as_str()
+    /// Changes the attributes and timestamps of the entry named `name` in
+    /// `self` as described by `changes`.
+    ///
+    /// # Errors
+    ///
+    /// If no entry named `name` exists, an error of `NotFound` is returned.
+    /// Files and directories opened before the change keep the metadata they
+    /// were opened with.
+    pub fn set_metadata<P: AsRef<OsStr>>(&self, name: P, changes: &MetadataChanges<Timestamp>) -> io::Result<()> {
+        let (_, slots) = self.find_with_slots(name.as_ref())?;
+        let location = *slots.last().unwrap();
+        self.vfat.lock(|vfat| -> io::Result<()> {
+            vfat.dir_entry_mut(location)?.metadata.apply(changes);
+            Ok(())
+        })
+    }
+
+    /// Marks the directory entry slots at `slots` as deleted.
+    pub(crate) fn mark_deleted(vfat: &mut VFat<HANDLE>, slots: &[EntryLocation]) -> io::Result<()> {
+        for location in slots {
+            vfat.write_dir_cluster(location.cluster, location.offset, &[0xE5])?;
         }
-        else {
-            self.short_name.as_str()
+        Ok(())
+    }
+
+    /// Returns `true` if `self` is the directory starting at `cluster` or one
+    /// of its subdirectories.
+    fn is_within(&self, cluster: Cluster) -> io::Result<bool> {
+        use traits::Entry;
+
+        let root = self.vfat.lock(|vfat| vfat.root_dir_cluster);
+        let mut current = self.cluster;
+        loop {
+            if current == cluster {
+                return Ok(true);
+            } else if current == root {
+                return Ok(false);
+            }
+
+            let parent = Dir {
+                cluster: current,
+                vfat: self.vfat.clone(),
+                short_name: String::new(),
+                long_name: String::new(),
+                metadata: Metadata::default(),
+            }
+            .find("..")?
+            .metadata()
+            .start_cluster();
+            current = if parent == 0 { root } else { Cluster::from(parent) };
+        }
+    }
+
+    /// Checks that `name` is a valid name for a new entry in `self` that
+    /// doesn't exist yet.
+    fn check_new_name<'a>(&self, name: &'a OsStr) -> io::Result<&'a str> {
+        let name = self.check_name(name)?;
+        self.check_not_exists(name)?;
+        Ok(name)
+    }
+
+    /// Checks that `name` is a valid name for an entry. Trailing spaces and
+    /// periods are not part of a name and are stripped.
+    fn check_name<'a>(&self, name: &'a OsStr) -> io::Result<&'a str> {
+        let name = match name.to_str() {
+            Some(name) => name.trim_end_matches([' ', '.']),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid UTF-8 name")),
+        };
+
+        if name.is_empty() || name.encode_utf16().count() > 255 || name.chars().any(is_invalid_char) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"));
+        }
+        Ok(name)
+    }
+
+    /// Checks that no entry named `name` exists in `self`.
+    fn check_not_exists(&self, name: &str) -> io::Result<()> {
+        match self.find(name) {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, "entry already exists")),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the directory entries for a new entry named `name` with
+    /// `metadata` and `size` into `self`: a unique short name entry, preceded
+    /// by long file name entries unless `name` is a valid short name itself.
+    ///
+    /// Returns the short and long name of the entry along with the location
+    /// of its regular entry.
+    fn add_entry(&self, name: &str, metadata: Metadata, size: u32) -> io::Result<(String, String, EntryLocation)> {
+        let dir_cluster = self.cluster;
+        self.vfat.lock(|vfat| {
+            let scan = Self::scan_slots(vfat, dir_cluster)?;
+            let existing = &scan.short_names;
+            let mut clusters = scan.clusters;
+
+            let (basis, lossy) = short_name_basis(name);
+            let short_name = if !lossy && !existing.contains(&basis) {
+                basis
+            } else {
+                (1..1000000)
+                    .map(|n| with_numeric_tail(&basis, n))
+                    .find(|candidate| !existing.contains(candidate))
+                    .ok_or(io::Error::new(io::ErrorKind::AlreadyExists, "no unique short name left"))?
+            };
+
+            let display_name = display_short_name(&short_name, 0, vfat.code_page());
+            let long_name = if display_name == name { String::new() } else { String::from(name) };
+
+            let mut entries = Vec::new();
+            if !long_name.is_empty() {
+                let checksum = lfn_checksum(&short_name);
+                let mut units: Vec<u16> = long_name.encode_utf16().collect();
+                if !units.len().is_multiple_of(13) {
+                    units.push(0);
+                }
+                while !units.len().is_multiple_of(13) {
+                    units.push(0xFFFF);
+                }
+
+                let count = units.len() / 13;
+                for i in (0..count).rev() {
+                    let mut chunk = [0u16; 13];
+                    chunk.copy_from_slice(&units[i * 13..(i + 1) * 13]);
+                    let sequence = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+                    entries.push(VFatDirEntry { long_filename: VFatLfnDirEntry::new(sequence, &chunk, checksum) });
+                }
+            }
+            let mut regular = VFatRegularDirEntry::new(short_name, metadata);
+            regular.set_size(size);
+            entries.push(VFatDirEntry { regular });
+
+            // Use the first run of free slots long enough to hold the entries,
+            // or else the free slots at the end of the directory.
+            let start = scan
+                .free_runs
+                .iter()
+                .find(|&&(_, len)| len >= entries.len())
+                .map_or(scan.end, |&(start, _)| start);
+
+            if start + entries.len() > MAX_DIR_ENTRIES {
+                return Err(io::Error::new(io::ErrorKind::Other, "directory is full"));
+            }
+
+            // Grow the directory by zeroed clusters until the entries fit. The
+            // fixed root directory of FAT12 and FAT16 volumes can't grow.
+            let bytes_per_cluster = vfat.dir_cluster_size(dir_cluster);
+            let slots_per_cluster = bytes_per_cluster / size_of::<VFatDirEntry>();
+            if vfat.is_fixed_root(dir_cluster) && start + entries.len() > slots_per_cluster {
+                return Err(io::Error::new(io::ErrorKind::Other, "directory is full"));
+            }
+            while clusters.len() * slots_per_cluster < start + entries.len() {
+                let cluster = vfat.alloc_cluster(clusters.last().cloned())?;
+                vfat.write_dir_cluster(cluster, 0, &vec![0u8; bytes_per_cluster])?;
+                clusters.push(cluster);
+            }
+
+            let mut location = None;
+            for (i, entry) in entries.iter().enumerate() {
+                let offset = (start + i) * size_of::<VFatDirEntry>();
+                let slot_location = EntryLocation {
+                    cluster: clusters[offset / bytes_per_cluster],
+                    offset: offset % bytes_per_cluster,
+                };
+                let bytes: &[u8] = unsafe { ::core::slice::from_ref(entry).cast() };
+                vfat.write_dir_cluster(slot_location.cluster, slot_location.offset, bytes)?;
+                location = Some(slot_location);
+            }
+
+            Ok((display_name, long_name, location.unwrap()))
+        })
+    }
+
+    /// Reads the slots of the directory starting at `cluster` one cluster at
+    /// a time, as `EntryIterator` does, and returns what `add_entry()` needs
+    /// to know about them.
+    fn scan_slots(vfat: &mut VFat<HANDLE>, cluster: Cluster) -> io::Result<SlotScan> {
+        let mut scan = SlotScan { short_names: Vec::new(), free_runs: Vec::new(), end: 0, clusters: vec![cluster] };
+        let mut data = EntryIterator::<HANDLE>::load(vfat, cluster)?;
+        let mut index = 0;
+        let mut run = 0;
+        loop {
+            for slot in data.iter() {
+                let unknown = unsafe { slot.unknown };
+                if unknown.prev_is_last_entry() {
+                    // Every slot from the end-of-directory marker on is free.
+                    scan.end = index - run;
+                    return Ok(scan);
+                } else if unknown.is_deleted_or_unused() {
+                    run += 1;
+                } else {
+                    if run > 0 {
+                        scan.free_runs.push((index - run, run));
+                        run = 0;
+                    }
+                    if !unknown.is_lnf() {
+                        scan.short_names.push(unsafe { slot.regular }.short_name());
+                    }
+                }
+                index += 1;
+            }
+
+            let last = *scan.clusters.last().unwrap();
+            match EntryIterator::<HANDLE>::next_cluster(vfat, last, scan.clusters.len() as u32)? {
+                Some((next, next_data)) => {
+                    scan.clusters.push(next);
+                    data = next_data;
+                }
+                None => {
+                    scan.end = index - run;
+                    return Ok(scan);
+                }
+            }
         }
     }
 }
 
+/// The slots of a directory, as found by `Dir::scan_slots()`.
+struct SlotScan {
+    /// The short names of the entries in the directory.
+    short_names: Vec<[u8; 11]>,
+    /// The runs of free slots before the end of the directory, as the index
+    /// of their first slot and their length.
+    free_runs: Vec<(usize, usize)>,
+    /// The index of the first of the free slots at the end of the directory,
+    /// which may lie past its last cluster.
+    end: usize,
+    /// The clusters of the directory.
+    clusters: Vec<Cluster>,
+}
+
 impl<HANDLE: VFatHandle> traits::Dir for Dir<HANDLE> {
     // FIXME: Implement `trait::Dir` for `Dir`.
     type Entry = Entry<HANDLE>;
     type Iter =  EntryIterator<HANDLE>;
 
     fn entries(&self) -> io::Result<Self::Iter> {
-        let mut buf = Vec::new();
-        self.vfat.lock(|vfat| vfat.read_chain(self.cluster, &mut buf))?;
+        let (data, code_page) = self.vfat.lock(|vfat| -> io::Result<_> {
+            Ok((EntryIterator::<HANDLE>::load(vfat, self.cluster)?, vfat.code_page()))
+        })?;
         Ok(EntryIterator{
             vfat: self.vfat.clone(),
+            code_page,
+            cluster: Some(self.cluster),
+            clusters_read: 1,
             curr_index: 0,
-            data: unsafe {buf.cast()},
+            data,
+            error: None,
         })
     }
 }
 
+/// Iterates over the entries of a directory, reading one cluster of the
+/// directory at a time.
 pub struct EntryIterator<HANDLE: VFatHandle> {
     pub vfat: HANDLE,
+    /// The code page short names are decoded with.
+    code_page: CodePage,
+    /// The cluster whose slots are in `data`, or `None` once the end of the
+    /// directory has been reached.
+    cluster: Option<Cluster>,
+    /// The number of clusters read so far, to stop at chains that loop.
+    clusters_read: u32,
     curr_index: usize,
     data: Vec<VFatDirEntry>,
+    /// The error that ended the iteration early, if reading the directory
+    /// failed.
+    error: Option<io::Error>,
 }
 
-impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
-    type Item = Entry<HANDLE>;
+impl<HANDLE: VFatHandle> EntryIterator<HANDLE> {
+    /// Reads the directory entry slots in `cluster`.
+    pub(crate) fn load(vfat: &mut VFat<HANDLE>, cluster: Cluster) -> io::Result<Vec<VFatDirEntry>> {
+        let mut buf = vec![0u8; vfat.dir_cluster_size(cluster)];
+        vfat.read_cluster(cluster, 0, &mut buf)?;
+        Ok(unsafe { buf.cast() })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut raw_long_file_name = [0u16; 260];
-        let current_index = self.curr_index;
-        while self.curr_index < self.data.len() {
-            let entry: &VFatDirEntry = self.data.get(self.curr_index).unwrap();
+    /// Returns the cluster of a directory following `cluster` along with its
+    /// slots, or `None` at the end of the directory. `clusters_read` is the
+    /// number of clusters of the directory read so far, to stop at chains
+    /// that loop.
+    fn next_cluster(
+        vfat: &mut VFat<HANDLE>,
+        cluster: Cluster,
+        clusters_read: u32,
+    ) -> io::Result<Option<(Cluster, Vec<VFatDirEntry>)>> {
+        if vfat.is_fixed_root(cluster) || clusters_read > vfat.cluster_count() {
+            return Ok(None);
+        }
+        match vfat.next_cluster(cluster)? {
+            Some(next) => Ok(Some((next, Self::load(vfat, next)?))),
+            None => Ok(None),
+        }
+    }
 
+    /// Returns the error that ended the iteration early, if reading the
+    /// directory failed. No more entries are returned after such an error, so
+    /// an iteration that ends is only complete if this returns `None`.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Returns the on-disk location of the slot at `index` in `self.data`.
+    fn location(&self, index: usize) -> EntryLocation {
+        EntryLocation {
+            cluster: self.cluster.unwrap(),
+            offset: index * size_of::<VFatDirEntry>(),
+        }
+    }
+
+    /// Returns the next slot of the directory along with its location, reading
+    /// the next cluster of the directory if every slot in `self.data` has been
+    /// returned. Returns `None` at the end of the cluster chain. If reading
+    /// the chain fails, the error is kept in `self.error` and `None` is
+    /// returned.
+    fn next_slot(&mut self) -> Option<(VFatDirEntry, EntryLocation)> {
+        let cluster = self.cluster?;
+        if self.curr_index == self.data.len() {
+            let clusters_read = self.clusters_read;
+            let next = self.vfat.lock(|vfat| Self::next_cluster(vfat, cluster, clusters_read));
+            match next {
+                Ok(Some((next, data))) => {
+                    self.cluster = Some(next);
+                    self.clusters_read += 1;
+                    self.curr_index = 0;
+                    self.data = data;
+                }
+                Ok(None) => {
+                    self.cluster = None;
+                    return None;
+                }
+                Err(e) => {
+                    self.cluster = None;
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+
+        let location = self.location(self.curr_index);
+        self.curr_index += 1;
+        Some((self.data[self.curr_index - 1], location))
+    }
+
+    /// Returns the next entry along with the locations of the slots it
+    /// occupies, long file name entries included.
+    fn next_info(&mut self) -> Option<DirEntryInfo> {
+        // The long file name entries read since the last regular entry, if they
+        // form a valid run so far.
+        let mut run: Option<LfnRun> = None;
+        while let Some((entry, location)) = self.next_slot() {
             let unknown = unsafe {entry.unknown};
 
             if unknown.is_deleted_or_unused() {
-                // Deleted entry
-                self.curr_index += 1;
+                // Deleted entry: long file name entries before it are orphans.
+                run = None;
                 continue;
             }
             else if unknown.prev_is_last_entry() {
-                // End of FAT
+                // End of directory: no slots are read past it.
+                self.cluster = None;
                 return None;
             }
-            
-            self.curr_index += 1;
+            else if unknown.is_volume_label() {
+                // The volume label is not a file
+                run = None;
+                continue;
+            }
+
             if unknown.is_lnf() {
                 let lnf = unsafe {entry.long_filename};
-
-                let lnf_index = (lnf.sequence & 0b11111) as usize - 1;
-                let slot = lnf_index * 13;
-                
-                unsafe {
-                    raw_long_file_name[slot..slot+5].copy_from_slice(&lnf.name);
-                    raw_long_file_name[slot + 5..slot + 11].copy_from_slice(&lnf.name_2);
-                    raw_long_file_name[slot + 11..slot + 13].copy_from_slice(&lnf.name_3);
-                }
+                run = LfnRun::next(run, &lnf, location).0;
             }
             else {
                 let regular_entry = unsafe { entry.regular };
 
-                let mut short_file_name = regular_entry.file_name;
+                let mut short_file_name = regular_entry.short_name();
 
                 if short_file_name[0] == 0x05 {
                     short_file_name[0] = 0xE5;
                 }
-                let name = ::core::str::from_utf8(&short_file_name).unwrap().trim_end();
-                let ext = ::core::str::from_utf8(&regular_entry.file_extension).unwrap().trim_end();
+                let short_name =
+                    display_short_name(&short_file_name, regular_entry.metadata.case_flags(), self.code_page);
+                // A run belongs to this entry only if it is complete and its
+                // checksum matches; otherwise the short name is used alone.
+                let (long_name, mut slots) = match run.take().and_then(|run| run.finish(&regular_entry.short_name()).ok()) {
+                    Some((long_name, slots)) => (long_name, slots),
+                    None => (String::new(), Vec::new()),
+                };
+                slots.push(location);
 
-                let mut short_name = String::from(name);
-                if !ext.is_empty() {
-                    short_name.push_str(".");
-                    short_name.push_str(ext);
-                }
-                let mut nul_byte_index = None;
-                for (i, byte) in raw_long_file_name.iter().enumerate() {
-                    if *byte == 0 {
-                        nul_byte_index = Some(i);
-                        break;
-                    }
-                }
-                let long_name = String::from_utf16(if let Some(len) = nul_byte_index {
-                    &raw_long_file_name[0..len]
-                } else {
-                    &raw_long_file_name
-                }).unwrap();
-
-                if regular_entry.metadata.attributes.directory() {
-                    return Some(Entry::Dir(Dir {
-                        cluster: Cluster::from(regular_entry.metadata.start_cluster()),
-                        vfat: self.vfat.clone(),
-                        short_name,
-                        long_name,
-                        metadata: regular_entry.metadata,
-                    }));
-                }
-                else {
-                    return Some(Entry::File(File {
-                        short_name: short_name,
-                        long_name: long_name, 
-                        metadata: regular_entry.metadata,
-                        start_cluster: Cluster::from(regular_entry.metadata.start_cluster()),
-                        vfat: self.vfat.clone(),
-                        size: regular_entry.size,
-                        offset: 0,
-                        curr_cluster: Some(Cluster::from(regular_entry.metadata.start_cluster())),
-                    }));
-                }
-                // let file_name = if short_file_name[0] == 0x00 {
-                //     let name = core::str::from_utf8(&regular_entry.file_name).unwrap().trim_end();
-                //     let extension = core::str::from_utf8(&regular_entry.file_extension).unwrap().trim_end();
-                //     let mut short_name = String::from(name);
-                //     if !extension.is_empty() {
-                //         short_name.push('.');
-                //         short_name.push_str(extension);
-                //     }
-                //     short_name
-                // } else {
-                //     let mut len = short_file_name.len();
-                //     for i in 0..len {
-                //         if short_file_name[i] == 0x00 || short_file_name[i] == 0xFF {
-                //             len = i;
-                //             break;
-                //         }
-                //     }
-
-                //     let long_file_name = ::core::char::decode_utf16(raw_long_file_name[0..len].iter().cloned())
-                //         .map(|r| r.unwrap())
-                //         .collect::<String>();
-                //     long_file_name
-                // };
+                return Some(DirEntryInfo {
+                    short_name,
+                    long_name,
+                    metadata: regular_entry.metadata,
+                    size: regular_entry.size,
+                    slots,
+                });
             }
         }
         None
     }
 }
+
+impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
+    type Item = Entry<HANDLE>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let vfat = self.vfat.clone();
+        self.next_info().map(|info| info.to_entry(vfat))
+    }
+}
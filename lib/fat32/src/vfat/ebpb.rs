@@ -1,7 +1,9 @@
+use alloc::string::String;
 use core::fmt;
 use shim::const_assert_size;
 
 use crate::traits::BlockDevice;
+use crate::util::SliceExt;
 use crate::vfat::Error;
 
 #[repr(C, packed)]
@@ -14,28 +16,28 @@ pub struct BiosParameterBlock {
     pub sectors_per_cluster: u8,
     pub reserved_sectors: u16,
     pub number_of_fat: u8,
-    max_directory_entries: u16,
+    pub max_directory_entries: u16,
     pub total_logical_sectors: u16,
     descriptor_type: u8,
     pub sectors_per_fat: u16,
     pub sectors_per_track: u16,
     heads_or_sides: u16,
-    num_hidden_sectors: u32,
+    pub num_hidden_sectors: u32,
     pub total_logical_sectors_2: u32,
 
     // EBPB
     pub sectors_per_fat_32: u32,
-    flags: u16,
+    pub flags: u16,
     fat_version: u16,
     pub root_dir_cluster_number: u32,
-    fs_info_sector_number: u16,
-    back_up_boot_sector_number: u16,
+    pub fs_info_sector_number: u16,
+    pub back_up_boot_sector_number: u16,
     formated_reserve: [u8; 12],
     drive_number: u8,
     windows_flag: u8,
     signature: u8,
-    volume_id: u32,
-    volume_label: [u8; 11],
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
     identifier_string: [u8; 8],
     boot_code: [u8; 420],
     bootable_partition_signature: u16,
@@ -44,6 +46,81 @@ pub struct BiosParameterBlock {
 const_assert_size!(BiosParameterBlock, 512);
 
 impl BiosParameterBlock {
+    /// Returns a FAT32 EBPB with the boot sector signatures, FAT32 type
+    /// strings and usual defaults filled in: a fixed disk with its FSInfo
+    /// sector at 1, a backup boot sector at 6 and the root directory at
+    /// cluster 2. The volume geometry is left zeroed for the caller to set.
+    pub(crate) fn new_fat32() -> BiosParameterBlock {
+        let mut bpb: BiosParameterBlock = unsafe { core::mem::zeroed() };
+        bpb.jump_instruction = [0xEB, 0x58, 0x90];
+        bpb.oem_dientifier = *b"RUSTOS  ";
+        bpb.descriptor_type = 0xF8;
+        bpb.root_dir_cluster_number = 2;
+        bpb.fs_info_sector_number = 1;
+        bpb.back_up_boot_sector_number = 6;
+        bpb.drive_number = 0x80;
+        bpb.signature = 0x29;
+        bpb.volume_label = *b"NO NAME    ";
+        bpb.identifier_string = *b"FAT32   ";
+        bpb.bootable_partition_signature = 0xAA55;
+        bpb
+    }
+
+    /// Returns `true` if the BPB describes a plausible FAT volume. Used to
+    /// tell a volume boot sector apart from an MBR, which share a signature.
+    pub(crate) fn is_plausible(&self) -> bool {
+        let bytes_per_sector = self.bytes_per_sector;
+        let sectors_per_fat = self.sectors_per_fat as u32 | self.sectors_per_fat_32;
+        (self.jump_instruction[0] == 0xEB || self.jump_instruction[0] == 0xE9)
+            && bytes_per_sector.is_power_of_two()
+            && (512..=4096).contains(&bytes_per_sector)
+            && self.sectors_per_cluster.is_power_of_two()
+            && self.reserved_sectors != 0
+            && self.number_of_fat != 0
+            && sectors_per_fat != 0
+            && self.total_sectors() != 0
+    }
+
+    /// The volume serial number, if the boot sector has an extended boot
+    /// signature. FAT12 and FAT16 volumes store it at a different offset than
+    /// FAT32 volumes, so prefer this to the `volume_id` field.
+    pub fn serial_number(&self) -> Option<u32> {
+        let (signature, id, _) = self.extended_fields();
+        match signature {
+            0x28 | 0x29 => Some(u32::from_le_bytes([id[0], id[1], id[2], id[3]])),
+            _ => None,
+        }
+    }
+
+    /// The volume label with trailing spaces removed, if the boot sector has
+    /// one. Like `serial_number()`, this reads the right offset on every FAT
+    /// type.
+    pub fn label(&self) -> Option<String> {
+        let (signature, _, label) = self.extended_fields();
+        if signature != 0x29 {
+            return None;
+        }
+        let len = label.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+        Some(label[..len].iter().map(|&byte| byte as char).collect())
+    }
+
+    /// The extended boot signature, volume ID and volume label bytes, which
+    /// follow the FAT32 EBPB fields on FAT32 volumes and the BPB directly on
+    /// FAT12 and FAT16 volumes.
+    fn extended_fields(&self) -> (u8, &[u8], &[u8]) {
+        let bytes: &[u8] = unsafe { core::slice::from_ref(self).cast() };
+        let start = if self.sectors_per_fat == 0 { 66 } else { 38 };
+        (bytes[start], &bytes[start + 1..start + 5], &bytes[start + 5..start + 16])
+    }
+
+    /// The number of sectors in the volume.
+    pub fn total_sectors(&self) -> u64 {
+        match self.total_logical_sectors {
+            0 => self.total_logical_sectors_2 as u64,
+            sectors => sectors as u64,
+        }
+    }
+
     /// Reads the FAT32 extended BIOS parameter block from sector `sector` of
     /// device `device`.
     ///
@@ -74,7 +151,25 @@ impl BiosParameterBlock {
 
 impl fmt::Debug for BiosParameterBlock {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("BioParameterBlock")
+        let oem_identifier = self.oem_dientifier;
+        f.debug_struct("BiosParameterBlock")
+            .field("oem_identifier", &String::from_utf8_lossy(&oem_identifier))
+            .field("bytes_per_sector", &{ self.bytes_per_sector })
+            .field("sectors_per_cluster", &self.sectors_per_cluster)
+            .field("reserved_sectors", &{ self.reserved_sectors })
+            .field("number_of_fat", &self.number_of_fat)
+            .field("max_directory_entries", &{ self.max_directory_entries })
+            .field("total_sectors", &self.total_sectors())
+            .field("descriptor_type", &format_args!("{:#04x}", self.descriptor_type))
+            .field("sectors_per_fat", &{ self.sectors_per_fat })
+            .field("sectors_per_fat_32", &{ self.sectors_per_fat_32 })
+            .field("num_hidden_sectors", &{ self.num_hidden_sectors })
+            .field("flags", &format_args!("{:#06x}", { self.flags }))
+            .field("root_dir_cluster_number", &{ self.root_dir_cluster_number })
+            .field("fs_info_sector_number", &{ self.fs_info_sector_number })
+            .field("back_up_boot_sector_number", &{ self.back_up_boot_sector_number })
+            .field("serial_number", &self.serial_number())
+            .field("label", &self.label())
             .finish()
     }
 }
@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use crate::vfat::*;
 use core::fmt;
 
@@ -19,9 +20,71 @@ pub enum Status {
     Eoc(u32),
 }
 
+/// The FAT variant of a volume. As the specification requires, it is
+/// determined by the number of data clusters alone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Returns the FAT type of a volume with `clusters` data clusters.
+    pub fn from_cluster_count(clusters: u32) -> FatType {
+        match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+
+    /// The width of a FAT entry in bits.
+    pub fn entry_bits(self) -> u32 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    /// The bits of an entry that hold its value.
+    fn mask(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    /// Converts the raw FAT entry `raw` of this type into the equivalent
+    /// FAT32 entry: reserved, bad and end of chain values are extended.
+    pub(crate) fn widen(self, raw: u32) -> u32 {
+        match self {
+            FatType::Fat32 => raw,
+            _ if raw >= self.mask() - 0xF => raw | (0x0FFFFFFF & !self.mask()),
+            _ => raw,
+        }
+    }
+
+    /// Converts the FAT32 entry `value` into the equivalent entry of this type.
+    pub(crate) fn narrow(self, value: u32) -> u32 {
+        value & self.mask()
+    }
+}
+
 #[repr(C, packed)]
 pub struct FatEntry(pub u32);
 
+/// A cluster whose entries differ between the copies of the FAT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatMismatch {
+    /// The cluster number of the entry.
+    pub cluster: u32,
+    /// The raw entry for the cluster in each FAT copy, in on-disk order.
+    pub entries: Vec<u32>,
+}
+
 impl FatEntry {
     /// Returns the `Status` of the FAT entry `self`.
     pub fn status(&self) -> Status {
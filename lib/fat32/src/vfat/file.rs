@@ -1,9 +1,10 @@
 use alloc::string::String;
+use alloc::vec::Vec;
 
 use shim::io::{self, SeekFrom};
 
 use crate::traits;
-use crate::vfat::{Cluster, Metadata, VFatHandle, FatEntry};
+use crate::vfat::{Cluster, EntryLocation, Metadata, VFatHandle};
 
 #[derive(Debug)]
 pub struct File<HANDLE: VFatHandle> {
@@ -16,10 +17,31 @@ pub struct File<HANDLE: VFatHandle> {
     pub size: u32,
     pub offset: u32,
     pub curr_cluster: Option<Cluster>,
+    /// The cluster before `curr_cluster` in the chain. New clusters are linked
+    /// after it once `curr_cluster` runs off the end of the chain.
+    pub prev_cluster: Option<Cluster>,
+    /// Location of this file's entry in its parent directory.
+    pub entry: EntryLocation,
+    /// The runs of physically contiguous clusters of the file's chain found
+    /// so far, in chain order.
+    extents: Vec<Extent>,
+    /// Whether `extents` covers the whole chain.
+    extents_complete: bool,
+}
+
+/// A run of physically contiguous clusters in a file's cluster chain.
+#[derive(Debug, Copy, Clone)]
+struct Extent {
+    /// The position of the run's first cluster in the chain.
+    index: u32,
+    /// The run's first cluster.
+    start: Cluster,
+    /// The number of clusters in the run.
+    len: u32,
 }
 
 impl<HANDLE: VFatHandle> File<HANDLE> {
-    pub fn new(short_name: String, long_name: String, metadata: Metadata, start_cluster: Cluster, vfat: HANDLE, size: u32) -> File<HANDLE> {
+    pub fn new(short_name: String, long_name: String, metadata: Metadata, start_cluster: Cluster, vfat: HANDLE, size: u32, entry: EntryLocation) -> File<HANDLE> {
         File {
             short_name,
             long_name,
@@ -29,7 +51,114 @@ impl<HANDLE: VFatHandle> File<HANDLE> {
             size,
             offset: 0,
             curr_cluster: Some(start_cluster),
+            prev_cluster: None,
+            entry,
+            extents: Vec::new(),
+            extents_complete: false,
+        }
+    }
+
+    /// Returns the cluster at position `index` in the file's chain, or `None`
+    /// if the chain is shorter. The extent map is extended as far as needed,
+    /// so the chain is only walked once per open file.
+    fn cluster_at(&mut self, index: u32) -> io::Result<Option<Cluster>> {
+        let (extents, start_cluster) = (&mut self.extents, self.start_cluster);
+        if extents.is_empty() && !self.extents_complete {
+            match start_cluster.is_valid() {
+                true => extents.push(Extent { index: 0, start: start_cluster, len: 1 }),
+                false => self.extents_complete = true,
+            }
+        }
+
+        let mapped = |extents: &Vec<Extent>| extents.last().map_or(0, |extent| extent.index + extent.len);
+        if !self.extents_complete && index >= mapped(extents) {
+            self.extents_complete = self.vfat.lock(|vfat| -> io::Result<bool> {
+                while index >= mapped(extents) {
+                    let last = extents.last_mut().unwrap();
+                    if last.index + last.len > vfat.cluster_count() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "cluster chain loops"));
+                    }
+                    let last_cluster = last.start.cluster_number() + last.len - 1;
+                    match vfat.next_cluster(Cluster::from(last_cluster))? {
+                        Some(next) if next.cluster_number() == last_cluster + 1 => last.len += 1,
+                        Some(next) => {
+                            let index = last.index + last.len;
+                            extents.push(Extent { index, start: next, len: 1 });
+                        }
+                        None => return Ok(true),
+                    }
+                }
+                Ok(false)
+            })?;
+        }
+
+        // The extent holding `index` is the last one starting at or before it.
+        let found = match self.extents.binary_search_by_key(&index, |extent| extent.index) {
+            Ok(position) => Some(position),
+            Err(0) => None,
+            Err(position) => Some(position - 1),
+        };
+        Ok(found
+            .map(|position| self.extents[position])
+            .filter(|extent| index < extent.index + extent.len)
+            .map(|extent| Cluster::from(extent.start.cluster_number() + index - extent.index)))
+    }
+
+    /// Returns the cluster holding the byte at `self.offset`. If the cluster
+    /// chain ends right before it, a new cluster is allocated and linked.
+    fn cluster_for_write(&mut self) -> io::Result<Cluster> {
+        if let Some(cluster) = self.curr_cluster {
+            if cluster.is_valid() {
+                return Ok(cluster);
+            }
         }
+
+        let prev_cluster = self.prev_cluster;
+        let cluster = self.vfat.lock(|vfat| vfat.alloc_cluster(prev_cluster))?;
+        // The chain grew past the end of the extent map.
+        self.extents_complete = false;
+        if prev_cluster.is_none() {
+            // The file was empty: this is its first cluster.
+            self.start_cluster = cluster;
+            self.metadata.set_start_cluster(cluster.cluster_number());
+        }
+        self.curr_cluster = Some(cluster);
+        Ok(cluster)
+    }
+
+    /// Writes the file's size, start cluster, modification time and access
+    /// date back to its entry in the parent directory.
+    fn update_entry(&mut self) -> io::Result<()> {
+        let (location, size, start_cluster) = (self.entry, self.size, self.start_cluster);
+        self.metadata = self.vfat.lock(|vfat| -> io::Result<Metadata> {
+            let now = vfat.now();
+            let entry = vfat.dir_entry_mut(location)?;
+            entry.set_size(size);
+            entry.metadata.set_start_cluster(start_cluster.cluster_number());
+            entry.metadata.set_modified(now);
+            entry.metadata.set_accessed(now);
+            Ok(entry.metadata)
+        })?;
+        Ok(())
+    }
+
+    /// Records today as the file's last access date in its entry, if the
+    /// volume's access time policy asks for it.
+    fn update_accessed(&mut self) -> io::Result<()> {
+        let (location, metadata) = (self.entry, self.metadata);
+        let updated = self.vfat.lock(|vfat| -> io::Result<Option<Metadata>> {
+            let now = vfat.now();
+            if !vfat.access_time_policy().should_update(&metadata, now) {
+                return Ok(None);
+            }
+            let entry = vfat.dir_entry_mut(location)?;
+            entry.metadata.set_accessed(now);
+            Ok(Some(entry.metadata))
+        })?;
+        if let Some(metadata) = updated {
+            self.metadata = metadata;
+        }
+        Ok(())
     }
 }
 
@@ -42,12 +171,169 @@ impl<HANDLE: VFatHandle> File<HANDLE> {
             self.short_name.as_str()
         }
     }
+
+    /// Changes the file's size to `len` bytes. Shrinking frees the clusters
+    /// past the new end, including any reserved by `preallocate`. Growing
+    /// fills the new bytes with zeros, one cluster at a time, and allocates
+    /// clusters as needed. An offset past the new end moves to the end.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `PermissionDenied` if the file is read only,
+    /// and of kind `InvalidInput` if `len` exceeds the largest FAT file size.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        use shim::io::Seek;
+
+        let len = self.check_resizable(len)?;
+        let offset = ::core::cmp::min(self.offset, len);
+        if len > self.size {
+            self.zero_extend(len)?;
+        } else {
+            let bytes_per_cluster = self.vfat.lock(|vfat| vfat.bytes_per_cluster()) as u64;
+            let keep = (len as u64).div_ceil(bytes_per_cluster) as u32;
+            self.truncate_chain(keep)?;
+        }
+        self.size = len;
+        self.update_entry()?;
+        self.seek(SeekFrom::Start(offset as u64))?;
+        Ok(())
+    }
+
+    /// Reserves clusters so that the file's chain can hold `len` bytes,
+    /// without changing the file's size. The clusters added form one
+    /// physically contiguous run, placed right after the chain's last cluster
+    /// when those clusters are free. Writes into the reserved range then need
+    /// no allocation and stay contiguous on disk.
+    ///
+    /// `check::check()` reports clusters reserved past the file's size as a
+    /// `SizeMismatch`, and `check::repair()` frees them, unless
+    /// `CheckOptions::preallocated` is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `PermissionDenied` if the file is read only,
+    /// of kind `InvalidInput` if `len` exceeds the largest FAT file size, and
+    /// of kind `Other` if the volume has no contiguous run of free clusters
+    /// long enough.
+    pub fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        use shim::io::Seek;
+
+        let len = self.check_resizable(len)?;
+        let bytes_per_cluster = self.vfat.lock(|vfat| vfat.bytes_per_cluster()) as u64;
+        let needed = (len as u64).div_ceil(bytes_per_cluster) as u32;
+        let have = self.chain_len()?;
+        if needed <= have {
+            return Ok(());
+        }
+
+        let last = match have {
+            0 => None,
+            _ => self.cluster_at(have - 1)?,
+        };
+        let first = self.vfat.lock(|vfat| vfat.alloc_run(last, needed - have))?;
+        self.extents_complete = false;
+        if last.is_none() {
+            self.start_cluster = first;
+            self.metadata.set_start_cluster(first.cluster_number());
+            self.update_entry()?;
+        }
+        // The offset may sit at the old end of the chain, which now continues.
+        let offset = self.offset;
+        self.seek(SeekFrom::Start(offset as u64))?;
+        Ok(())
+    }
+
+    /// Checks that the file may be resized to `len` bytes, returning `len` as
+    /// a FAT file size.
+    fn check_resizable(&self, len: u64) -> io::Result<u32> {
+        if self.metadata.attributes.read_only() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file is read only"));
+        }
+        if len > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "file size too large"));
+        }
+        Ok(len as u32)
+    }
+
+    /// Returns the number of clusters in the file's chain.
+    fn chain_len(&mut self) -> io::Result<u32> {
+        self.cluster_at(u32::MAX)?;
+        Ok(self.extents.last().map_or(0, |extent| extent.index + extent.len))
+    }
+
+    /// Zeroes the bytes from the file's size up to `len`, one cluster at a
+    /// time. Clusters already in the chain, such as those reserved by
+    /// `preallocate`, may hold stale data and are zeroed too; the rest are
+    /// allocated. The size and the entry are left for the caller to update.
+    /// The extent map is reset, so the caller must seek afterwards.
+    fn zero_extend(&mut self, len: u32) -> io::Result<()> {
+        let bytes_per_cluster = self.vfat.lock(|vfat| vfat.bytes_per_cluster()) as u64;
+        let zeros = vec![0u8; bytes_per_cluster as usize];
+        let first = self.size as u64 / bytes_per_cluster;
+        let last = (len as u64 - 1) / bytes_per_cluster;
+        let mut prev = match first {
+            0 => None,
+            _ => self.cluster_at(first as u32 - 1)?,
+        };
+        let mut allocating = false;
+        for index in first..=last {
+            let start = if index == first { (self.size as u64 % bytes_per_cluster) as usize } else { 0 };
+            let end = ::core::cmp::min(len as u64 - index * bytes_per_cluster, bytes_per_cluster) as usize;
+            let existing = match allocating {
+                true => None,
+                false => self.cluster_at(index as u32)?,
+            };
+            let cluster = self.vfat.lock(|vfat| -> io::Result<Cluster> {
+                let cluster = match existing {
+                    Some(cluster) => cluster,
+                    None => vfat.alloc_cluster(prev)?,
+                };
+                vfat.write_cluster(cluster, start, &zeros[start..end])?;
+                Ok(cluster)
+            })?;
+            if existing.is_none() {
+                allocating = true;
+                if prev.is_none() {
+                    self.start_cluster = cluster;
+                    self.metadata.set_start_cluster(cluster.cluster_number());
+                }
+            }
+            prev = Some(cluster);
+        }
+        self.extents.clear();
+        self.extents_complete = false;
+        Ok(())
+    }
+
+    /// Frees the clusters of the file's chain from position `keep` on. The
+    /// extent map is reset, so the caller must seek afterwards.
+    fn truncate_chain(&mut self, keep: u32) -> io::Result<()> {
+        if keep == 0 {
+            let start_cluster = self.start_cluster;
+            if start_cluster.is_valid() {
+                self.vfat.lock(|vfat| vfat.free_chain(start_cluster))?;
+            }
+            self.start_cluster = Cluster::from(0);
+            self.metadata.set_start_cluster(0);
+        } else if let Some(last) = self.cluster_at(keep - 1)? {
+            self.vfat.lock(|vfat| -> io::Result<()> {
+                if let Some(rest) = vfat.next_cluster(last)? {
+                    vfat.set_fat_entry(last, 0x0FFFFFFF)?;
+                    vfat.free_chain(rest)?;
+                }
+                Ok(())
+            })?;
+        }
+        self.extents.clear();
+        self.extents_complete = false;
+        Ok(())
+    }
 }
 
 // FIXME: Implement `traits::File` (and its supertraits) for `File`.
 impl<HANDLE: VFatHandle> traits::File for File<HANDLE> {
     fn sync(&mut self) -> io::Result<()> {
-        unimplemented!()
+        self.vfat.lock(|vfat| vfat.sync())
     }
 
     fn size(&self) -> u64 {
@@ -56,52 +342,116 @@ impl<HANDLE: VFatHandle> traits::File for File<HANDLE> {
 }
 
 impl<HANDLE: VFatHandle> io::Read for File<HANDLE> {
+    /// Reads from the current offset into `buf`. Runs of physically
+    /// contiguous clusters are read from the disk in one call each, bypassing
+    /// the sector cache.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let read_size = ::core::cmp::min(buf.len(), self.size as usize - self.offset as usize);
-        let mut rest_size = read_size;
-        let bytes_per_sector = self.vfat.lock(|vfat| vfat.bytes_per_sector) as u32;
-        let sectors_per_cluster = self.vfat.lock(|vfat| vfat.sectors_per_cluster) as u32;
-        let bytes_per_cluster = bytes_per_sector * sectors_per_cluster;
+        let bytes_per_cluster = self.vfat.lock(|vfat| vfat.bytes_per_cluster());
         let mut current_cluster = self.curr_cluster;
-        let mut current_offset_in_cluster = (self.offset % bytes_per_cluster) as usize;
+        let mut prev_cluster = self.prev_cluster;
+        let mut current_offset_in_cluster = self.offset as usize % bytes_per_cluster;
         let mut buffer_offset = 0;
-        while rest_size > 0 {
-            let newly_read_size = match self.vfat.lock(|vfat| vfat.read_cluster(
-                current_cluster.unwrap(), current_offset_in_cluster, &mut buf[buffer_offset..read_size]
-            )) {
-                Ok(new_size) => new_size,
-                Err(e) => 0,
+        while buffer_offset < read_size {
+            // A chain shorter than the file's size means the volume is corrupt.
+            let first = match current_cluster {
+                Some(cluster) => cluster,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "cluster chain is shorter than the file")),
             };
-            if newly_read_size == bytes_per_cluster as usize - current_offset_in_cluster {
-                match self.vfat.lock(|vfat| vfat.find_next_cluster(current_cluster.unwrap())) {
-                    Ok(next_cluster) => {
-                        current_cluster = Some(next_cluster);
-                    },
-                    Err(e) => {
-                        match e.kind() {
-                            io::ErrorKind::Other => current_cluster = None,
-                            _ => (),
-                        }
-                    },
+
+            // Extend the run while the rest of the read goes past it and the
+            // chain continues with the physically next cluster.
+            let rest_size = read_size - buffer_offset;
+            let run = self.vfat.lock(|vfat| -> io::Result<u32> {
+                let mut run = 1;
+                while run as usize * bytes_per_cluster - current_offset_in_cluster < rest_size {
+                    let last = first.cluster_number() + run - 1;
+                    match vfat.next_cluster(Cluster::from(last))? {
+                        Some(next) if next.cluster_number() == last + 1 => run += 1,
+                        _ => break,
+                    }
                 }
+                Ok(run)
+            })?;
+
+            let len = ::core::cmp::min(run as usize * bytes_per_cluster - current_offset_in_cluster, rest_size);
+            self.vfat.lock(|vfat| vfat.read_contiguous(
+                first, current_offset_in_cluster, &mut buf[buffer_offset..buffer_offset + len]
+            ))?;
+            buffer_offset += len;
+
+            // Move to the cluster holding the byte after the last one read,
+            // which follows the run in the chain if the run was read to its end.
+            let end = current_offset_in_cluster + len;
+            let consumed = (end / bytes_per_cluster) as u32;
+            if consumed > 0 {
+                prev_cluster = Some(Cluster::from(first.cluster_number() + consumed - 1));
             }
-            buffer_offset += newly_read_size;
-            rest_size -= newly_read_size;
-            current_offset_in_cluster = 0;
+            current_cluster = match consumed == run {
+                true => self.vfat.lock(|vfat| vfat.next_cluster(Cluster::from(first.cluster_number() + run - 1)))?,
+                false => Some(Cluster::from(first.cluster_number() + consumed)),
+            };
+            current_offset_in_cluster = end % bytes_per_cluster;
         }
         self.offset += read_size as u32;
         self.curr_cluster = current_cluster;
+        self.prev_cluster = prev_cluster;
+        if read_size > 0 {
+            self.update_accessed()?;
+        }
         Ok(read_size)
     }
 }
 
 impl<HANDLE: VFatHandle> io::Write for File<HANDLE> {
-    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-        unimplemented!()
+    /// Writes `buf` at the current offset, overwriting existing data and
+    /// growing the file (and its cluster chain) as needed. The size,
+    /// modification time and access date in the parent directory entry are
+    /// updated. Data only reaches the disk once the file is synced.
+    ///
+    /// Returns an error of kind `PermissionDenied` if the file was read only
+    /// when it was opened.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.metadata.attributes.read_only() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file is read only"));
+        }
+        let bytes_per_cluster = self.vfat.lock(|vfat| vfat.bytes_per_cluster());
+        let write_size = ::core::cmp::min(buf.len(), (u32::MAX - self.offset) as usize);
+
+        let mut bytes_written = 0;
+        while bytes_written < write_size {
+            // A partial write is reported as such; the error surfaces on the
+            // next call.
+            let cluster = match self.cluster_for_write() {
+                Ok(cluster) => cluster,
+                Err(_) if bytes_written > 0 => break,
+                Err(e) => return Err(e),
+            };
+            let offset_in_cluster = self.offset as usize % bytes_per_cluster;
+            let newly_written_size = self.vfat.lock(|vfat| {
+                vfat.write_cluster(cluster, offset_in_cluster, &buf[bytes_written..write_size])
+            })?;
+
+            bytes_written += newly_written_size;
+            self.offset += newly_written_size as u32;
+            if offset_in_cluster + newly_written_size == bytes_per_cluster {
+                self.prev_cluster = Some(cluster);
+                self.curr_cluster = self.vfat.lock(|vfat| vfat.next_cluster(cluster))?;
+            }
+        }
+
+        if self.offset > self.size {
+            self.size = self.offset;
+        }
+        if bytes_written > 0 {
+            self.update_entry()?;
+        }
+        Ok(bytes_written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        unimplemented!()
+        use traits::File;
+        self.sync()
     }
 }
 
@@ -116,38 +466,36 @@ impl<HANDLE: VFatHandle> io::Seek for File<HANDLE> {
     /// new position from the start of the stream. That position can be used
     /// later with SeekFrom::Start.
     ///
+    /// The clusters of the file are looked up in its extent map, so only the
+    /// part of the chain no earlier seek went past is walked.
+    ///
     /// # Errors
     ///
     /// Seeking before the start of a file or beyond the end of the file results
     /// in an `InvalidInput` error.
-    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
-        let seek_offset = match _pos {
-            SeekFrom::Current(offset) => self.offset + offset as u32,
-            SeekFrom::End(offset) => self.size + offset as u32,
-            SeekFrom::Start(offset) => offset as u32,
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let seek_offset = match pos {
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+            SeekFrom::End(offset) => self.size as i64 + offset,
+            SeekFrom::Start(offset) => ::core::cmp::min(offset, i64::MAX as u64) as i64,
         };
-
-        if seek_offset >= self.size {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, ""));
-        }
-        else {
-            self.offset = seek_offset;
-            let mut vfat =  &self.vfat;
-            let bytes_per_cluster = vfat.lock(|vfat| vfat.bytes_per_sector) as u32 * vfat.lock(|vfat| vfat.sectors_per_cluster) as u32;
-            let cluster = self.offset/bytes_per_cluster;
-            self.curr_cluster = Some(self.start_cluster);
-            for i in 0..cluster {
-                self.curr_cluster = match vfat.lock(|vfat| vfat.find_next_cluster(self.curr_cluster.unwrap())) {
-                    Ok(next_cluster) => Some(next_cluster),
-                    Err(e) => {
-                        match e.kind() {
-                            io::ErrorKind::Other => None,
-                            _ => None,
-                        }
-                    },
-                };
-            }
-            Ok(self.offset as u64)
+        if seek_offset < 0 || seek_offset > self.size as i64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek out of file bounds"));
         }
+
+        // `curr_cluster` holds the byte at the new offset and `prev_cluster`
+        // precedes it. At the end of a file that fills its last cluster, no
+        // cluster holds that byte yet.
+        let offset = seek_offset as u32;
+        let bytes_per_cluster = self.vfat.lock(|vfat| vfat.bytes_per_cluster()) as u32;
+        let index = offset / bytes_per_cluster;
+        let (curr_cluster, prev_cluster) = match index {
+            0 => (Some(self.start_cluster), None),
+            _ => (self.cluster_at(index)?, self.cluster_at(index - 1)?),
+        };
+        self.offset = offset;
+        self.curr_cluster = curr_cluster;
+        self.prev_cluster = prev_cluster;
+        Ok(self.offset as u64)
     }
 }
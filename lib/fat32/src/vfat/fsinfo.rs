@@ -0,0 +1,101 @@
+use core::fmt;
+use shim::const_assert_size;
+
+use crate::traits::BlockDevice;
+use crate::vfat::Error;
+
+/// The FAT32 FSInfo sector, which caches the number of free clusters and a
+/// hint for where to look for the next free one.
+#[repr(C, packed)]
+pub struct FsInfo {
+    lead_signature: u32,
+    reserved_1: [u8; 480],
+    struct_signature: u32,
+    free_count: u32,
+    next_free: u32,
+    reserved_2: [u8; 12],
+    trail_signature: u32,
+}
+
+const_assert_size!(FsInfo, 512);
+
+const LEAD_SIGNATURE: u32 = 0x41615252;
+const STRUCT_SIGNATURE: u32 = 0x61417272;
+const TRAIL_SIGNATURE: u32 = 0xAA550000;
+
+/// Value of `free_count` and `next_free` when the value is not known.
+const UNKNOWN: u32 = 0xFFFFFFFF;
+
+impl FsInfo {
+    /// Returns a valid FSInfo structure recording `free_count` free clusters
+    /// and the next free cluster hint `next_free`.
+    pub(crate) fn new(free_count: Option<u32>, next_free: Option<u32>) -> FsInfo {
+        let mut fs_info = FsInfo {
+            lead_signature: LEAD_SIGNATURE,
+            reserved_1: [0; 480],
+            struct_signature: STRUCT_SIGNATURE,
+            free_count: UNKNOWN,
+            next_free: UNKNOWN,
+            reserved_2: [0; 12],
+            trail_signature: TRAIL_SIGNATURE,
+        };
+        fs_info.set_free_count(free_count);
+        fs_info.set_next_free(next_free);
+        fs_info
+    }
+
+    /// Reads the FSInfo structure from sector `sector` of device `device`.
+    ///
+    /// # Errors
+    ///
+    /// If any of the three FSInfo signatures is invalid, returns an error of
+    /// `BadSignature`.
+    pub fn from<T: BlockDevice>(mut device: T, sector: u64) -> Result<FsInfo, Error> {
+        let mut buf = [0u8; 512];
+        if device.read_sector(sector, &mut buf)? != 512 {
+            return Err(Error::Io(shim::io::Error::new(shim::io::ErrorKind::UnexpectedEof, "FSInfo should be 512 bytes")));
+        }
+
+        let fs_info: FsInfo = unsafe { core::mem::transmute(buf) };
+        if fs_info.lead_signature != LEAD_SIGNATURE
+            || fs_info.struct_signature != STRUCT_SIGNATURE
+            || fs_info.trail_signature != TRAIL_SIGNATURE
+        {
+            return Err(Error::BadSignature);
+        }
+        Ok(fs_info)
+    }
+
+    /// The last known number of free clusters, if known.
+    pub fn free_count(&self) -> Option<u32> {
+        match self.free_count {
+            UNKNOWN => None,
+            count => Some(count),
+        }
+    }
+
+    /// The cluster number to start looking for free clusters at, if known.
+    pub fn next_free(&self) -> Option<u32> {
+        match self.next_free {
+            UNKNOWN => None,
+            cluster => Some(cluster),
+        }
+    }
+
+    pub fn set_free_count(&mut self, count: Option<u32>) {
+        self.free_count = count.unwrap_or(UNKNOWN);
+    }
+
+    pub fn set_next_free(&mut self, cluster: Option<u32>) {
+        self.next_free = cluster.unwrap_or(UNKNOWN);
+    }
+}
+
+impl fmt::Debug for FsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FsInfo")
+            .field("free_count", &self.free_count())
+            .field("next_free", &self.next_free())
+            .finish()
+    }
+}
@@ -0,0 +1,173 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::ops::Range;
+use hashbrown::HashMap;
+
+use crate::vfat::{Cluster, DirEntryInfo};
+
+/// The number of directory entries a `VFat` remembers lookups of unless
+/// configured otherwise.
+pub const DEFAULT_LOOKUP_CAPACITY: usize = 256;
+
+/// The size of a directory entry slot in bytes.
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Counters describing how well the directory entry lookup cache is doing.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LookupStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that had to scan the directory.
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct LookupEntry {
+    info: DirEntryInfo,
+    /// Value of the cache's access clock when the entry was last used.
+    last_used: u64,
+}
+
+/// A bounded cache of directory entries found by name, keyed by the first
+/// cluster of the parent directory and the case-folded name.
+///
+/// Entries are dropped whenever a slot they occupy is written to and when a
+/// cluster of their parent directory is freed, so the cache never disagrees
+/// with the directories on disk.
+#[derive(Debug)]
+pub(crate) struct LookupCache {
+    entries: HashMap<(Cluster, String), LookupEntry>,
+    /// The keys of `entries` by their `last_used` value, least recently used
+    /// first.
+    order: BTreeMap<u64, (Cluster, String)>,
+    capacity: usize,
+    clock: u64,
+    stats: LookupStats,
+}
+
+impl LookupCache {
+    /// Creates an empty cache that holds at most `capacity` entries. A
+    /// capacity of 0 disables caching.
+    pub(crate) fn new(capacity: usize) -> LookupCache {
+        LookupCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            capacity,
+            clock: 0,
+            stats: LookupStats::default(),
+        }
+    }
+
+    /// The key of the entry named `name` in the directory starting at
+    /// `parent`. Names are compared case-insensitively, like `Dir::find()`.
+    fn key(parent: Cluster, name: &str) -> (Cluster, String) {
+        (parent, name.to_ascii_lowercase())
+    }
+
+    /// Returns the cached entry named `name` in the directory starting at
+    /// `parent`, counting a hit or a miss.
+    pub(crate) fn get(&mut self, parent: Cluster, name: &str) -> Option<DirEntryInfo> {
+        self.clock += 1;
+        let key = Self::key(parent, name);
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                self.order.remove(&entry.last_used);
+                self.order.insert(self.clock, key);
+                entry.last_used = self.clock;
+                Some(entry.info.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Remembers that `info` is the entry named `name` in the directory
+    /// starting at `parent`, evicting the least recently used entry if the
+    /// cache is full.
+    pub(crate) fn insert(&mut self, parent: Cluster, name: &str, info: DirEntryInfo) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = Self::key(parent, name);
+        match self.entries.get(&key) {
+            Some(entry) => {
+                self.order.remove(&entry.last_used);
+            }
+            None => {
+                while self.entries.len() >= self.capacity {
+                    self.evict();
+                }
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, LookupEntry { info, last_used: self.clock });
+    }
+
+    /// Drops every entry occupying a slot in the `bytes` byte range of
+    /// `cluster`.
+    pub(crate) fn invalidate_bytes(&mut self, cluster: Cluster, bytes: Range<usize>) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let order = &mut self.order;
+        self.entries.retain(|_, entry| {
+            let occupies = entry.info.slots().iter().any(|slot| {
+                slot.cluster == cluster && slot.offset < bytes.end && bytes.start < slot.offset + DIR_ENTRY_SIZE
+            });
+            if occupies {
+                order.remove(&entry.last_used);
+            }
+            !occupies
+        });
+    }
+
+    /// Drops every entry occupying a slot in `cluster` and every entry of the
+    /// directory starting at `cluster`. Used when `cluster` is freed.
+    pub(crate) fn invalidate_cluster(&mut self, cluster: Cluster) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let order = &mut self.order;
+        self.entries.retain(|(parent, _), entry| {
+            let keep = *parent != cluster && entry.info.slots().iter().all(|slot| slot.cluster != cluster);
+            if !keep {
+                order.remove(&entry.last_used);
+            }
+            keep
+        });
+    }
+
+    /// Drops every entry.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Changes the maximum number of entries to `capacity`, evicting entries
+    /// if there are more cached than that.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Returns the hit and miss counters of the cache.
+    pub(crate) fn stats(&self) -> LookupStats {
+        self.stats
+    }
+
+    /// Removes the least recently used entry from the cache.
+    fn evict(&mut self) {
+        let victim = self.order.keys().next().cloned();
+        if let Some(last_used) = victim {
+            let key = self.order.remove(&last_used).unwrap();
+            self.entries.remove(&key);
+        }
+    }
+}
@@ -1,8 +1,7 @@
 use core::fmt;
+use core::time::Duration;
 
-use alloc::string::String;
-
-use crate::traits;
+use crate::traits::{self, MetadataChanges};
 
 /// A date as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
@@ -20,19 +19,27 @@ pub struct Time(u16);
 pub struct Attributes(u8);
 
 /// A structure containing a date and time.
+///
+/// FAT records local time without a time zone. The conversions to and from
+/// the Unix epoch treat timestamps as UTC.
 #[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Timestamp {
     pub date: Date,
     pub time: Time,
+    /// Hundredths of a second past `time`, in range [0, 200) as `time` has a
+    /// two second resolution. Only creation times record them on disk.
+    pub hundredths: u8,
 }
 
 /// Metadata for a directory entry.
-#[repr(packed(1))]
+#[repr(C, packed)]
 #[derive(Default, Debug, Clone, Copy)]
 pub struct Metadata {
     // FIXME: Fill me in.
     pub attributes: Attributes,
-    __reserved: u8,
+    /// Windows NT case flags: whether the base name and extension of the
+    /// short name are displayed in lower case.
+    case_flags: u8,
     tenths_creation_time: u8,
     creation_time: Time,
     creation_date: Date,
@@ -43,16 +50,256 @@ pub struct Metadata {
     low_cluster_number: u16,
 }
 
+impl Date {
+    /// Returns the on-disk date for `year`-`month`-`day`. `year` must be in
+    /// range [1980, 2107].
+    pub fn new(year: usize, month: u8, day: u8) -> Date {
+        Date((((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16)
+    }
+}
+
+impl Time {
+    /// Returns the on-disk time for `hour`:`minute`:`second`. FAT stores
+    /// seconds with a two second resolution, so odd seconds are rounded down.
+    pub fn new(hour: u8, minute: u8, second: u8) -> Time {
+        Time(((hour as u16) << 11) | ((minute as u16) << 5) | (second as u16 / 2))
+    }
+}
+
+impl Timestamp {
+    /// The earliest point in time representable on disk: 01/01/1980 00:00:00.
+    pub const EPOCH: Timestamp = Timestamp {
+        date: Date(0b0000_0000_0010_0001),
+        time: Time(0),
+        hundredths: 0,
+    };
+
+    /// The latest point in time representable on disk: 12/31/2107 23:59:59.99.
+    pub const MAX: Timestamp = Timestamp {
+        date: Date(0b1111_1111_1001_1111),
+        time: Time(0b1011_1111_0111_1101),
+        hundredths: 199,
+    };
+
+    /// Returns the timestamp `since_unix_epoch` after 01/01/1970 00:00:00, or
+    /// `None` if that is before `EPOCH` or after `MAX`. Time below the
+    /// resolution of `hundredths` is dropped.
+    pub fn from_duration(since_unix_epoch: Duration) -> Option<Timestamp> {
+        if since_unix_epoch < Timestamp::EPOCH.to_duration() || since_unix_epoch > Timestamp::MAX.to_duration() {
+            return None;
+        }
+
+        let secs = since_unix_epoch.as_secs();
+
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let secs_of_day = secs % SECS_PER_DAY;
+        let second = (secs_of_day % 60) as u8;
+        Some(Timestamp {
+            date: Date::new(year as usize, month, day),
+            time: Time::new((secs_of_day / 3600) as u8, (secs_of_day / 60 % 60) as u8, second),
+            hundredths: (second % 2) * 100 + (since_unix_epoch.subsec_nanos() / 10_000_000) as u8,
+        })
+    }
+
+    /// Returns the timestamp `secs` seconds after 01/01/1970 00:00:00, or
+    /// `None` if that is before `EPOCH` or after `MAX`.
+    pub fn from_unix_time(secs: u64) -> Option<Timestamp> {
+        Timestamp::from_duration(Duration::from_secs(secs))
+    }
+
+    /// The time elapsed between 01/01/1970 00:00:00 and `self`.
+    pub fn to_duration(&self) -> Duration {
+        Duration::from_secs(self.unix_time()) + Duration::from_millis(self.hundredths.min(199) as u64 % 100 * 10)
+    }
+
+    /// The number of whole seconds between 01/01/1970 00:00:00 and `self`,
+    /// counting the odd second kept in `hundredths`. Corrupt fields, such as a
+    /// month of 0, are clamped to the nearest valid value.
+    pub fn unix_time(&self) -> u64 {
+        use traits::Timestamp;
+
+        let month = self.month().clamp(1, 12);
+        let days = days_from_civil(self.year() as u64, month, self.day().max(1));
+        days * SECS_PER_DAY + self.hour() as u64 * 3600 + self.minute() as u64 * 60 + self.second() as u64
+            + self.hundredths.min(199) as u64 / 100
+    }
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The number of days between 01/01/1970 and `month`/`day`/`year`, which
+/// must not be before 1970.
+fn days_from_civil(year: u64, month: u8, day: u8) -> u64 {
+    // Years start in March here, so leap days end them.
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * ((month as u64 + 9) % 12) + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, month and day `days` days after 01/01/1970.
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let days = days + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * march_month + 2) / 5 + 1) as u8;
+    let month = if march_month < 10 { march_month + 3 } else { march_month - 9 } as u8;
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
 impl Metadata {
+    /// NT case flag set if the base name of the short name is in lower case.
+    pub(crate) const LOWERCASE_BASE: u8 = 0x08;
+    /// NT case flag set if the extension of the short name is in lower case.
+    pub(crate) const LOWERCASE_EXTENSION: u8 = 0x10;
+
+    /// Returns the metadata of a new entry with `attributes` whose data starts
+    /// at `start_cluster`. The entry is created, accessed and modified at
+    /// `timestamp`.
+    pub(crate) fn new(attributes: Attributes, start_cluster: u32, timestamp: Timestamp) -> Metadata {
+        let mut metadata = Metadata {
+            attributes,
+            tenths_creation_time: timestamp.hundredths,
+            creation_time: timestamp.time,
+            creation_date: timestamp.date,
+            last_access_date: timestamp.date,
+            ..Metadata::default()
+        };
+        metadata.set_start_cluster(start_cluster);
+        metadata.set_modified(timestamp);
+        metadata
+    }
+
     pub fn start_cluster(&self) -> u32 {
         ((self.high_cluster_number as u32) << 16) + self.low_cluster_number as u32
     }
+
+    pub fn set_start_cluster(&mut self, cluster: u32) {
+        self.high_cluster_number = (cluster >> 16) as u16;
+        self.low_cluster_number = cluster as u16;
+    }
+
+    /// The NT case flags of the entry.
+    pub(crate) fn case_flags(&self) -> u8 {
+        self.case_flags
+    }
+
+    /// Whether the base name of the short name is displayed in lower case.
+    pub fn lowercase_base(&self) -> bool {
+        self.case_flags & Self::LOWERCASE_BASE != 0
+    }
+
+    /// Whether the extension of the short name is displayed in lower case.
+    pub fn lowercase_extension(&self) -> bool {
+        self.case_flags & Self::LOWERCASE_EXTENSION != 0
+    }
+
+    pub fn set_modified(&mut self, timestamp: Timestamp) {
+        self.last_modification_date = timestamp.date;
+        self.last_modification_time = timestamp.time;
+    }
+
+    /// Records `timestamp` as the creation time.
+    pub fn set_created(&mut self, timestamp: Timestamp) {
+        self.creation_date = timestamp.date;
+        self.creation_time = timestamp.time;
+        self.tenths_creation_time = timestamp.hundredths;
+    }
+
+    /// Applies the attribute and timestamp changes in `changes`.
+    pub(crate) fn apply(&mut self, changes: &MetadataChanges<Timestamp>) {
+        let flags = [
+            (Attributes::READ_ONLY, changes.read_only),
+            (Attributes::HIDDEN, changes.hidden),
+            (Attributes::SYSTEM, changes.system),
+            (Attributes::ARCHIVE, changes.archive),
+        ];
+        for &(flag, value) in &flags {
+            if let Some(value) = value {
+                self.attributes.set(flag, value);
+            }
+        }
+        if let Some(created) = changes.created {
+            self.set_created(created);
+        }
+        if let Some(accessed) = changes.accessed {
+            self.set_accessed(accessed);
+        }
+        if let Some(modified) = changes.modified {
+            self.set_modified(modified);
+        }
+    }
+
+    /// Records the date of `timestamp` as the last access date. FAT doesn't
+    /// record the time of day of accesses.
+    pub fn set_accessed(&mut self, timestamp: Timestamp) {
+        self.last_access_date = timestamp.date;
+    }
 }
 
 impl Attributes {
+    /// The entry may not be written to.
+    pub const READ_ONLY: Attributes = Attributes(0x01);
+    /// The entry is hidden from normal directory listings.
+    pub const HIDDEN: Attributes = Attributes(0x02);
+    /// The entry belongs to the operating system.
+    pub const SYSTEM: Attributes = Attributes(0x04);
+    /// The entry is the volume label rather than a file.
+    pub const VOLUME_ID: Attributes = Attributes(0x08);
+    /// The entry is a directory.
+    pub const DIRECTORY: Attributes = Attributes(0x10);
+    /// The entry changed since it was last backed up.
+    pub const ARCHIVE: Attributes = Attributes(0x20);
+    /// The entry is part of a long file name. This combination of flags is
+    /// never set on regular entries.
+    pub const LFN: Attributes = Attributes(0x0F);
+
+    /// The raw attribute byte.
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Whether every flag set in `flags` is set in `self`.
+    pub fn contains(&self, flags: Attributes) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Sets the flags set in `flags` if `value` is `true` and clears them
+    /// otherwise.
+    pub fn set(&mut self, flags: Attributes, value: bool) {
+        match value {
+            true => self.0 |= flags.0,
+            false => self.0 &= !flags.0,
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.contains(Attributes::READ_ONLY)
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.contains(Attributes::HIDDEN)
+    }
+
+    pub fn system(&self) -> bool {
+        self.contains(Attributes::SYSTEM)
+    }
+
+    pub fn volume_id(&self) -> bool {
+        self.contains(Attributes::VOLUME_ID)
+    }
+
     pub fn directory(&self) -> bool {
         (self.0 & 0x10) != 0
     }
+
+    pub fn archive(&self) -> bool {
+        self.contains(Attributes::ARCHIVE)
+    }
 }
 
 // FIXME: Implement `traits::Timestamp` for `Timestamp`.
@@ -87,24 +334,27 @@ impl traits::Metadata for Metadata {
     type Timestamp = Timestamp;
 
     fn read_only(&self) -> bool {
-        self.attributes.0 & 0x01 != 0
+        self.attributes.read_only()
     }
 
     fn hidden(&self) -> bool {
-        self.attributes.0 & 0x02 != 0
+        self.attributes.hidden()
     }
 
     fn created(&self) -> Self::Timestamp {
         Timestamp {
             date: self.creation_date,
             time: self.creation_time,
+            hundredths: self.tenths_creation_time,
         }
     }
 
+    /// The last access date, at midnight: FAT only records the date.
     fn accessed(&self) -> Self::Timestamp {
         Timestamp {
             date: self.last_access_date,
             time: Time(0),
+            hundredths: 0,
         }
     }
 
@@ -112,6 +362,7 @@ impl traits::Metadata for Metadata {
         Timestamp {
             date: self.last_modification_date,
             time: self.last_modification_time,
+            hundredths: 0,
         }
     }
 }
@@ -1,22 +1,35 @@
 pub(crate) mod cache;
+pub(crate) mod clock;
 pub(crate) mod cluster;
+pub(crate) mod codepage;
 pub(crate) mod dir;
 pub(crate) mod ebpb;
 pub(crate) mod entry;
 pub(crate) mod error;
 pub(crate) mod fat;
 pub(crate) mod file;
+pub(crate) mod fsinfo;
+pub(crate) mod lookup;
 pub(crate) mod metadata;
 pub(crate) mod vfat;
 
+pub use self::cache::{CacheStats, DEFAULT_CACHE_CAPACITY};
+pub use self::clock::{AccessTimePolicy, Clock, FixedClock};
+pub use self::codepage::CodePage;
 pub use self::dir::Dir;
 pub use self::ebpb::BiosParameterBlock;
 pub use self::entry::Entry;
 pub use self::error::Error;
+pub use self::fat::{FatMismatch, FatType};
 pub use self::file::File;
+pub use self::fsinfo::FsInfo;
+pub use self::lookup::{LookupStats, DEFAULT_LOOKUP_CAPACITY};
 pub use self::metadata::{Attributes, Date, Metadata, Time, Timestamp};
-pub use self::vfat::{VFat, VFatHandle};
+pub use self::vfat::{FsStats, MountOptions, VFat, VFatHandle};
 
 pub(crate) use self::cache::{CachedPartition, Partition};
 pub(crate) use self::cluster::Cluster;
+pub(crate) use self::dir::{display_short_name, DirEntryInfo, EntryIterator, EntryLocation, LfnRun};
+pub(crate) use self::dir::{VFatDirEntry, VFatRegularDirEntry};
 pub(crate) use self::fat::{FatEntry, Status};
+pub(crate) use self::lookup::LookupCache;
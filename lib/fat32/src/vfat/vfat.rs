@@ -1,22 +1,23 @@
 use core::fmt::Debug;
 use core::marker::PhantomData;
 use core::mem::size_of;
-use core::borrow::BorrowMut;
 
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use shim::ffi::OsStr;
 use shim::io;
-use shim::ioerr;
-use shim::newioerr;
-use shim::path;
 use shim::path::Path;
 
-use crate::mbr::MasterBootRecord;
-use crate::traits::{BlockDevice, FileSystem};
+use crate::format::MAX_CLUSTERS;
+use crate::partition;
+use crate::traits::{BlockDevice, FileSystem, MetadataChanges};
 use crate::util::SliceExt;
-use crate::vfat::{BiosParameterBlock, CachedPartition, Partition, Metadata};
-use crate::vfat::{Cluster, Dir, Entry, Error, FatEntry, File, Status};
+use crate::vfat::{BiosParameterBlock, CacheStats, CachedPartition, FsInfo, Partition, Metadata, Timestamp};
+use crate::vfat::{AccessTimePolicy, Clock, FixedClock};
+use crate::vfat::{CodePage, LookupCache, LookupStats, DEFAULT_LOOKUP_CAPACITY};
+use crate::vfat::{Cluster, Dir, Entry, EntryLocation, Error, FatEntry, FatMismatch, FatType, File, Status, VFatRegularDirEntry};
 
 /// A generic trait that handles a critical section as a closure
 pub trait VFatHandle: Clone + Debug + Send + Sync {
@@ -24,83 +25,244 @@ pub trait VFatHandle: Clone + Debug + Send + Sync {
     fn lock<R>(&self, f: impl FnOnce(&mut VFat<Self>) -> R) -> R;
 }
 
+/// Usage statistics of a mounted volume, as returned by `VFat::statfs()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FsStats {
+    /// The size of a cluster in bytes.
+    pub cluster_size: usize,
+    /// The number of data clusters on the volume.
+    pub total_clusters: u32,
+    /// The number of free data clusters on the volume.
+    pub free_clusters: u32,
+    /// The cluster number the next allocation starts looking at.
+    pub next_free: u32,
+}
+
+/// Options for `VFat::from_options()`.
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    /// The partition to mount, numbered as in the list returned by
+    /// `partition::partitions()`. If `None`, the first FAT partition is
+    /// mounted.
+    pub partition: Option<usize>,
+    /// The OEM code page short names are decoded with.
+    pub code_page: CodePage,
+    /// When reading a file updates its last access date.
+    pub access_time: AccessTimePolicy,
+}
+
 #[derive(Debug)]
 pub struct VFat<HANDLE: VFatHandle> {
     phantom: PhantomData<HANDLE>,
     device: CachedPartition,
     pub bytes_per_sector: u16,
     pub sectors_per_cluster: u8,
+    fat_type: FatType,
     sectors_per_fat: u32,
     fat_start_sector: u64,
+    number_of_fats: u8,
+    /// The only FAT copy in use if mirroring is disabled.
+    active_fat: Option<u8>,
+    /// First sector and length in sectors of the fixed root directory region
+    /// of FAT12 and FAT16 volumes. Its length is 0 on FAT32 volumes.
+    root_dir_start_sector: u64,
+    root_dir_sectors: u64,
     data_start_sector: u64,
+    cluster_count: u32,
+    /// The first cluster of the root directory. FAT12 and FAT16 volumes have a
+    /// fixed root directory region instead, denoted by cluster 0.
     pub root_dir_cluster: Cluster,
+    /// Logical sector of the FSInfo structure, if the volume has a valid one.
+    fs_info_sector: Option<u64>,
+    /// Number of free clusters, if known.
+    free_clusters: Option<u32>,
+    /// Cluster number to start searching for free clusters at.
+    next_free: u32,
+    /// Whether `free_clusters` or `next_free` changed since the last sync.
+    fs_info_dirty: bool,
+    /// Directory entries found by `Dir::find()`, by parent and name.
+    pub(crate) lookups: LookupCache,
+    /// The code page short names are decoded with.
+    code_page: CodePage,
+    /// The source of the times stamped on entries.
+    clock: Box<dyn Clock>,
+    /// When reading a file updates its last access date.
+    access_time: AccessTimePolicy,
 }
 
 impl<HANDLE: VFatHandle> VFat<HANDLE> {
-    pub fn from<T>(mut device: T) -> Result<HANDLE, Error>
+    pub fn from<T>(device: T) -> Result<HANDLE, Error>
     where
         T: BlockDevice + 'static,
     {
-        let mbr: MasterBootRecord = MasterBootRecord::from(&mut device)?;
-        for i in 0..4 {
-            let _partition = mbr.get_partition(i);
-            match _partition.partition_type {
-                0xB | 0xC => {
-                    let partition_start = _partition.relative_sector as u64;
-                    let ebpb = BiosParameterBlock::from(&mut device, partition_start).unwrap(); // break point
-                    let logical_sectors_number = if ebpb.total_logical_sectors != 0 {
-                        ebpb.total_logical_sectors as u64
-                    }
-                    else {
-                        ebpb.total_logical_sectors_2 as u64
-                    };
-                    let partition = Partition {
-                        start: partition_start,
-                        num_sectors: logical_sectors_number as u64, // TODO
-                        sector_size: ebpb.bytes_per_sector as u64, 
-                    };
+        VFat::from_options(device, &MountOptions::default())
+    }
 
-                    let cached_device = CachedPartition::new(device, partition);
-                    let fat_start_sector = ebpb.reserved_sectors as u64;
-                    let data_start_sector = ebpb.reserved_sectors as u64 + ebpb.sectors_per_fat_32 as u64 * ebpb.number_of_fat as u64; //TODO
-                    let vfat =  VFat {
-                        phantom: PhantomData,
-                        device: cached_device,
-                        bytes_per_sector: ebpb.bytes_per_sector,
-                        sectors_per_cluster: ebpb.sectors_per_cluster,
-                        sectors_per_fat: ebpb.sectors_per_fat_32,
-                        fat_start_sector: fat_start_sector,
-                        data_start_sector: data_start_sector,
-                        root_dir_cluster: Cluster::from(ebpb.root_dir_cluster_number),
-                    };
-                    return Ok(VFatHandle::new(vfat));
-                },
-                _ => {}
-            }
+    /// Mounts the FAT volume on partition `index` of `device`, numbered as in
+    /// the list returned by `partition::partitions()`. The partition's type
+    /// isn't checked, only its boot sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotFound` if `device` has no partition `index`, and
+    /// `BadSignature` or `InvalidBpb` if the partition doesn't hold a valid FAT
+    /// volume. Returns the same errors as `partition::partitions()` if the
+    /// partition table is invalid.
+    pub fn from_partition<T>(device: T, index: usize) -> Result<HANDLE, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        let options = MountOptions {
+            partition: Some(index),
+            ..MountOptions::default()
+        };
+        VFat::from_options(device, &options)
+    }
+
+    /// Mounts a FAT volume of `device` according to `options`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `from_partition()` if `options` names a
+    /// partition. Otherwise returns `Io(err)` with an error of kind
+    /// `InvalidData` if `device` has no FAT partition.
+    pub fn from_options<T>(mut device: T, options: &MountOptions) -> Result<HANDLE, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        let partitions = partition::partitions(&mut device)?;
+        let partition = match options.partition {
+            Some(index) => partitions.get(index).ok_or(Error::NotFound)?,
+            None => match partitions.iter().find(|partition| partition.partition_type.is_fat()) {
+                Some(partition) => partition,
+                None => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "fat32 not found!"))),
+            },
+        };
+        VFat::mount(device, partition.start, options)
+    }
+
+    /// Mounts the FAT volume starting at sector `partition_start` of `device`.
+    fn mount<T>(mut device: T, partition_start: u64, options: &MountOptions) -> Result<HANDLE, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        let ebpb = BiosParameterBlock::from(&mut device, partition_start)?;
+        // The volume's sectors must be made of whole sectors of the device.
+        let bytes_per_sector = ebpb.bytes_per_sector as u64;
+        if !ebpb.is_plausible() || !bytes_per_sector.is_multiple_of(device.sector_size()) {
+            return Err(Error::InvalidBpb);
         }
-        Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "fat32 not found!")))
+        let logical_sectors_number = ebpb.total_sectors();
+        let partition = Partition {
+            start: partition_start,
+            num_sectors: logical_sectors_number,
+            sector_size: bytes_per_sector,
+        };
+
+        // FAT12 and FAT16 volumes have a 16-bit FAT size and a
+        // fixed root directory region after the FATs.
+        let sectors_per_fat = if ebpb.sectors_per_fat != 0 {
+            ebpb.sectors_per_fat as u32
+        } else {
+            ebpb.sectors_per_fat_32
+        };
+        let root_dir_sectors = (ebpb.max_directory_entries as u64 * 32).div_ceil(bytes_per_sector);
+        let fat_start_sector = ebpb.reserved_sectors as u64;
+        let root_dir_start_sector = fat_start_sector + sectors_per_fat as u64 * ebpb.number_of_fat as u64;
+        let data_start_sector = root_dir_start_sector + root_dir_sectors;
+        if data_start_sector >= logical_sectors_number {
+            return Err(Error::InvalidBpb);
+        }
+        let cluster_count = ::core::cmp::min(
+            (logical_sectors_number - data_start_sector) / ebpb.sectors_per_cluster as u64,
+            MAX_CLUSTERS as u64,
+        ) as u32;
+        let fat_type = FatType::from_cluster_count(cluster_count);
+
+        // Every cluster needs an entry in the FAT, and a FAT32 root directory
+        // must start at a data cluster.
+        let fat_entries = sectors_per_fat as u64 * bytes_per_sector * 8 / fat_type.entry_bits() as u64;
+        if fat_entries < cluster_count as u64 + 2 {
+            return Err(Error::InvalidBpb);
+        }
+        let root_cluster = ebpb.root_dir_cluster_number;
+        if fat_type == FatType::Fat32 && (root_cluster < 2 || root_cluster >= cluster_count + 2) {
+            return Err(Error::InvalidBpb);
+        }
+
+        // The FSInfo sector is only a hint: volumes without a
+        // valid one are still mounted.
+        let factor = bytes_per_sector / device.sector_size();
+        let fs_info_sector = match ebpb.fs_info_sector_number {
+            _ if fat_type != FatType::Fat32 => None,
+            0 | 0xFFFF => None,
+            sector => Some(sector as u64),
+        };
+        let fs_info = fs_info_sector
+            .and_then(|sector| FsInfo::from(&mut device, partition_start + sector * factor).ok());
+
+        let cached_device = CachedPartition::new(device, partition);
+        // Bit 7 of the flags disables mirroring; bits 0-3 then
+        // select the active FAT.
+        let flags = ebpb.flags;
+        let active_fat = match (flags & 0xF) as u8 {
+            _ if fat_type != FatType::Fat32 => None,
+            active if flags & 0x80 != 0 && active < ebpb.number_of_fat => Some(active),
+            _ => None,
+        };
+        let root_dir_cluster = match fat_type {
+            FatType::Fat32 => Cluster::from(ebpb.root_dir_cluster_number),
+            _ => Cluster::from(0),
+        };
+        let vfat = VFat {
+            phantom: PhantomData,
+            device: cached_device,
+            bytes_per_sector: ebpb.bytes_per_sector,
+            sectors_per_cluster: ebpb.sectors_per_cluster,
+            fat_type,
+            sectors_per_fat,
+            fat_start_sector,
+            number_of_fats: ebpb.number_of_fat,
+            active_fat,
+            root_dir_start_sector,
+            root_dir_sectors,
+            data_start_sector,
+            cluster_count,
+            root_dir_cluster,
+            fs_info_sector: fs_info.as_ref().and(fs_info_sector),
+            free_clusters: fs_info
+                .as_ref()
+                .and_then(|fs_info| fs_info.free_count())
+                .filter(|&count| count <= cluster_count),
+            next_free: fs_info
+                .as_ref()
+                .and_then(|fs_info| fs_info.next_free())
+                .filter(|&cluster| cluster >= 2 && cluster < cluster_count + 2)
+                .unwrap_or(2),
+            fs_info_dirty: false,
+            lookups: LookupCache::new(DEFAULT_LOOKUP_CAPACITY),
+            code_page: options.code_page,
+            clock: Box::new(FixedClock(Timestamp::EPOCH)),
+            access_time: options.access_time,
+        };
+        Ok(VFatHandle::new(vfat))
     }
 
-    // TODO: The following methods may be useful here:
-    
-    //  * A method to read from an offset of a cluster into a buffer.
-    
+    /// Reads `cluster` starting at byte `offset` of the cluster into `buf`.
+    /// At most the remainder of the cluster is read; the number of bytes read
+    /// is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `offset` is past the end of
+    /// the cluster.
     pub fn read_cluster(&mut self, cluster: Cluster, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
         use core::cmp::min;
 
-        if !cluster.is_valid() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid Cluster"));
-        }
-
         let sector_size = self.device.sector_size() as usize;
-        let size = min(
-            buf.len(),
-            self.bytes_per_sector as usize * self.sectors_per_cluster as usize - offset,
-        );
+        let size = min(buf.len(), self.cluster_remainder(cluster, offset)?);
 
-        let mut current_sector = self.data_start_sector
-            + cluster.cluster_index() as u64 * self.sectors_per_cluster as u64
-            + offset as u64 / self.bytes_per_sector as u64;
+        let mut current_sector = self.cluster_sector(cluster)? + offset as u64 / self.bytes_per_sector as u64;
 
         let mut bytes_read = 0;
         let mut offset_in_sector = offset % self.bytes_per_sector as usize;
@@ -116,17 +278,113 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
 
         Ok(size)
     }
-    
-    //  * A method to read all of the clusters chained from a starting cluster
-    //    into a vector.
-    
+
+    /// Reads `buf.len()` bytes starting `offset` bytes into the run of
+    /// physically contiguous data clusters that starts at `start`. The whole
+    /// sectors of the range are read from the disk in one call, bypassing the
+    /// sector cache; partial sectors at either end are read through it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if the range extends past the
+    /// last data cluster.
+    pub fn read_contiguous(&mut self, start: Cluster, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        use core::cmp::min;
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        let clusters = (offset + buf.len()).div_ceil(self.bytes_per_cluster());
+        let last = Cluster::from(start.cluster_number().saturating_add(clusters as u32 - 1));
+        if self.is_fixed_root(start) || self.cluster_sector(last).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid Cluster"));
+        }
+
+        let mut sector = self.cluster_sector(start)? + (offset / bytes_per_sector) as u64;
+        let offset_in_sector = offset % bytes_per_sector;
+        let mut bytes_read = 0;
+        if offset_in_sector != 0 {
+            let content = self.device.get(sector)?;
+            bytes_read = min(buf.len(), bytes_per_sector - offset_in_sector);
+            buf[..bytes_read].copy_from_slice(&content[offset_in_sector..offset_in_sector + bytes_read]);
+            sector += 1;
+        }
+
+        let whole = (buf.len() - bytes_read) / bytes_per_sector * bytes_per_sector;
+        if whole > 0 {
+            self.device.read_sectors(sector, &mut buf[bytes_read..bytes_read + whole])?;
+            bytes_read += whole;
+            sector += (whole / bytes_per_sector) as u64;
+        }
+
+        if bytes_read < buf.len() {
+            let content = self.device.get(sector)?;
+            let rest = buf.len() - bytes_read;
+            buf[bytes_read..].copy_from_slice(&content[..rest]);
+        }
+        Ok(buf.len())
+    }
+
+    /// Whether `cluster` denotes the fixed root directory region of a FAT12 or
+    /// FAT16 volume.
+    pub(crate) fn is_fixed_root(&self, cluster: Cluster) -> bool {
+        self.root_dir_sectors != 0 && cluster.cluster_number() == 0
+    }
+
+    /// Returns the first sector of `cluster`, which may be the fixed root
+    /// directory region.
+    fn cluster_sector(&self, cluster: Cluster) -> io::Result<u64> {
+        if self.is_fixed_root(cluster) {
+            Ok(self.root_dir_start_sector)
+        } else if cluster.is_valid() && cluster.cluster_number() < self.cluster_count + 2 {
+            Ok(self.data_start_sector + cluster.cluster_index() as u64 * self.sectors_per_cluster as u64)
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid Cluster"))
+        }
+    }
+
+    /// The number of bytes of the directory cluster `cluster` from `offset`
+    /// on, or an error of kind `InvalidInput` if `offset` is past its end.
+    fn cluster_remainder(&self, cluster: Cluster, offset: usize) -> io::Result<usize> {
+        self.dir_cluster_size(cluster)
+            .checked_sub(offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset past the end of the cluster"))
+    }
+
+    /// The size in bytes of the directory cluster `cluster`. This is the size
+    /// of the whole region for the fixed root directory of FAT12 and FAT16
+    /// volumes, and the cluster size otherwise.
+    pub fn dir_cluster_size(&self, cluster: Cluster) -> usize {
+        if self.is_fixed_root(cluster) {
+            self.root_dir_sectors as usize * self.bytes_per_sector as usize
+        } else {
+            self.bytes_per_cluster()
+        }
+    }
+
+    /// Reads every cluster of the chain starting at `start` into `buf`, which
+    /// is resized to hold them. Returns the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the chain loops or reaches an
+    /// invalid FAT entry.
     pub fn read_chain(&mut self, start: Cluster, buf: &mut Vec<u8>) -> io::Result<usize> {
+        if self.is_fixed_root(start) {
+            buf.resize(self.dir_cluster_size(start), 0);
+            return self.read_cluster(start, 0, buf);
+        }
+
         let mut bytes_read = 0;
 
         let mut current_cluster = start;
         let mut cluster_number = 0;
         loop {
             cluster_number = cluster_number + 1;
+            if cluster_number > self.cluster_count as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "cluster chain loops"));
+            }
             let current_entry = self.fat_entry(current_cluster)?;
             match current_entry.status() {
                 Status::Data(next_cluster) => {
@@ -145,30 +403,195 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
             }
         }
     }
-    
-    //  * A method to return a reference to a `FatEntry` for a cluster where the
-    //    reference points directly into a cached sector.
-    
-    pub fn fat_entry(&mut self, cluster: Cluster) -> io::Result<&FatEntry> {
-        // if !cluster.is_valid() {
-        //     return Err(io::Error::new(io::ErrorKind::InvalidData, ""));
-        // }
 
-        // let fat_width = size_of::<FatEntry>();
+    /// Returns the FAT entry for `cluster`. Entries of FAT12 and FAT16 volumes
+    /// are converted to the equivalent FAT32 entry.
+    pub fn fat_entry(&mut self, cluster: Cluster) -> io::Result<FatEntry> {
+        let raw = self.raw_fat_entry(self.active_fat.unwrap_or(0), cluster.cluster_number())?;
+        Ok(FatEntry(self.fat_type.widen(raw)))
+    }
+
+    /// The FAT variant of the volume.
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Returns the byte offset of the entry for `cluster` from the start of a
+    /// FAT. FAT12 entries are a byte and a half wide.
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// Reads `buf.len()` bytes at byte `offset` of FAT copy `copy`. The bytes
+    /// may span two sectors, as FAT12 entries can.
+    fn read_fat_bytes(&mut self, copy: u8, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let bytes_per_sector = self.bytes_per_sector as u64;
+        let sector = self.fat_copy_start(copy) + offset / bytes_per_sector;
+        let at = (offset % bytes_per_sector) as usize;
+        let first = core::cmp::min(buf.len(), bytes_per_sector as usize - at);
+        buf[..first].copy_from_slice(&self.device.get(sector)?[at..at + first]);
+        if first < buf.len() {
+            let rest = buf.len() - first;
+            buf[first..].copy_from_slice(&self.device.get(sector + 1)?[..rest]);
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at byte `offset` of FAT copy `copy`. The bytes may span
+    /// two sectors.
+    fn write_fat_bytes(&mut self, copy: u8, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let bytes_per_sector = self.bytes_per_sector as u64;
+        let sector = self.fat_copy_start(copy) + offset / bytes_per_sector;
+        let at = (offset % bytes_per_sector) as usize;
+        let first = core::cmp::min(buf.len(), bytes_per_sector as usize - at);
+        self.device.get_mut(sector)?[at..at + first].copy_from_slice(&buf[..first]);
+        if first < buf.len() {
+            let rest = buf.len() - first;
+            self.device.get_mut(sector + 1)?[..rest].copy_from_slice(&buf[first..]);
+        }
+        Ok(())
+    }
+
+    /// The number of bytes holding a FAT entry.
+    fn fat_entry_bytes(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
 
-        // let cluster_in_fat_sector = cluster.cluster_number() * fat_width as u32 / self.bytes_per_sector as u32;
-        // let data = self.device.get(self.fat_start_sector + cluster_in_fat_sector as u64)?;
+    /// Returns an error of kind `InvalidData` if the FAT has no entry for
+    /// `cluster`, which a corrupt chain or directory entry may point to.
+    fn check_fat_index(&self, cluster: u32) -> io::Result<()> {
+        if cluster >= self.cluster_count + 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "cluster number out of range"));
+        }
+        Ok(())
+    }
+
+    /// Returns the raw entry for `cluster` in FAT copy `copy`, as stored on
+    /// disk.
+    fn raw_fat_entry(&mut self, copy: u8, cluster: u32) -> io::Result<u32> {
+        self.check_fat_index(cluster)?;
+        let mut bytes = [0u8; 4];
+        let len = self.fat_entry_bytes();
+        let offset = self.fat_offset(cluster);
+        self.read_fat_bytes(copy, offset, &mut bytes[..len])?;
+
+        let value = u32::from_le_bytes(bytes);
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => value >> 4,
+            FatType::Fat12 => value & 0xFFF,
+            _ => value,
+        })
+    }
 
-        // let index = (cluster.cluster_number() * fat_width as u32 - cluster_in_fat_sector * self.bytes_per_sector as u32) as usize;
-        // let entry = unsafe {&data[index..index+fat_width].cast()[0]};
-        // Ok(entry)
+    /// Overwrites the entry for `cluster` in FAT copy `copy` with the raw
+    /// `value`, leaving the bits around the entry untouched.
+    fn set_raw_fat_entry(&mut self, copy: u8, cluster: u32, value: u32) -> io::Result<()> {
+        self.check_fat_index(cluster)?;
+        let mut bytes = [0u8; 4];
+        let len = self.fat_entry_bytes();
+        let offset = self.fat_offset(cluster);
+        self.read_fat_bytes(copy, offset, &mut bytes[..len])?;
 
-        let cluser_num_in_sector: u64 = cluster.cluster_number() as u64 * size_of::<FatEntry>() as u64
-            / self.bytes_per_sector as u64;
-        let entry_offset: usize = cluster.cluster_number() as usize * size_of::<FatEntry>() % self.bytes_per_sector as usize;
-        let content = self.device.get(self.fat_start_sector + cluser_num_in_sector)?;
-        let entries: &[FatEntry] = unsafe {content.cast()};
-        Ok(&entries[entry_offset/size_of::<FatEntry>()])
+        let old = u32::from_le_bytes(bytes);
+        let new = match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => (old & 0x000F) | (value << 4),
+            FatType::Fat12 => (old & 0xF000) | value,
+            FatType::Fat16 => value,
+            FatType::Fat32 => (old & 0xF0000000) | value,
+        };
+        self.write_fat_bytes(copy, offset, &new.to_le_bytes()[..len])
+    }
+
+    /// Returns the first sector of FAT copy `copy`.
+    fn fat_copy_start(&self, copy: u8) -> u64 {
+        self.fat_start_sector + copy as u64 * self.sectors_per_fat as u64
+    }
+
+    /// The number of FAT copies on the volume.
+    pub fn number_of_fats(&self) -> u8 {
+        self.number_of_fats
+    }
+
+    /// Whether updates to the FAT are mirrored to every copy. If not, only the
+    /// active copy is read and written.
+    pub fn fat_mirroring(&self) -> bool {
+        self.active_fat.is_none()
+    }
+
+    /// Compares every copy of the FAT and returns the clusters whose entries
+    /// differ between copies. An empty vector means all copies agree.
+    pub fn verify_fat_mirrors(&mut self) -> io::Result<Vec<FatMismatch>> {
+        let mut mismatches = Vec::new();
+        if self.number_of_fats < 2 {
+            return Ok(mismatches);
+        }
+
+        let bits_per_sector = self.bytes_per_sector as u64 * 8;
+        let entry_bits = self.fat_type.entry_bits() as u64;
+        let last_cluster = self.cluster_count as u64 + 2;
+        for sector in 0..self.sectors_per_fat as u64 {
+            if sector * bits_per_sector >= last_cluster * entry_bits {
+                break;
+            }
+
+            let mut copies = Vec::with_capacity(self.number_of_fats as usize);
+            for copy in 0..self.number_of_fats {
+                copies.push(self.device.get(self.fat_copy_start(copy) + sector)?.to_vec());
+            }
+            if copies.iter().all(|data| data == &copies[0]) {
+                continue;
+            }
+
+            // Compare every entry that lies at least partly in the sector.
+            let first = sector * bits_per_sector / entry_bits;
+            let end = ::core::cmp::min(((sector + 1) * bits_per_sector).div_ceil(entry_bits), last_cluster);
+            for cluster in first..end {
+                let cluster = cluster as u32;
+                if mismatches.last().map(|mismatch: &FatMismatch| mismatch.cluster) == Some(cluster) {
+                    continue;
+                }
+
+                let mut entries = Vec::with_capacity(self.number_of_fats as usize);
+                for copy in 0..self.number_of_fats {
+                    entries.push(self.raw_fat_entry(copy, cluster)?);
+                }
+                if entries.iter().any(|&entry| entry != entries[0]) {
+                    mismatches.push(FatMismatch { cluster, entries });
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Overwrites every other FAT copy with the contents of copy `source`, for
+    /// example to recover from a damaged primary FAT with a good backup.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if there is no copy `source`.
+    pub fn restore_fat(&mut self, source: u8) -> io::Result<()> {
+        if source >= self.number_of_fats {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such FAT copy"));
+        }
+
+        // Directories may have lost or gained clusters.
+        self.lookups.clear();
+        for sector in 0..self.sectors_per_fat as u64 {
+            let data = self.device.get(self.fat_copy_start(source) + sector)?.to_vec();
+            for copy in (0..self.number_of_fats).filter(|&copy| copy != source) {
+                let start = self.fat_copy_start(copy);
+                self.device.get_mut(start + sector)?.copy_from_slice(&data);
+            }
+        }
+        Ok(())
     }
 
     pub fn find_next_cluster(&mut self, cluster: Cluster) -> io::Result<Cluster> {
@@ -177,13 +600,334 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
             Ok(entry) => {
                 match entry.status() {
                     Status::Data(next_cluster) => Ok(next_cluster),
-                    Status::Eoc(cluster) => Err(io::Error::new(io::ErrorKind::Other, "break")),
+                    Status::Eoc(_) => Err(io::Error::new(io::ErrorKind::Other, "break")),
                     _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid cluster chain")),
                 }
             },
             Err(e) => Err(e),
         }
     }
+
+    /// Returns the cluster following `cluster` in its chain, or `None` if
+    /// `cluster` is the last one.
+    pub fn next_cluster(&mut self, cluster: Cluster) -> io::Result<Option<Cluster>> {
+        match self.fat_entry(cluster)?.status() {
+            Status::Data(next_cluster) => Ok(Some(next_cluster)),
+            Status::Eoc(_) => Ok(None),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid cluster chain")),
+        }
+    }
+
+    /// Returns every cluster in the chain starting at `start`, in order.
+    pub fn cluster_chain(&mut self, start: Cluster) -> io::Result<Vec<Cluster>> {
+        if self.is_fixed_root(start) {
+            return Ok(vec![start]);
+        }
+
+        let mut clusters = Vec::new();
+        let mut current_cluster = Some(start);
+        while let Some(cluster) = current_cluster {
+            if clusters.len() >= self.cluster_count as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "cluster chain loops"));
+            }
+            clusters.push(cluster);
+            current_cluster = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// The number of data clusters on the volume. Valid cluster numbers are
+    /// `2..cluster_count() + 2`.
+    pub(crate) fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub fn bytes_per_cluster(&self) -> usize {
+        self.bytes_per_sector as usize * self.sectors_per_cluster as usize
+    }
+
+    /// Writes `buf` into `cluster` starting at byte `offset` of the cluster.
+    /// At most the remainder of the cluster is written; the number of bytes
+    /// written is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `offset` is past the end of
+    /// the cluster.
+    pub fn write_cluster(&mut self, cluster: Cluster, offset: usize, buf: &[u8]) -> io::Result<usize> {
+        use core::cmp::min;
+
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        let size = min(buf.len(), self.cluster_remainder(cluster, offset)?);
+
+        let mut current_sector = self.cluster_sector(cluster)? + (offset / bytes_per_sector) as u64;
+
+        let mut bytes_written = 0;
+        let mut offset_in_sector = offset % bytes_per_sector;
+
+        while bytes_written < size {
+            let content = self.device.get_mut(current_sector)?;
+            let copy_size = min(size - bytes_written, bytes_per_sector - offset_in_sector);
+            content[offset_in_sector..offset_in_sector + copy_size]
+                .copy_from_slice(&buf[bytes_written..bytes_written + copy_size]);
+            offset_in_sector = 0;
+            bytes_written += copy_size;
+            current_sector += 1;
+        }
+
+        Ok(size)
+    }
+
+    /// Like `write_cluster()`, for a cluster of a directory: cached lookups of
+    /// the entries in the slots written to are dropped.
+    pub(crate) fn write_dir_cluster(&mut self, cluster: Cluster, offset: usize, buf: &[u8]) -> io::Result<usize> {
+        self.lookups.invalidate_bytes(cluster, offset..offset + buf.len());
+        self.write_cluster(cluster, offset, buf)
+    }
+
+    /// Overwrites the FAT entry for `cluster` with the FAT32 entry `value`,
+    /// converted to the volume's FAT type. The reserved upper four bits of
+    /// FAT32 entries are preserved.
+    pub fn set_fat_entry(&mut self, cluster: Cluster, value: u32) -> io::Result<()> {
+        if value == 0 {
+            self.lookups.invalidate_cluster(cluster);
+        }
+        let value = self.fat_type.narrow(value);
+        let copies = match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.number_of_fats,
+        };
+        for copy in copies {
+            self.set_raw_fat_entry(copy, cluster.cluster_number(), value)?;
+        }
+        Ok(())
+    }
+
+    /// Finds a free cluster, marks it as the end of a chain and links it after
+    /// `prev` if given. The new cluster is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `Other` if the volume has no free clusters.
+    pub fn alloc_cluster(&mut self, prev: Option<Cluster>) -> io::Result<Cluster> {
+        if self.free_clusters == Some(0) {
+            return Err(io::Error::new(io::ErrorKind::Other, "no free clusters left on volume"));
+        }
+
+        // Search from the next free hint, wrapping around to the first cluster.
+        for i in 0..self.cluster_count {
+            let number = 2 + (self.next_free - 2 + i) % self.cluster_count;
+            let cluster = Cluster::from(number);
+            if self.fat_entry(cluster)?.status() == Status::Free {
+                self.set_fat_entry(cluster, 0x0FFFFFFF)?;
+                if let Some(prev) = prev {
+                    self.set_fat_entry(prev, cluster.cluster_number())?;
+                }
+                self.next_free = if number + 1 < self.cluster_count + 2 { number + 1 } else { 2 };
+                self.free_clusters = self.free_clusters.map(|count| count.saturating_sub(1));
+                self.fs_info_dirty = true;
+                return Ok(cluster);
+            }
+        }
+        self.free_clusters = Some(0);
+        self.fs_info_dirty = true;
+        Err(io::Error::new(io::ErrorKind::Other, "no free clusters left on volume"))
+    }
+
+    /// Finds `count` physically contiguous free clusters, chains them in order
+    /// and links the run after `prev` if given. The first cluster of the run
+    /// is returned. The run right after `prev` is preferred, so that the chain
+    /// stays contiguous.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `count` is 0, and of kind
+    /// `Other` if the volume has no run of `count` free clusters.
+    pub fn alloc_run(&mut self, prev: Option<Cluster>, count: u32) -> io::Result<Cluster> {
+        if count == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty cluster run"));
+        }
+        if self.free_clusters.is_some_and(|free| free < count) || count > self.cluster_count {
+            return Err(io::Error::new(io::ErrorKind::Other, "no contiguous run of free clusters left on volume"));
+        }
+
+        let end = self.cluster_count + 2;
+        let mut start = None;
+        if let Some(prev) = prev {
+            let first = prev.cluster_number() + 1;
+            if first <= end - count && self.first_used(first, count)?.is_none() {
+                start = Some(first);
+            }
+        }
+        let mut candidate = 2;
+        while start.is_none() && candidate <= end - count {
+            // A failed candidate's used cluster can't be part of any later run.
+            match self.first_used(candidate, count)? {
+                Some(used) => candidate = used + 1,
+                None => start = Some(candidate),
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "no contiguous run of free clusters left on volume")),
+        };
+
+        for number in start..start + count {
+            let next = if number + 1 == start + count { 0x0FFFFFFF } else { number + 1 };
+            self.set_fat_entry(Cluster::from(number), next)?;
+        }
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, start)?;
+        }
+        let after = start + count;
+        self.next_free = if after < end { after } else { 2 };
+        self.free_clusters = self.free_clusters.map(|free| free - count);
+        self.fs_info_dirty = true;
+        Ok(Cluster::from(start))
+    }
+
+    /// Returns the number of the first cluster in use among the `count`
+    /// clusters starting at cluster number `first`, if any.
+    fn first_used(&mut self, first: u32, count: u32) -> io::Result<Option<u32>> {
+        for number in first..first + count {
+            if self.fat_entry(Cluster::from(number))?.status() != Status::Free {
+                return Ok(Some(number));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the cluster size, total and free cluster counts and the next
+    /// free cluster hint of the volume. If the free cluster count isn't known
+    /// from the FSInfo sector, the FAT is scanned once to determine it.
+    pub fn statfs(&mut self) -> io::Result<FsStats> {
+        let free_clusters = match self.free_clusters {
+            Some(count) => count,
+            None => {
+                let mut count = 0;
+                for number in 2..self.cluster_count + 2 {
+                    if self.fat_entry(Cluster::from(number))?.status() == Status::Free {
+                        count += 1;
+                    }
+                }
+                self.free_clusters = Some(count);
+                self.fs_info_dirty = true;
+                count
+            }
+        };
+
+        Ok(FsStats {
+            cluster_size: self.bytes_per_cluster(),
+            total_clusters: self.cluster_count,
+            free_clusters,
+            next_free: self.next_free,
+        })
+    }
+
+    /// The number of free clusters as currently recorded, without scanning
+    /// the FAT.
+    pub(crate) fn recorded_free_clusters(&self) -> Option<u32> {
+        self.free_clusters
+    }
+
+    /// Discards the recorded free cluster count and scans the FAT for the
+    /// actual one, for use after the FAT was modified directly.
+    pub(crate) fn recount_free_clusters(&mut self) -> io::Result<u32> {
+        self.free_clusters = None;
+        Ok(self.statfs()?.free_clusters)
+    }
+
+    /// Marks every cluster in the chain starting at `start` as free.
+    pub fn free_chain(&mut self, start: Cluster) -> io::Result<()> {
+        for cluster in self.cluster_chain(start)? {
+            self.set_fat_entry(cluster, 0)?;
+            self.free_clusters = self.free_clusters.map(|count| count + 1);
+            self.fs_info_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Returns a mutable reference to the regular directory entry at `location`.
+    /// The sector holding it is marked dirty.
+    pub(crate) fn dir_entry_mut(&mut self, location: EntryLocation) -> io::Result<&mut VFatRegularDirEntry> {
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        let sector = self.cluster_sector(location.cluster)? + (location.offset / bytes_per_sector) as u64;
+        self.lookups.invalidate_bytes(location.cluster, location.offset..location.offset + size_of::<VFatRegularDirEntry>());
+        let content = self.device.get_mut(sector)?;
+        let entries: &mut [VFatRegularDirEntry] = unsafe { content.cast_mut() };
+        Ok(&mut entries[location.offset % bytes_per_sector / size_of::<VFatRegularDirEntry>()])
+    }
+
+    /// The timestamp stamped on entries that are created, written to or read,
+    /// as read from the volume's clock.
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// Makes `clock` the source of the times stamped on entries.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// When reading a file updates its last access date.
+    pub fn access_time_policy(&self) -> AccessTimePolicy {
+        self.access_time
+    }
+
+    /// Makes `policy` decide when reading a file updates its last access date.
+    pub fn set_access_time_policy(&mut self, policy: AccessTimePolicy) {
+        self.access_time = policy;
+    }
+
+    /// Writes all modified sectors, and the updated FSInfo sector, back to
+    /// the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let (true, Some(sector)) = (self.fs_info_dirty, self.fs_info_sector) {
+            let content = self.device.get_mut(sector)?;
+            let fs_info: &mut [FsInfo] = unsafe { content[..size_of::<FsInfo>()].cast_mut() };
+            fs_info[0].set_free_count(self.free_clusters);
+            fs_info[0].set_next_free(Some(self.next_free));
+            self.fs_info_dirty = false;
+        }
+        self.device.flush()
+    }
+
+    /// Reads the FSInfo sector of the volume, as of the last `sync()`.
+    /// Returns `None` if the volume has no valid FSInfo sector.
+    pub fn fs_info(&mut self) -> Result<Option<FsInfo>, Error> {
+        match self.fs_info_sector {
+            Some(sector) => FsInfo::from(&mut self.device, sector).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the hit, miss and write-back counters of the sector cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.device.stats()
+    }
+
+    /// Limits the sector cache to `sectors` sectors, writing back and evicting
+    /// sectors if more are cached.
+    pub fn set_cache_capacity(&mut self, sectors: usize) -> io::Result<()> {
+        self.device.set_capacity(sectors)
+    }
+
+    /// Returns the hit and miss counters of the directory entry lookup cache,
+    /// which remembers the entries found by `Dir::find()` and so by `open()`.
+    pub fn lookup_stats(&self) -> LookupStats {
+        self.lookups.stats()
+    }
+
+    /// The OEM code page short names are decoded with.
+    pub fn code_page(&self) -> CodePage {
+        self.code_page
+    }
+
+    /// Limits the directory entry lookup cache to `entries` entries, evicting
+    /// entries if more are cached. A limit of 0 disables the cache.
+    pub fn set_lookup_capacity(&mut self, entries: usize) {
+        self.lookups.set_capacity(entries)
+    }
 }
 
 impl<'a, HANDLE: VFatHandle> FileSystem for &'a HANDLE {
@@ -239,7 +983,9 @@ impl<'a, HANDLE: VFatHandle> FileSystem for &'a HANDLE {
                         return Err(io::Error::from(io::ErrorKind::Other));
                     }
                 },
-                _ => unimplemented!()
+                Component::Prefix(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "path prefixes are not supported"));
+                },
             }
         }
 
@@ -248,4 +994,45 @@ impl<'a, HANDLE: VFatHandle> FileSystem for &'a HANDLE {
             None => Err(io::Error::from(io::ErrorKind::NotFound)),
         }
     }
+
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        let (parent, name) = split_parent(path.as_ref())?;
+        self.open_dir(parent)?.create_file(name)
+    }
+
+    fn create_dir<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Dir> {
+        let (parent, name) = split_parent(path.as_ref())?;
+        self.open_dir(parent)?.create_dir(name)
+    }
+
+    fn remove<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let (parent, name) = split_parent(path.as_ref())?;
+        self.open_dir(parent)?.remove(name)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let (parent, name) = split_parent(path.as_ref())?;
+        self.open_dir(parent)?.remove_dir(name)
+    }
+
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()> {
+        let (from_parent, from_name) = split_parent(from.as_ref())?;
+        let (to_parent, to_name) = split_parent(to.as_ref())?;
+        let from_dir = self.open_dir(from_parent)?;
+        let to_dir = self.open_dir(to_parent)?;
+        from_dir.rename(from_name, &to_dir, to_name)
+    }
+
+    fn set_metadata<P: AsRef<Path>>(self, path: P, changes: &MetadataChanges<Timestamp>) -> io::Result<()> {
+        let (parent, name) = split_parent(path.as_ref())?;
+        self.open_dir(parent)?.set_metadata(name, changes)
+    }
+}
+
+/// Splits the absolute path `path` into its parent directory and file name.
+fn split_parent(path: &Path) -> io::Result<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if path.is_absolute() => Ok((parent, name)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "path must be absolute and name an entry")),
+    }
 }
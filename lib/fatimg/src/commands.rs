@@ -0,0 +1,327 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use fat32::check::{self, LostChains};
+use fat32::format::FormatOptions;
+use fat32::host::StdVFatHandle as Handle;
+use fat32::partition::{self, PartitionType};
+use fat32::traits::{Dir as DirTrait, Entry as EntryTrait, File as FileTrait};
+use fat32::traits::{FileSystem, Metadata as MetadataTrait, MetadataChanges, Timestamp as TimestampTrait};
+use fat32::vfat::{BiosParameterBlock, Dir, Entry, FatType, MountOptions, VFatHandle};
+use fat32::MasterBootRecord;
+
+use crate::image::{self, HostClock, Image};
+use crate::Command;
+
+/// Runs `command` on the image at `path`. Returns `false` if the command ran
+/// but found the volume in need of repair.
+pub fn run(path: &Path, sector_size: u64, partition: Option<usize>, command: &Command) -> io::Result<bool> {
+    let clock = HostClock::from_env()?;
+    if let Command::Format { cluster_size, ref label, no_partition_table } = *command {
+        let options = FormatOptions {
+            cluster_size,
+            volume_label: label.clone(),
+            volume_id: clock.volume_id(),
+            partition_table: !no_partition_table,
+            ..FormatOptions::default()
+        };
+        return format(path, sector_size, &options).map(|()| true);
+    }
+
+    let writes = match *command {
+        Command::Put { .. } | Command::Mkdir { .. } | Command::Rm { .. } => true,
+        Command::Check { repair, .. } => repair,
+        _ => false,
+    };
+    let mut image = image::open(path, sector_size, writes)?;
+    if let Command::Info = *command {
+        print_partitions(&mut image, partition)?;
+    }
+
+    let options = MountOptions { partition, ..MountOptions::default() };
+    let vfat = image::mount(image, &options, clock)?;
+    let clean = match *command {
+        Command::Info => info(&vfat),
+        Command::Ls { recursive, ref path } => ls(&vfat, &image_path(path), recursive),
+        Command::Cat { ref paths } => cat(&vfat, paths),
+        Command::Get { ref from, ref to } => get(&vfat, &image_path(from), to.as_ref().map(PathBuf::as_path)),
+        Command::Put { ref from, ref to } => put(&vfat, from, &image_path(to.as_ref().map_or("/", String::as_str)), clock),
+        Command::Mkdir { parents, ref path } => mkdir(&vfat, &image_path(path), parents),
+        Command::Rm { recursive, ref path } => rm(&vfat, &image_path(path), recursive),
+        Command::Check { repair, save_lost } => return check(&vfat, repair, save_lost),
+        Command::Format { .. } => unreachable!(),
+    }
+    .map(|()| true)?;
+
+    if writes {
+        vfat.lock(|vfat| vfat.sync())?;
+    }
+    Ok(clean)
+}
+
+/// Returns `path` as an absolute path in the image.
+fn image_path(path: &str) -> PathBuf {
+    Path::new("/").join(path)
+}
+
+/// Prints the MBR, the partitions and the boot sector of the selected
+/// partition.
+fn print_partitions(image: &mut Image, partition: Option<usize>) -> io::Result<()> {
+    let partitions = partition::partitions(&mut *image).map_err(image::mount_error)?;
+    if partitions.iter().all(|partition| partition.partition_type != PartitionType::Superfloppy) {
+        let mbr = MasterBootRecord::from(&mut *image).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        println!("{:#?}", mbr);
+    }
+    println!("Partitions:");
+    for (i, partition) in partitions.iter().enumerate() {
+        println!("  {}: {:?}, sectors {}..{}", i, partition.partition_type, partition.start, partition.start + partition.num_sectors);
+    }
+
+    let selected = image::partition(image, partition)?;
+    let bpb = BiosParameterBlock::from(&mut *image, selected.start).map_err(image::mount_error)?;
+    println!("{:#?}", bpb);
+    Ok(())
+}
+
+/// Prints the FSInfo sector, type and cluster usage of the volume.
+fn info(vfat: &Handle) -> io::Result<()> {
+    let (fat_type, fs_info, stats) = vfat.lock(|vfat| -> io::Result<_> {
+        Ok((vfat.fat_type(), vfat.fs_info().map_err(image::mount_error)?, vfat.statfs()?))
+    })?;
+    match fs_info {
+        Some(fs_info) => println!("{:#?}", fs_info),
+        None if fat_type == FatType::Fat32 => println!("No valid FSInfo sector"),
+        None => {}
+    }
+    let used = stats.total_clusters - stats.free_clusters;
+    println!("{:?} volume, {} byte clusters", fat_type, stats.cluster_size);
+    println!("{} clusters: {} used, {} free", stats.total_clusters, used, stats.free_clusters);
+    println!(
+        "{} bytes: {} used, {} free",
+        stats.total_clusters as u64 * stats.cluster_size as u64,
+        used as u64 * stats.cluster_size as u64,
+        stats.free_clusters as u64 * stats.cluster_size as u64
+    );
+    Ok(())
+}
+
+/// Lists the entry at `path`, and the entries of its subdirectories if
+/// `recursive` is set.
+fn ls(vfat: &Handle, path: &Path, recursive: bool) -> io::Result<()> {
+    let entry = vfat.open(path)?;
+    match entry.as_dir() {
+        Some(dir) => list_dir(path, dir, recursive, recursive, &mut Vec::new()),
+        None => {
+            print_entry(&entry);
+            Ok(())
+        }
+    }
+}
+
+/// Lists the entries of `dir`, at `path`, and of its subdirectories if
+/// `recursive` is set. `ancestors` holds the start clusters of the
+/// directories `dir` was reached through.
+fn list_dir(path: &Path, dir: &Dir<Handle>, recursive: bool, header: bool, ancestors: &mut Vec<u32>) -> io::Result<()> {
+    if header {
+        println!("{}:", path.display());
+    }
+    enter(dir, path, ancestors)?;
+    let mut subdirs = Vec::new();
+    for entry in children(dir)? {
+        print_entry(&entry);
+        if recursive && entry.is_dir() {
+            subdirs.push(entry);
+        }
+    }
+    for entry in subdirs {
+        println!();
+        list_dir(&path.join(entry.name()), entry.as_dir().unwrap(), recursive, header, ancestors)?;
+    }
+    ancestors.pop();
+    Ok(())
+}
+
+/// Returns the entries of `dir` other than `.` and `..`, or an error if the
+/// directory can't be read to the end.
+fn children(dir: &Dir<Handle>) -> io::Result<Vec<Entry<Handle>>> {
+    let mut entries = dir.entries()?;
+    let children = entries.by_ref().filter(|entry| entry.name() != "." && entry.name() != "..").collect();
+    match entries.take_error() {
+        Some(e) => Err(e),
+        None => Ok(children),
+    }
+}
+
+/// Pushes the start cluster of `dir`, at `path`, onto `ancestors`. Fails if
+/// it is there already: the directory contains itself, and walking it would
+/// never end.
+fn enter(dir: &Dir<Handle>, path: &Path, ancestors: &mut Vec<u32>) -> io::Result<()> {
+    let cluster = dir.cluster.cluster_number();
+    if ancestors.contains(&cluster) {
+        let message = format!("{} loops back to a directory above it; run check --repair", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    ancestors.push(cluster);
+    Ok(())
+}
+
+/// Prints the attributes, size, modification time and name of `entry`.
+fn print_entry(entry: &Entry<Handle>) {
+    let metadata = entry.metadata();
+    let attributes = metadata.attributes;
+    let flag = |set: bool, c: char| if set { c } else { '-' };
+    let modified = metadata.modified();
+    println!(
+        "{}{}{}{}{} {:>10} {:04}-{:02}-{:02} {:02}:{:02} {}",
+        flag(entry.is_dir(), 'd'),
+        flag(attributes.read_only(), 'r'),
+        flag(attributes.hidden(), 'h'),
+        flag(attributes.system(), 's'),
+        flag(attributes.archive(), 'a'),
+        entry.as_file().map_or(0, |file| file.size()),
+        modified.year(),
+        modified.month(),
+        modified.day(),
+        modified.hour(),
+        modified.minute(),
+        entry.name()
+    );
+}
+
+/// Writes the files at `paths` to stdout.
+fn cat(vfat: &Handle, paths: &[String]) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for path in paths {
+        let mut file = vfat.open_file(image_path(path))?;
+        io::copy(&mut file, &mut stdout)?;
+    }
+    stdout.flush()
+}
+
+/// Copies the file at `from` in the image to `to` on the host, or to a file
+/// of the same name in the current directory.
+fn get(vfat: &Handle, from: &Path, to: Option<&Path>) -> io::Result<()> {
+    let mut file = vfat.open_file(from)?;
+    let name = from.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    let mut to = to.map_or_else(|| PathBuf::from(name), Path::to_path_buf);
+    if to.is_dir() {
+        to.push(name);
+    }
+    io::copy(&mut file, &mut fs::File::create(to)?)?;
+    Ok(())
+}
+
+/// Copies the host file `from` to `to` in the image, or into `to` if it is a
+/// directory. The file keeps its modification time.
+fn put(vfat: &Handle, from: &Path, to: &Path, clock: HostClock) -> io::Result<()> {
+    let mut host_file = fs::File::open(from)?;
+    let modified = host_file.metadata()?.modified().ok();
+    let mut to = to.to_path_buf();
+    if vfat.open(&to).map(|entry| entry.is_dir()).unwrap_or(false) {
+        let name = from.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        to.push(name);
+    }
+
+    let mut file = match vfat.open_file(&to) {
+        Ok(mut file) => {
+            file.set_len(0)?;
+            file
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => vfat.create_file(&to)?,
+        Err(e) => return Err(e),
+    };
+    io::copy(&mut host_file, &mut file)?;
+    drop(file);
+
+    if let Some(modified) = modified {
+        let changes = MetadataChanges { modified: Some(clock.file_time(modified)), ..MetadataChanges::default() };
+        vfat.set_metadata(&to, &changes)?;
+    }
+    Ok(())
+}
+
+/// Creates the directory `path`, and its missing parents if `parents` is set.
+fn mkdir(vfat: &Handle, path: &Path, parents: bool) -> io::Result<()> {
+    if !parents {
+        return vfat.create_dir(path).map(|_| ());
+    }
+
+    let mut partial = PathBuf::from("/");
+    for component in path.components().skip(1) {
+        partial.push(component);
+        match vfat.open(&partial) {
+            Ok(ref entry) if entry.is_dir() => continue,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a directory", partial.display()))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                vfat.create_dir(&partial)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the file or empty directory at `path`, or the directory and its
+/// contents if `recursive` is set.
+fn rm(vfat: &Handle, path: &Path, recursive: bool) -> io::Result<()> {
+    let entry = vfat.open(path)?;
+    let dir = match entry.as_dir() {
+        Some(dir) => dir,
+        None => return vfat.remove(path),
+    };
+    if path.parent().is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "refusing to remove the root directory"));
+    }
+    if recursive {
+        remove_contents(vfat, path, dir, &mut Vec::new())?;
+    }
+    vfat.remove_dir(path)
+}
+
+/// Removes everything in the directory `dir` at `path`. `ancestors` holds the
+/// start clusters of the directories `dir` was reached through.
+fn remove_contents(vfat: &Handle, path: &Path, dir: &Dir<Handle>, ancestors: &mut Vec<u32>) -> io::Result<()> {
+    enter(dir, path, ancestors)?;
+    for entry in children(dir)? {
+        let child = path.join(entry.name());
+        match entry.as_dir() {
+            Some(subdir) => {
+                remove_contents(vfat, &child, subdir, ancestors)?;
+                vfat.remove_dir(&child)?;
+            }
+            None => vfat.remove(&child)?,
+        }
+    }
+    ancestors.pop();
+    Ok(())
+}
+
+/// Formats the whole image at `path` as an empty FAT32 volume.
+fn format(path: &Path, sector_size: u64, options: &FormatOptions) -> io::Result<()> {
+    let sectors = fs::metadata(path)?.len() / sector_size;
+    let image = image::open(path, sector_size, true)?;
+    fat32::format::format(image, sectors, options)
+}
+
+/// Checks the volume and prints what is wrong with it, repairing it if
+/// `repair` is set. Returns `true` if the volume was clean or got repaired.
+fn check(vfat: &Handle, repair: bool, save_lost: bool) -> io::Result<bool> {
+    let report = match repair {
+        true => check::repair(vfat, if save_lost { LostChains::Save } else { LostChains::Reclaim })?,
+        false => check::check(vfat)?,
+    };
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!("{} files, {} directories, {} clusters in use", report.files, report.directories, report.used_clusters);
+    if report.is_clean() {
+        println!("No problems found");
+    } else if report.repaired {
+        println!("{} problems repaired", report.problems.len());
+    } else {
+        println!("{} problems found", report.problems.len());
+    }
+    Ok(report.is_clean() || report.repaired)
+}
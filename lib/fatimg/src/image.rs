@@ -0,0 +1,105 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fat32::host::{StdVFatHandle, StreamDevice};
+use fat32::partition::{self, PartitionInfo};
+use fat32::vfat::{Clock, MountOptions, Timestamp, VFat, VFatHandle};
+
+/// A disk image file, read and written in sectors of a given size.
+pub type Image = StreamDevice<File>;
+
+/// Opens the image at `path` with `sector_size` byte sectors, for writing too
+/// if `write` is set.
+pub fn open(path: &Path, sector_size: u64, write: bool) -> io::Result<Image> {
+    let file = OpenOptions::new().read(true).write(write).open(path)?;
+    StreamDevice::new(file, sector_size)
+}
+
+/// Returns the partition of `image` selected by `index`, numbered as in
+/// `partition::partitions()`, or the first FAT partition if `None`.
+pub fn partition(image: &mut Image, index: Option<usize>) -> io::Result<PartitionInfo> {
+    let partitions = partition::partitions(image).map_err(mount_error)?;
+    let found = match index {
+        Some(index) => partitions.get(index).cloned(),
+        None => partitions.into_iter().find(|partition| partition.partition_type.is_fat()),
+    };
+    found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such FAT partition"))
+}
+
+/// Mounts the volume on `image` selected by `options`. New and modified
+/// entries get timestamps from `clock`.
+pub fn mount(image: Image, options: &MountOptions, clock: HostClock) -> io::Result<StdVFatHandle> {
+    let vfat = VFat::<StdVFatHandle>::from_options(image, options).map_err(mount_error)?;
+    vfat.lock(|vfat| vfat.set_clock(clock));
+    Ok(vfat)
+}
+
+/// Converts an error from mounting or probing a volume into an I/O error.
+pub fn mount_error(error: fat32::vfat::Error) -> io::Error {
+    match error {
+        fat32::vfat::Error::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, format!("not a valid FAT volume: {:?}", error)),
+    }
+}
+
+/// The host's clock, or a fixed time for reproducible images if the
+/// `SOURCE_DATE_EPOCH` environment variable is set.
+#[derive(Debug, Copy, Clone)]
+pub struct HostClock {
+    source_date_epoch: Option<Duration>,
+}
+
+impl HostClock {
+    pub fn from_env() -> io::Result<HostClock> {
+        let source_date_epoch = match ::std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(secs) => match secs.trim().parse() {
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid SOURCE_DATE_EPOCH")),
+            },
+            Err(_) => None,
+        };
+        Ok(HostClock { source_date_epoch })
+    }
+
+    /// Returns the timestamp of a host file last modified at `modified`. With
+    /// `SOURCE_DATE_EPOCH` set, later times are clamped to it.
+    pub fn file_time(&self, modified: SystemTime) -> Timestamp {
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let clamped = match self.source_date_epoch {
+            Some(epoch) => ::std::cmp::min(since_epoch, epoch),
+            None => since_epoch,
+        };
+        to_timestamp(clamped)
+    }
+
+    /// Returns a volume serial number made from the current time, like
+    /// `mkfs.vfat` does.
+    pub fn volume_id(&self) -> u32 {
+        let since_epoch = match self.source_date_epoch {
+            Some(epoch) => epoch,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+        };
+        since_epoch.as_secs() as u32
+    }
+}
+
+impl Clock for HostClock {
+    fn now(&self) -> Timestamp {
+        match self.source_date_epoch {
+            Some(epoch) => to_timestamp(epoch),
+            None => to_timestamp(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()),
+        }
+    }
+}
+
+/// Converts a time since the Unix epoch to a timestamp, clamped to the range
+/// FAT can represent.
+fn to_timestamp(since_unix_epoch: Duration) -> Timestamp {
+    match Timestamp::from_duration(since_unix_epoch) {
+        Some(timestamp) => timestamp,
+        None if since_unix_epoch < Timestamp::EPOCH.to_duration() => Timestamp::EPOCH,
+        None => Timestamp::MAX,
+    }
+}
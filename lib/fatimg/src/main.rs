@@ -0,0 +1,109 @@
+mod commands;
+mod image;
+
+use std::path::PathBuf;
+use std::process;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Inspect and edit FAT disk images without mounting them.")]
+struct Opt {
+    #[structopt(help = "Path to the disk image", parse(from_os_str))]
+    image: PathBuf,
+
+    #[structopt(short = "s", long = "sector-size", help = "Sector size of the image in bytes",
+                default_value = "512")]
+    sector_size: u64,
+
+    #[structopt(short = "p", long = "partition",
+                help = "Partition to use, numbered from 0 (defaults to the first FAT partition)")]
+    partition: Option<usize>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(name = "info", about = "Show the partition table, boot sector, FSInfo and cluster usage")]
+    Info,
+
+    #[structopt(name = "ls", about = "List a directory")]
+    Ls {
+        #[structopt(short = "R", help = "List subdirectories recursively")]
+        recursive: bool,
+        #[structopt(help = "Directory or file in the image", default_value = "/")]
+        path: String,
+    },
+
+    #[structopt(name = "cat", about = "Write files in the image to stdout")]
+    Cat {
+        #[structopt(help = "Files in the image", raw(required = "true"))]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "get", about = "Copy a file out of the image")]
+    Get {
+        #[structopt(help = "File in the image")]
+        from: String,
+        #[structopt(help = "Destination on the host (defaults to the file's name)", parse(from_os_str))]
+        to: Option<PathBuf>,
+    },
+
+    #[structopt(name = "put", about = "Copy a file into the image, replacing an existing one")]
+    Put {
+        #[structopt(help = "File on the host", parse(from_os_str))]
+        from: PathBuf,
+        #[structopt(help = "Destination in the image (defaults to the root directory)")]
+        to: Option<String>,
+    },
+
+    #[structopt(name = "mkdir", about = "Create a directory")]
+    Mkdir {
+        #[structopt(short = "p", help = "Create missing parents and accept existing directories")]
+        parents: bool,
+        #[structopt(help = "Directory in the image")]
+        path: String,
+    },
+
+    #[structopt(name = "rm", about = "Remove a file or an empty directory")]
+    Rm {
+        #[structopt(short = "r", help = "Remove directories and their contents")]
+        recursive: bool,
+        #[structopt(help = "File or directory in the image")]
+        path: String,
+    },
+
+    #[structopt(name = "format", about = "Format the whole image as an empty FAT32 volume")]
+    Format {
+        #[structopt(short = "c", long = "cluster-size",
+                    help = "Cluster size in bytes (defaults to one picked from the image size)")]
+        cluster_size: Option<u32>,
+        #[structopt(short = "n", long = "label", help = "Volume label, at most 11 characters")]
+        label: Option<String>,
+        #[structopt(long = "no-partition-table", help = "Format the image as one volume, without an MBR")]
+        no_partition_table: bool,
+    },
+
+    #[structopt(name = "check", about = "Check the volume for errors")]
+    Check {
+        #[structopt(long = "repair", help = "Repair the errors found")]
+        repair: bool,
+        #[structopt(long = "save-lost", raw(requires = r#""repair""#),
+                    help = "Save lost chains to /FOUND.000 instead of freeing them")]
+        save_lost: bool,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    match commands::run(&opt.image, opt.sector_size, opt.partition, &opt.command) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("fatimg: {}", e);
+            process::exit(2);
+        }
+    }
+}
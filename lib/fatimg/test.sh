@@ -0,0 +1,63 @@
+#! /bin/bash
+
+WORK=$(mktemp -d)
+IMAGE="${WORK}/disk.img"
+FATIMG=./target/debug/fatimg
+
+function cleanup_and_exit() {
+  rm -rf "${WORK}"
+  exit $1
+}
+
+function fail() {
+  echo -e "${KRED}ERROR: $1${KNRM}" >&2
+  cleanup_and_exit 1
+}
+
+# Use color when outputting to the terminal.
+if [ -t 1 ]; then
+  KNRM="\x1B[0m"; KRED="\x1B[31m"; KGRN="\x1B[32m"; KBLU="\x1B[34m"
+else
+  KNRM=""; KRED=""; KGRN=""; KBLU=""
+fi
+
+echo -e "${KBLU}Compiling project with 'cargo build'...${KNRM}"
+if ! cargo build; then
+  echo -e "${KRED}ERROR: fatimg compilation failed${KNRM}" >&2
+  cleanup_and_exit 1
+fi
+
+echo -e "${KBLU}Formatting a 64 MiB image...${KNRM}"
+dd if=/dev/zero of="${IMAGE}" bs=1048576 count=64 2> /dev/null
+${FATIMG} "${IMAGE}" format -n TEST || fail "format failed"
+
+echo -e "${KBLU}Creating directories and copying files in...${KNRM}"
+${FATIMG} "${IMAGE}" mkdir -p /docs/notes/old || fail "mkdir -p failed"
+${FATIMG} "${IMAGE}" mkdir -p /docs/notes || fail "mkdir -p of an existing directory failed"
+head -c 200000 /dev/urandom > "${WORK}/random.bin"
+echo "hello, world" > "${WORK}/Hello World.txt"
+${FATIMG} "${IMAGE}" put "${WORK}/random.bin" /docs/notes/old || fail "put failed"
+${FATIMG} "${IMAGE}" put "${WORK}/Hello World.txt" /docs || fail "put failed"
+
+echo -e "${KBLU}Listing the image...${KNRM}"
+listing=$(${FATIMG} "${IMAGE}" ls -R) || fail "ls -R failed"
+echo "${listing}"
+for expected in "/docs/notes/old:" " 200000 .* random.bin$" " 13 .* Hello World.txt$"; do
+  grep -q -- "${expected}" <<< "${listing}" || fail "'${expected}' is missing from the listing"
+done
+
+echo -e "${KBLU}Copying files back out...${KNRM}"
+${FATIMG} "${IMAGE}" get /docs/notes/old/random.bin "${WORK}/random.out" || fail "get failed"
+cmp "${WORK}/random.bin" "${WORK}/random.out" || fail "random.bin changed in the image"
+[[ "$(${FATIMG} "${IMAGE}" cat "/docs/Hello World.txt")" = "hello, world" ]] || fail "cat printed the wrong contents"
+
+echo -e "${KBLU}Removing the tree...${KNRM}"
+${FATIMG} "${IMAGE}" rm /docs 2> /dev/null && fail "rm removed a directory that isn't empty"
+${FATIMG} "${IMAGE}" rm -r /docs || fail "rm -r failed"
+[[ -z "$(${FATIMG} "${IMAGE}" ls)" ]] || fail "the root directory isn't empty after rm -r"
+
+echo -e "${KBLU}Checking the volume...${KNRM}"
+${FATIMG} "${IMAGE}" check || fail "the volume has errors"
+
+echo -e "${KGRN}SUCCESS${KNRM}"
+cleanup_and_exit 0